@@ -1,8 +1,34 @@
 /// Bitcoin constants
 pub const SOFTWARE_VERSION: &str = "0.1.0";
 pub const TX_VERSION: u32 = 1;
-pub const COINBASE_VALUE: u128 = 50_000_000_000; // 50 BTC
+pub const COIN: u128 = 1_000_000_000; // smallest units in 1 BTC
+pub const MAX_MONEY: u128 = 21_000_000 * COIN; // 21 million BTC
 
-pub const AVERAGE_BLOCK_TIME_MS: u64 = 5000; // 5 seconds
+pub const MAX_BLOCK_SIZE: usize = 1_000_000; // bytes of an encoded block
+pub const MEDIAN_TIME_SPAN: usize = 11; // previous blocks whose median timestamp a new block must exceed
+pub const MAX_FUTURE_BLOCK_TIME_MS: u128 = 2 * 60 * 60 * 1000; // 2 hours ahead of the local clock
 
-pub const NUMBER_OF_NODES: u32 = 5;
+pub const MAX_MEMPOOL_SIZE: usize = 5_000_000; // bytes of encoded transactions kept in the mempool
+pub const MAX_MEMPOOL_ANCESTORS: usize = 25; // unconfirmed ancestors allowed for a mempool transaction
+pub const MAX_MEMPOOL_DESCENDANTS: usize = 25; // unconfirmed descendants allowed for a mempool transaction
+pub const MAX_BIP125_RBF_SEQUENCE: u32 = 0xfffffffd; // input sequences up to this value signal replace-by-fee
+pub const MAX_REPLACEMENT_EVICTIONS: usize = 100; // mempool transactions a single replacement can evict
+pub const INCREMENTAL_RELAY_FEE_RATE: u128 = 1; // satoshis per byte a replacement pays on top of the replaced fees
+
+pub const MAX_ORPHAN_BLOCKS: usize = 100; // blocks with an unknown previous block kept until it arrives
+pub const ORPHAN_BLOCK_EXPIRY_MS: u128 = 10 * 60 * 1000; // 10 minutes
+
+pub const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024; // bytes of a blk*.dat file before a new one is started
+pub const MAX_CHAINSTATE_CACHE_SIZE: usize = 10_000; // changed outputs and undo entries kept in memory before a flush
+pub const CHAINSTATE_FLUSH_INTERVAL_MS: u128 = 60 * 1000; // 1 minute
+pub const MAX_CHAINSTATE_JOURNAL_SIZE: u64 = 16 * 1024 * 1024; // bytes of journaled changes before a new snapshot is written
+
+pub const PROTOCOL_VERSION: u32 = 1; // version of the peer-to-peer protocol
+pub const MAX_MESSAGE_PAYLOAD_SIZE: usize = 2 * MAX_BLOCK_SIZE; // bytes of a network message after its header
+pub const MAX_INV_ITEMS: usize = 50_000; // items of an inv or getdata message
+pub const MAX_HEADERS_RESULTS: usize = 2000; // headers sent in answer to a getheaders message
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 10 * 1000; // 10 seconds to exchange version and verack
+pub const PING_INTERVAL_MS: u128 = 2 * 60 * 1000; // 2 minutes between pings of a peer
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16; // blocks requested from one peer at a time while syncing
+pub const BLOCK_DOWNLOAD_WINDOW: usize = 1024; // blocks after the first missing one that are downloaded ahead
+pub const BLOCK_DOWNLOAD_TIMEOUT_MS: u128 = 60 * 1000; // 1 minute before a requested block is asked from another peer
@@ -1,14 +1,13 @@
 use core::fmt;
 
-use secp256k1::hashes::sha256;
-use secp256k1::PublicKey;
+use secp256k1::hashes::{sha256, Hash};
 
 use crate::core::transaction::Transaction;
-use crate::constants::SOFTWARE_VERSION;
-use crate::utils::hash::sha256_hash;
-use crate::utils::time::get_current_timestamp_ms;
+use crate::core::pow::{compact_to_target, hash_meets_target};
+use crate::core::serialize::{write_var_bytes, write_vec, DecodeError, Decodable, Encodable, Reader};
+use crate::utils::hash::hash256;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Block {
     /// The block header contains metadata about the block
     pub header: BlockHeader,
@@ -21,6 +20,7 @@ pub struct Block {
 }
 
 impl Block {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(software_version: String, previous_block_hash: Option<sha256::Hash>, merkle_root: sha256::Hash, timestamp: u128, difficulty_target: u32, nonce: u32, transactions: Vec<Transaction>, coinbase_transaction: Transaction) -> Block {
         Block {
             header: BlockHeader {
@@ -37,11 +37,16 @@ impl Block {
     }
 
     pub fn hash_block(&self) -> sha256::Hash {
-        sha256_hash(self.header.to_string().as_str())
+        self.header.hash()
+    }
+
+    /// Checks if the block hash is lower than or equal to the target encoded in `difficulty_target`
+    pub fn has_valid_proof_of_work(&self) -> bool {
+        self.header.has_valid_proof_of_work()
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BlockHeader {
     /// The version of the block
     pub software_version: String,
@@ -51,13 +56,81 @@ pub struct BlockHeader {
     pub merkle_root: sha256::Hash,
     /// The time of the block creation
     pub timestamp: u128,
-    /// The target value for the block hash
-    /// Number of leading zeros in the hash
+    /// The target value for the block hash in the compact "bits" format
+    /// (the hash, read as a big-endian number, has to be lower than or equal to the target)
     pub difficulty_target: u32,
     /// The nonce value that miners increment
     pub nonce: u32,
 }
 
+impl BlockHeader {
+    /// Double SHA-256 of the canonical header encoding
+    pub fn hash(&self) -> sha256::Hash {
+        hash256(&self.encode())
+    }
+
+    /// Checks if the header hash is lower than or equal to the target encoded in `difficulty_target`
+    pub fn has_valid_proof_of_work(&self) -> bool {
+        match compact_to_target(self.difficulty_target) {
+            Some(target) => hash_meets_target(&self.hash(), &target),
+            None => false,
+        }
+    }
+}
+
+/// Header encoding: var_str software_version, previous block hash (32 zero bytes for the genesis block),
+/// merkle root, timestamp (u128), difficulty target (u32) and nonce (u32)
+impl Encodable for BlockHeader {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        write_var_bytes(writer, self.software_version.as_bytes());
+        let previous_block_hash = self.previous_block_hash.unwrap_or(sha256::Hash::all_zeros());
+        writer.extend_from_slice(previous_block_hash.as_byte_array());
+        writer.extend_from_slice(self.merkle_root.as_byte_array());
+        writer.extend_from_slice(&self.timestamp.to_le_bytes());
+        writer.extend_from_slice(&self.difficulty_target.to_le_bytes());
+        writer.extend_from_slice(&self.nonce.to_le_bytes());
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode_from(reader: &mut Reader) -> Result<BlockHeader, DecodeError> {
+        let software_version = reader.read_var_string()?;
+        let previous_block_hash = reader.read_hash()?;
+        Ok(BlockHeader {
+            software_version,
+            previous_block_hash: if previous_block_hash == sha256::Hash::all_zeros() { None } else { Some(previous_block_hash) },
+            merkle_root: reader.read_hash()?,
+            timestamp: reader.read_u128()?,
+            difficulty_target: reader.read_u32()?,
+            nonce: reader.read_u32()?,
+        })
+    }
+}
+
+/// Block encoding: header followed by the CompactSize prefixed list of transactions,
+/// the coinbase transaction is the first one in the list and isn't encoded separately
+impl Encodable for Block {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        self.header.encode_to(writer);
+        write_vec(writer, &self.transactions);
+    }
+}
+
+/// The coinbase transaction of a decoded block is a copy of its first transaction,
+/// so the `CoinbaseMismatch` check only catches blocks assembled in memory
+impl Decodable for Block {
+    fn decode_from(reader: &mut Reader) -> Result<Block, DecodeError> {
+        let header = BlockHeader::decode_from(reader)?;
+        let transactions: Vec<Transaction> = reader.read_vec()?;
+        let coinbase_transaction = transactions.first().ok_or(DecodeError::EmptyBlock)?.clone();
+        Ok(Block {
+            header,
+            transactions,
+            coinbase_transaction,
+        })
+    }
+}
+
 impl fmt::Display for BlockHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "BlockHeader({:?})", self)
@@ -67,12 +140,14 @@ impl fmt::Display for BlockHeader {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use secp256k1::hashes::Hash;
-    use secp256k1::Secp256k1;
+    use secp256k1::{PublicKey, Secp256k1};
     use secp256k1::rand::rngs::OsRng;
-    use crate::core::transaction::Transaction;
+    use crate::constants::{COIN, SOFTWARE_VERSION};
+    use crate::utils::hash::sha256_hash;
+    use crate::core::transaction::{p2pkh_script, Transaction};
     use crate::utils::time::get_current_timestamp_ms;
 
+    const COINBASE_VALUE: u128 = 50 * COIN;
     const DUMMY_NONCE: u32 = 1234567;
     const DUMMY_DIFFICULTY_TARGET: u32 = 0xabcdef12;
 
@@ -92,9 +167,7 @@ mod tests {
 
     fn create_dummy_transaction() -> Transaction {
         let pub_key = generate_public_key();
-        let script_pub_key = "76a914...88ac".to_string();
-
-        Transaction::new_coinbase_transaction(script_pub_key, pub_key)
+        Transaction::new_coinbase_transaction(p2pkh_script(&pub_key), pub_key, 0, COINBASE_VALUE)
     }
 
     #[test]
@@ -149,6 +222,27 @@ mod tests {
         assert_eq!(block_hash.as_byte_array().len(), 32);  // SHA-256 hash bi trebao imati 32 bajta
     }
 
+    #[test]
+    fn test_has_valid_proof_of_work() {
+        let dummy_transaction = create_dummy_transaction();
+        let mut block = Block::new(
+            SOFTWARE_VERSION.to_string(),
+            None,
+            get_dummy_merkle_root(),
+            get_current_timestamp_ms(),
+            0x2000ffff,
+            0,
+            vec![dummy_transaction.clone()],
+            dummy_transaction,
+        );
+        while !block.has_valid_proof_of_work() {
+            block.header.nonce += 1;
+        }
+        // the same hash doesn't satisfy a harder target
+        block.header.difficulty_target = 0x03000001;
+        assert!(!block.has_valid_proof_of_work());
+    }
+
     #[test]
     fn test_block_header_display() {
         let dummy_previous_block_hash = generate_dummy_previous_block_hash();
@@ -171,4 +265,66 @@ mod tests {
         assert!(header_display.contains("difficulty_target"));
         assert!(header_display.contains("nonce"));
     }
+
+    fn create_dummy_block() -> Block {
+        let dummy_transaction = create_dummy_transaction();
+        Block::new(
+            SOFTWARE_VERSION.to_string(),
+            Some(generate_dummy_previous_block_hash()),
+            get_dummy_merkle_root(),
+            get_current_timestamp_ms(),
+            DUMMY_DIFFICULTY_TARGET,
+            DUMMY_NONCE,
+            vec![dummy_transaction.clone(), create_dummy_transaction()],
+            dummy_transaction,
+        )
+    }
+
+    #[test]
+    fn test_block_header_encoding() {
+        let block = create_dummy_block();
+        let bytes = block.header.encode();
+        // var_str version + 2 hashes + timestamp + target + nonce
+        assert_eq!(bytes.len(), 1 + SOFTWARE_VERSION.len() + 32 + 32 + 16 + 4 + 4);
+        assert_eq!(&bytes[bytes.len() - 4..], &DUMMY_NONCE.to_le_bytes());
+        assert_eq!(BlockHeader::decode(&bytes).unwrap(), block.header);
+    }
+
+    #[test]
+    fn test_genesis_header_encoding() {
+        let mut block = create_dummy_block();
+        block.header.previous_block_hash = None;
+        let decoded_header = BlockHeader::decode(&block.header.encode()).unwrap();
+        assert_eq!(decoded_header.previous_block_hash, None);
+    }
+
+    #[test]
+    fn test_block_encoding_roundtrip() {
+        let block = create_dummy_block();
+        let decoded_block = Block::decode(&block.encode()).unwrap();
+        assert_eq!(decoded_block, block);
+        assert_eq!(decoded_block.hash_block(), block.hash_block());
+    }
+
+    #[test]
+    fn test_block_decoding_errors() {
+        let block = create_dummy_block();
+        let mut bytes = block.encode();
+        bytes.push(0);
+        assert_eq!(Block::decode(&bytes), Err(DecodeError::TrailingBytes));
+        assert_eq!(Block::decode(&bytes[..bytes.len() - 10]), Err(DecodeError::UnexpectedEnd));
+
+        let mut bytes = block.header.encode();
+        bytes.push(0);
+        assert_eq!(Block::decode(&bytes), Err(DecodeError::EmptyBlock));
+    }
+
+    #[test]
+    fn test_hash_block_depends_on_encoding() {
+        let mut block = create_dummy_block();
+        let block_hash = block.hash_block();
+        assert_eq!(block_hash, hash256(&block.header.encode()));
+        block.header.nonce += 1;
+        assert_ne!(block.hash_block(), block_hash);
+    }
 }
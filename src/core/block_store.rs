@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use secp256k1::hashes::{sha256, Hash};
+
+use crate::constants::MAX_BLOCK_FILE_SIZE;
+use crate::core::block::Block;
+use crate::core::serialize::{DecodeError, Decodable, Encodable, Reader};
+
+/// Name of the block index file, kept next to the block files
+const INDEX_FILE_NAME: &str = "index.dat";
+
+/// Size of a block record header in the block files: magic bytes and block size (u32)
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// Size of an encoded index record: block hash followed by its `BlockIndexEntry`
+const INDEX_RECORD_SIZE: usize = 32 + 4 + 8 + 4 + 8 + 1;
+
+/// Validation state of a stored block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// The block has a valid proof of work but it wasn't connected to the active chain yet
+    Stored,
+    /// The block was connected to the active chain
+    Valid,
+    /// The block (or one of its ancestors) failed validation
+    Invalid,
+}
+
+/// Position and state of a stored block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockIndexEntry {
+    /// Number of the `blk*.dat` file holding the block
+    pub file: u32,
+    /// Offset of the encoded block in the file (after its record header)
+    pub offset: u64,
+    /// Size of the encoded block in bytes
+    pub size: u32,
+    pub height: u64,
+    pub status: BlockStatus,
+}
+
+/// Index entry encoding: file (u32), offset (u64), size (u32), height (u64) and status (u8)
+impl Encodable for BlockIndexEntry {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(&self.file.to_le_bytes());
+        writer.extend_from_slice(&self.offset.to_le_bytes());
+        writer.extend_from_slice(&self.size.to_le_bytes());
+        writer.extend_from_slice(&self.height.to_le_bytes());
+        writer.push(match self.status {
+            BlockStatus::Stored => 0,
+            BlockStatus::Valid => 1,
+            BlockStatus::Invalid => 2,
+        });
+    }
+}
+
+impl Decodable for BlockIndexEntry {
+    fn decode_from(reader: &mut Reader) -> Result<BlockIndexEntry, DecodeError> {
+        Ok(BlockIndexEntry {
+            file: reader.read_u32()?,
+            offset: reader.read_u64()?,
+            size: reader.read_u32()?,
+            height: reader.read_u64()?,
+            status: match reader.read_u8()? {
+                0 => BlockStatus::Stored,
+                1 => BlockStatus::Valid,
+                2 => BlockStatus::Invalid,
+                _ => return Err(DecodeError::InvalidBlockStatus),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreError {
+    /// Reading or writing a file failed
+    Io(String),
+    /// A block record doesn't start with the magic bytes of the network
+    BadMagic { file: u32, offset: u64 },
+    /// A stored block can't be decoded
+    CorruptBlock { file: u32, offset: u64, error: DecodeError },
+    /// A record of the block index can't be decoded
+    CorruptIndex(DecodeError),
+    /// The index points to a block with another hash
+    HashMismatch(sha256::Hash),
+    /// A stored block's previous block isn't in the index
+    UnknownParent(sha256::Hash),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(error) => write!(f, "block storage error: {}", error),
+            StoreError::BadMagic { file, offset } => write!(f, "bad magic bytes in {} at offset {}", block_file_name(*file), offset),
+            StoreError::CorruptBlock { file, offset, error } => write!(f, "corrupt block in {} at offset {}: {}", block_file_name(*file), offset, error),
+            StoreError::CorruptIndex(error) => write!(f, "corrupt {}: {}", INDEX_FILE_NAME, error),
+            StoreError::HashMismatch(hash) => write!(f, "stored block {} has another hash", hash),
+            StoreError::UnknownParent(hash) => write!(f, "previous block {} isn't stored", hash),
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(error: std::io::Error) -> StoreError {
+        StoreError::Io(error.to_string())
+    }
+}
+
+/// Append-only block storage: blocks are written one after another to `blk00000.dat`, `blk00001.dat`, ...
+/// (each record is the network magic, the block size and the encoded block)
+/// and a new file is started once the current one reaches `max_file_size` bytes.
+/// The block index (hash -> file, offset, height and status) is an append-only log of `index.dat`,
+/// the last record of a hash wins
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    magic: [u8; 4],
+    index: HashMap<sha256::Hash, BlockIndexEntry>,
+    index_file: File,
+    /// Number of the file new blocks are appended to
+    current_file: u32,
+    current_file_size: u64,
+    max_file_size: u64,
+}
+
+impl BlockStore {
+    /// Opens the block storage in `dir` (created if it doesn't exist) for the network with the given magic bytes
+    pub fn open(dir: &Path, magic: [u8; 4]) -> Result<BlockStore, StoreError> {
+        BlockStore::open_with_max_file_size(dir, magic, MAX_BLOCK_FILE_SIZE)
+    }
+
+    /// Opens the block storage and loads its index.
+    /// Blocks written after the last index record (the node stopped in between) are indexed again
+    /// and a partially written block at the end of the last file is cut off
+    pub fn open_with_max_file_size(dir: &Path, magic: [u8; 4], max_file_size: u64) -> Result<BlockStore, StoreError> {
+        fs::create_dir_all(dir)?;
+        let mut index_bytes = vec![];
+        let mut index_file = OpenOptions::new().read(true).append(true).create(true).open(dir.join(INDEX_FILE_NAME))?;
+        index_file.read_to_end(&mut index_bytes)?;
+
+        let mut index = HashMap::new();
+        // a record cut short by a crash is ignored, the last complete record of a hash wins
+        for record in index_bytes.chunks_exact(INDEX_RECORD_SIZE) {
+            let (hash, entry) = decode_index_record(record).map_err(StoreError::CorruptIndex)?;
+            index.insert(hash, entry);
+        }
+        let complete_size = index_bytes.len() - index_bytes.len() % INDEX_RECORD_SIZE;
+        if complete_size != index_bytes.len() {
+            index_file.set_len(complete_size as u64)?;
+        }
+
+        let current_file = index.values().map(|entry| entry.file).max().unwrap_or(0);
+        let mut store = BlockStore { dir: dir.to_path_buf(), magic, index, index_file, current_file, current_file_size: 0, max_file_size };
+        store.recover_last_file()?;
+        // the node may have stopped right after starting a new file
+        while store.block_file_path(store.current_file + 1).exists() {
+            store.current_file += 1;
+            store.recover_last_file()?;
+        }
+        Ok(store)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn contains(&self, hash: &sha256::Hash) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, hash: &sha256::Hash) -> Option<&BlockIndexEntry> {
+        self.index.get(hash)
+    }
+
+    /// Appends a block at `height` to the block files and indexes it, a block that's already stored is skipped
+    pub fn write_block(&mut self, block: &Block, height: u64) -> Result<(), StoreError> {
+        let hash = block.hash_block();
+        if self.contains(&hash) {
+            return Ok(());
+        }
+        let encoded = block.encode();
+        let record_size = RECORD_HEADER_SIZE + encoded.len() as u64;
+        if self.current_file_size > 0 && self.current_file_size + record_size > self.max_file_size {
+            self.current_file += 1;
+            self.current_file_size = 0;
+        }
+
+        let mut record = Vec::with_capacity(record_size as usize);
+        record.extend_from_slice(&self.magic);
+        record.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        record.extend_from_slice(&encoded);
+        let mut file = OpenOptions::new().append(true).create(true).open(self.block_file_path(self.current_file))?;
+        if let Err(error) = file.write_all(&record).and_then(|_| file.sync_data()) {
+            // drop a partially written record, the next block goes where `current_file_size` expects it
+            let _ = file.set_len(self.current_file_size);
+            return Err(error.into());
+        }
+
+        let entry = BlockIndexEntry {
+            file: self.current_file,
+            offset: self.current_file_size + RECORD_HEADER_SIZE,
+            size: encoded.len() as u32,
+            height,
+            status: BlockStatus::Stored,
+        };
+        self.current_file_size += record_size;
+        self.write_index_record(hash, entry)
+    }
+
+    /// Records the validation state of a stored block
+    pub fn set_status(&mut self, hash: &sha256::Hash, status: BlockStatus) -> Result<(), StoreError> {
+        let Some(entry) = self.index.get(hash) else {
+            return Ok(());
+        };
+        if entry.status == status {
+            return Ok(());
+        }
+        let entry = BlockIndexEntry { status, ..*entry };
+        self.write_index_record(*hash, entry)
+    }
+
+    /// Reads a stored block, None if the block isn't in the index
+    pub fn read_block(&self, hash: &sha256::Hash) -> Result<Option<Block>, StoreError> {
+        let Some(entry) = self.index.get(hash) else {
+            return Ok(None);
+        };
+        let mut file = File::open(self.block_file_path(entry.file))?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut bytes = vec![0; entry.size as usize];
+        file.read_exact(&mut bytes)?;
+        let block = Block::decode(&bytes)
+            .map_err(|error| StoreError::CorruptBlock { file: entry.file, offset: entry.offset, error })?;
+        if block.hash_block() != *hash {
+            return Err(StoreError::HashMismatch(*hash));
+        }
+        Ok(Some(block))
+    }
+
+    /// All stored blocks with their state, ordered by height (then by the order they were stored),
+    /// so a previous block always comes before the blocks built on it
+    pub fn load_blocks(&self) -> Result<Vec<(Block, BlockStatus)>, StoreError> {
+        let mut entries: Vec<(&sha256::Hash, &BlockIndexEntry)> = self.index.iter().collect();
+        entries.sort_by_key(|(_, entry)| (entry.height, entry.file, entry.offset));
+        entries.into_iter()
+            .map(|(hash, entry)| {
+                let block = self.read_block(hash)?.expect("indexed block");
+                Ok((block, entry.status))
+            })
+            .collect()
+    }
+
+    fn write_index_record(&mut self, hash: sha256::Hash, entry: BlockIndexEntry) -> Result<(), StoreError> {
+        let mut record = hash.to_byte_array().to_vec();
+        entry.encode_to(&mut record);
+        self.index_file.write_all(&record)?;
+        self.index_file.sync_data()?;
+        self.index.insert(hash, entry);
+        Ok(())
+    }
+
+    /// Indexes the complete blocks of the last file written after its last indexed block
+    /// and truncates the file after them
+    fn recover_last_file(&mut self) -> Result<(), StoreError> {
+        let path = self.block_file_path(self.current_file);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        let mut position = self.index.values()
+            .filter(|entry| entry.file == self.current_file)
+            .map(|entry| entry.offset + entry.size as u64)
+            .max()
+            .unwrap_or(0);
+
+        while let Some((block, size)) = self.read_record(&bytes, position)? {
+            let hash = block.hash_block();
+            let height = match block.header.previous_block_hash {
+                Some(previous) => self.index.get(&previous).ok_or(StoreError::UnknownParent(previous))?.height + 1,
+                None => 0,
+            };
+            let entry = BlockIndexEntry {
+                file: self.current_file,
+                offset: position + RECORD_HEADER_SIZE,
+                size,
+                height,
+                status: BlockStatus::Stored,
+            };
+            self.write_index_record(hash, entry)?;
+            position += RECORD_HEADER_SIZE + size as u64;
+        }
+
+        if position < bytes.len() as u64 {
+            println!("Dropping {} bytes of a partially written block from {}", bytes.len() as u64 - position, path.display());
+            OpenOptions::new().write(true).open(&path)?.set_len(position)?;
+        }
+        self.current_file_size = position;
+        Ok(())
+    }
+
+    /// Reads the complete block record starting at `position`, None if the record is missing or cut short
+    fn read_record(&self, bytes: &[u8], position: u64) -> Result<Option<(Block, u32)>, StoreError> {
+        let start = position as usize;
+        let Some(header) = bytes.get(start..start + RECORD_HEADER_SIZE as usize) else {
+            return Ok(None);
+        };
+        if header[..4] != self.magic {
+            return Err(StoreError::BadMagic { file: self.current_file, offset: position });
+        }
+        let size = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let block_start = start + RECORD_HEADER_SIZE as usize;
+        let Some(block_bytes) = bytes.get(block_start..block_start + size as usize) else {
+            return Ok(None);
+        };
+        let block = Block::decode(block_bytes)
+            .map_err(|error| StoreError::CorruptBlock { file: self.current_file, offset: position, error })?;
+        Ok(Some((block, size)))
+    }
+
+    fn block_file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(block_file_name(file))
+    }
+}
+
+fn block_file_name(file: u32) -> String {
+    format!("blk{:05}.dat", file)
+}
+
+fn decode_index_record(record: &[u8]) -> Result<(sha256::Hash, BlockIndexEntry), DecodeError> {
+    let mut reader = Reader::new(record);
+    let hash = reader.read_hash()?;
+    let entry = BlockIndexEntry::decode_from(&mut reader)?;
+    Ok((hash, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::params::ChainParams;
+    use crate::core::test_utils::mine_blocks;
+    use crate::utils::temp_dir::TempDir;
+
+    const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    #[test]
+    fn test_write_and_read_blocks() {
+        let dir = TempDir::new();
+        let blocks = mine_blocks(&ChainParams::regtest(), &[], 3);
+        let mut store = BlockStore::open(dir.path(), MAGIC).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            store.write_block(block, height as u64).unwrap();
+        }
+        store.write_block(&blocks[0], 0).unwrap();
+        assert_eq!(store.len(), 3);
+
+        let entry = store.get(&blocks[2].hash_block()).unwrap();
+        assert_eq!(entry.height, 2);
+        assert_eq!(entry.status, BlockStatus::Stored);
+        assert_eq!(store.read_block(&blocks[1].hash_block()), Ok(Some(blocks[1].clone())));
+        assert_eq!(store.read_block(&sha256::Hash::all_zeros()), Ok(None));
+    }
+
+    #[test]
+    fn test_reopen_keeps_blocks_and_status() {
+        let dir = TempDir::new();
+        let blocks = mine_blocks(&ChainParams::regtest(), &[], 3);
+        {
+            let mut store = BlockStore::open(dir.path(), MAGIC).unwrap();
+            // stored out of height order, they are loaded by height
+            store.write_block(&blocks[0], 0).unwrap();
+            store.write_block(&blocks[2], 2).unwrap();
+            store.write_block(&blocks[1], 1).unwrap();
+            store.set_status(&blocks[1].hash_block(), BlockStatus::Valid).unwrap();
+            store.set_status(&blocks[2].hash_block(), BlockStatus::Invalid).unwrap();
+        }
+
+        let store = BlockStore::open(dir.path(), MAGIC).unwrap();
+        let expected = vec![
+            (blocks[0].clone(), BlockStatus::Stored),
+            (blocks[1].clone(), BlockStatus::Valid),
+            (blocks[2].clone(), BlockStatus::Invalid),
+        ];
+        assert_eq!(store.load_blocks(), Ok(expected));
+    }
+
+    #[test]
+    fn test_new_file_when_full() {
+        let dir = TempDir::new();
+        let blocks = mine_blocks(&ChainParams::regtest(), &[], 3);
+        let block_size = RECORD_HEADER_SIZE + blocks[0].encode().len() as u64;
+        let mut store = BlockStore::open_with_max_file_size(dir.path(), MAGIC, 2 * block_size + 10).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            store.write_block(block, height as u64).unwrap();
+        }
+        assert_eq!(store.get(&blocks[1].hash_block()).unwrap().file, 0);
+        assert_eq!(store.get(&blocks[2].hash_block()).unwrap().file, 1);
+        assert_eq!(store.get(&blocks[2].hash_block()).unwrap().offset, RECORD_HEADER_SIZE);
+
+        // appending continues in the last file after a restart
+        drop(store);
+        let store = BlockStore::open_with_max_file_size(dir.path(), MAGIC, 2 * block_size + 10).unwrap();
+        assert_eq!(store.read_block(&blocks[2].hash_block()), Ok(Some(blocks[2].clone())));
+        assert_eq!(store.current_file, 1);
+        assert_eq!(store.current_file_size, block_size);
+    }
+
+    #[test]
+    fn test_recover_unindexed_and_partial_blocks() {
+        let dir = TempDir::new();
+        let blocks = mine_blocks(&ChainParams::regtest(), &[], 3);
+        {
+            let mut store = BlockStore::open(dir.path(), MAGIC).unwrap();
+            for (height, block) in blocks.iter().enumerate() {
+                store.write_block(block, height as u64).unwrap();
+            }
+        }
+        // the node stopped before the last index record was complete and while a block was being written
+        let index_path = dir.path().join(INDEX_FILE_NAME);
+        let index_size = fs::metadata(&index_path).unwrap().len();
+        OpenOptions::new().write(true).open(&index_path).unwrap().set_len(index_size - 10).unwrap();
+        let block_path = dir.path().join(block_file_name(0));
+        let complete_size = fs::metadata(&block_path).unwrap().len();
+        let mut block_file = OpenOptions::new().append(true).open(&block_path).unwrap();
+        block_file.write_all(&MAGIC).unwrap();
+        block_file.write_all(&[0xff, 0x00]).unwrap();
+
+        let mut store = BlockStore::open(dir.path(), MAGIC).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(&blocks[2].hash_block()).unwrap().height, 2);
+        assert_eq!(fs::metadata(&block_path).unwrap().len(), complete_size);
+        assert_eq!(fs::metadata(&index_path).unwrap().len(), 3 * INDEX_RECORD_SIZE as u64);
+
+        // new blocks are appended after the recovered ones
+        let block = mine_blocks(&ChainParams::regtest(), &[], 1).remove(0);
+        store.write_block(&block, 0).unwrap();
+        assert_eq!(store.read_block(&block.hash_block()), Ok(Some(block)));
+    }
+
+    #[test]
+    fn test_other_network_blocks() {
+        let dir = TempDir::new();
+        let blocks = mine_blocks(&ChainParams::regtest(), &[], 1);
+        BlockStore::open(dir.path(), MAGIC).unwrap().write_block(&blocks[0], 0).unwrap();
+        fs::remove_file(dir.path().join(INDEX_FILE_NAME)).unwrap();
+
+        let other_magic = [0xf9, 0xbe, 0xb4, 0xd9];
+        assert_eq!(BlockStore::open(dir.path(), other_magic).unwrap_err(), StoreError::BadMagic { file: 0, offset: 0 });
+    }
+}
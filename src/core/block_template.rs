@@ -0,0 +1,217 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use secp256k1::hashes::sha256;
+use secp256k1::PublicKey;
+
+use crate::constants::SOFTWARE_VERSION;
+use crate::core::block::{Block, BlockHeader};
+use crate::core::mempool::{Mempool, MempoolEntry};
+use crate::core::params::ChainParams;
+use crate::core::pow::expected_difficulty_target;
+use crate::core::serialize::Encodable;
+use crate::core::transaction::{calculate_merkle_root, p2pkh_script, Transaction};
+use crate::core::validation::{block_subsidy, median_time_past};
+use crate::utils::time::get_current_timestamp_ms;
+
+/// Bytes kept free for the transaction count, its CompactSize encoding grows with the number of transactions
+const TRANSACTION_COUNT_RESERVED_SIZE: usize = 8;
+
+/// Unmined block ready to be passed to `mine_block`
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    /// Block with a zero nonce, its coinbase claims the block subsidy plus the fees of the other transactions
+    pub block: Block,
+    /// Size of the encoded block in bytes
+    pub size: usize,
+}
+
+impl BlockTemplate {
+    /// Builds a block on top of `previous_headers` containing a coinbase paying `miner_pub_key`
+    /// followed by `transactions`, which pay `fees` in total
+    pub fn new(miner_pub_key: PublicKey, previous_headers: &[BlockHeader], transactions: Vec<Transaction>, fees: u128, params: &ChainParams) -> BlockTemplate {
+        let height = previous_headers.len() as u64;
+        let coinbase_value = block_subsidy(height, params) + fees;
+        let coinbase_transaction = Transaction::new_coinbase_transaction(p2pkh_script(&miner_pub_key), miner_pub_key, height, coinbase_value);
+        let mut all_transactions = vec![coinbase_transaction.clone()];
+        all_transactions.extend(transactions);
+        let merkle_root = calculate_merkle_root(&all_transactions);
+        let block = Block::new(
+            SOFTWARE_VERSION.to_string(),
+            previous_headers.last().map(|header| header.hash()),
+            merkle_root,
+            // the timestamp has to be later than the median time past even if the local clock is behind
+            get_current_timestamp_ms().max(median_time_past(previous_headers) + 1),
+            expected_difficulty_target(previous_headers, params),
+            0,
+            all_transactions,
+            coinbase_transaction
+        );
+        let size = block.encode().len();
+        BlockTemplate { block, size }
+    }
+
+    /// Builds a block template with the mempool transactions paying the highest fee rates
+    /// that fit in a block of `max_block_size` bytes (see `select_transactions`)
+    pub fn from_mempool(miner_pub_key: PublicKey, previous_headers: &[BlockHeader], mempool: &Mempool, max_block_size: usize, params: &ChainParams) -> BlockTemplate {
+        let empty_size = BlockTemplate::new(miner_pub_key, previous_headers, vec![], 0, params).size;
+        let available_size = max_block_size.saturating_sub(empty_size + TRANSACTION_COUNT_RESERVED_SIZE);
+        let (transactions, fees) = select_transactions(mempool, available_size);
+        BlockTemplate::new(miner_pub_key, previous_headers, transactions, fees, params)
+    }
+}
+
+/// Fee and size of a mempool transaction together with its ancestors that are not selected yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Package {
+    fee: u128,
+    size: usize,
+    txid: sha256::Hash,
+}
+
+/// Packages are ordered by fee rate (fee / size)
+impl Ord for Package {
+    fn cmp(&self, other: &Package) -> Ordering {
+        (self.fee * other.size as u128).cmp(&(other.fee * self.size as u128))
+            .then_with(|| self.fee.cmp(&other.fee))
+            .then_with(|| other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Package) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Selects mempool transactions by ancestor fee rate: the transaction whose package
+/// (itself and its unselected ancestors) has the highest fee rate is added together with its ancestors,
+/// so a high fee child pays for its low fee parents. Packages that don't fit in `max_size` bytes are skipped.
+/// Returns the transactions (parents before children) and the fees they pay
+pub fn select_transactions(mempool: &Mempool, max_size: usize) -> (Vec<Transaction>, u128) {
+    let entries = mempool.entries();
+    let mut ancestor_counts: HashMap<sha256::Hash, usize> = HashMap::new();
+    let mut packages: HashMap<sha256::Hash, Package> = HashMap::new();
+    for entry in &entries {
+        let ancestors = mempool.ancestors(&entry.txid);
+        let (fee, size) = ancestors.iter()
+            .filter_map(|txid| mempool.get(txid))
+            .fold((entry.fee, entry.size), |(fee, size), ancestor| (fee + ancestor.fee, size + ancestor.size));
+        ancestor_counts.insert(entry.txid, ancestors.len());
+        packages.insert(entry.txid, Package { fee, size, txid: entry.txid });
+    }
+
+    // outdated packages stay in the heap and are skipped when they don't match `packages`
+    let mut queue: BinaryHeap<Package> = packages.values().copied().collect();
+    let mut selected: HashSet<sha256::Hash> = HashSet::new();
+    let mut transactions = vec![];
+    let mut fees: u128 = 0;
+    let mut size = 0;
+    while let Some(package) = queue.pop() {
+        if selected.contains(&package.txid) || packages.get(&package.txid) != Some(&package) {
+            continue;
+        }
+        if size + package.size > max_size {
+            continue;
+        }
+
+        let mut package_entries: Vec<&MempoolEntry> = mempool.ancestors(&package.txid).iter()
+            .filter(|txid| !selected.contains(*txid))
+            .chain(std::iter::once(&package.txid))
+            .filter_map(|txid| mempool.get(txid))
+            .collect();
+        // an ancestor always has fewer ancestors than its descendants
+        package_entries.sort_by_key(|entry| ancestor_counts[&entry.txid]);
+
+        for entry in package_entries {
+            selected.insert(entry.txid);
+            transactions.push(entry.transaction.clone());
+            fees += entry.fee;
+            size += entry.size;
+            for descendant in mempool.descendants(&entry.txid) {
+                if selected.contains(&descendant) {
+                    continue;
+                }
+                if let Some(descendant_package) = packages.get_mut(&descendant) {
+                    descendant_package.fee -= entry.fee;
+                    descendant_package.size -= entry.size;
+                    queue.push(*descendant_package);
+                }
+            }
+        }
+    }
+    (transactions, fees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_utils::{create_funded_utxo_set, create_spend, COINBASE_VALUE};
+    use crate::core::utxo::OutPoint;
+    use crate::utils::wallets::generate_keypair;
+
+    #[test]
+    fn test_selection_by_fee_rate() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(3);
+        let mut mempool = Mempool::default();
+        let low = mempool.add_transaction(create_spend(&[outpoints[0]], COINBASE_VALUE, 1_000, 1, &secret_key), &utxo_set).unwrap();
+        let high = mempool.add_transaction(create_spend(&[outpoints[1]], COINBASE_VALUE, 3_000, 1, &secret_key), &utxo_set).unwrap();
+        let medium = mempool.add_transaction(create_spend(&[outpoints[2]], COINBASE_VALUE, 2_000, 1, &secret_key), &utxo_set).unwrap();
+
+        let (transactions, fees) = select_transactions(&mempool, usize::MAX);
+        let order: Vec<sha256::Hash> = transactions.iter().map(|transaction| transaction.hash()).collect();
+        assert_eq!(order, vec![high, medium, low]);
+        assert_eq!(fees, 6_000);
+
+        // only two transactions fit
+        let size = mempool.get(&high).unwrap().size + mempool.get(&medium).unwrap().size.max(mempool.get(&low).unwrap().size);
+        let (transactions, fees) = select_transactions(&mempool, size);
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(fees, 5_000);
+        assert_eq!(select_transactions(&mempool, 0), (vec![], 0));
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(2);
+        let mut mempool = Mempool::default();
+        let parent = mempool.add_transaction(create_spend(&[outpoints[0]], COINBASE_VALUE, 100, 1, &secret_key), &utxo_set).unwrap();
+        let child_spend = create_spend(&[OutPoint { txid: parent, index: 0 }], COINBASE_VALUE - 100, 10_000, 1, &secret_key);
+        let child = mempool.add_transaction(child_spend, &utxo_set).unwrap();
+        let other = mempool.add_transaction(create_spend(&[outpoints[1]], COINBASE_VALUE, 3_000, 1, &secret_key), &utxo_set).unwrap();
+
+        let (transactions, fees) = select_transactions(&mempool, usize::MAX);
+        let order: Vec<sha256::Hash> = transactions.iter().map(|transaction| transaction.hash()).collect();
+        assert_eq!(order, vec![parent, child, other]);
+        assert_eq!(fees, 13_100);
+
+        // only the parent and child package fits
+        let package_size = mempool.get(&parent).unwrap().size + mempool.get(&child).unwrap().size;
+        let (transactions, _) = select_transactions(&mempool, package_size);
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].hash(), parent);
+    }
+
+    #[test]
+    fn test_template_from_mempool() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(2);
+        let mut mempool = Mempool::default();
+        for outpoint in &outpoints {
+            mempool.add_transaction(create_spend(&[*outpoint], COINBASE_VALUE, 1_000, 1, &secret_key), &utxo_set).unwrap();
+        }
+        let (_, miner_pub_key) = generate_keypair();
+        let params = ChainParams::regtest();
+
+        let template = BlockTemplate::from_mempool(miner_pub_key, &[], &mempool, usize::MAX, &params);
+        assert_eq!(template.block.transactions.len(), 3);
+        assert_eq!(template.size, template.block.encode().len());
+        assert_eq!(template.block.coinbase_transaction.outputs[0].value, COINBASE_VALUE + 2_000);
+        assert_eq!(template.block.header.merkle_root, calculate_merkle_root(&template.block.transactions));
+
+        // a block too small for any transaction only has the coinbase
+        let empty_size = BlockTemplate::new(miner_pub_key, &[], vec![], 0, &params).size;
+        let template = BlockTemplate::from_mempool(miner_pub_key, &[], &mempool, empty_size + 100, &params);
+        assert_eq!(template.block.transactions.len(), 1);
+        assert_eq!(template.block.coinbase_transaction.outputs[0].value, COINBASE_VALUE);
+    }
+}
@@ -0,0 +1,617 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use secp256k1::hashes::sha256;
+
+use crate::core::block::{Block, BlockHeader};
+use crate::core::block_store::{BlockStatus, BlockStore, StoreError};
+use crate::core::chainstate::Chainstate;
+use crate::core::consensus::Node;
+use crate::core::params::ChainParams;
+use crate::core::pow::{block_work, check_proof_of_work};
+use crate::core::utxo::UtxoSet;
+use crate::core::validation::ValidationError;
+use crate::utils::u256::U256;
+
+/// Block stored in the tree
+#[derive(Debug, Clone)]
+pub struct BlockEntry {
+    pub block: Block,
+    pub height: u64,
+    /// Total work of the chain ending with this block
+    pub chain_work: U256,
+    /// Set when the block (or one of its ancestors) failed validation while being connected
+    pub invalid: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    /// The block is already in the tree
+    Duplicate,
+    /// The previous block isn't in the tree
+    UnknownParent(sha256::Hash),
+    /// A block without a previous block was received but the tree already has a genesis block
+    UnexpectedGenesis,
+    /// The previous block failed validation
+    InvalidParent(sha256::Hash),
+    /// The block doesn't have a valid proof of work for its position
+    /// or it failed validation when it was connected to the active chain
+    InvalidBlock { hash: sha256::Hash, error: ValidationError },
+    /// The block couldn't be written to the block storage
+    Store(StoreError),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::Duplicate => write!(f, "block is already known"),
+            ChainError::UnknownParent(hash) => write!(f, "previous block {} is unknown", hash),
+            ChainError::UnexpectedGenesis => write!(f, "the genesis block is already known"),
+            ChainError::InvalidParent(hash) => write!(f, "previous block {} is invalid", hash),
+            ChainError::InvalidBlock { hash, error } => write!(f, "block {} is invalid: {}", hash, error),
+            ChainError::Store(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Changes of the active chain caused by a new block
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainUpdate {
+    /// Blocks removed from the active chain, starting with the old tip
+    pub disconnected: Vec<Block>,
+    /// Blocks added to the active chain, ending with the new tip
+    pub connected: Vec<Block>,
+}
+
+impl ChainUpdate {
+    /// True if the block was stored on a side branch without changing the active chain
+    pub fn is_empty(&self) -> bool {
+        self.disconnected.is_empty() && self.connected.is_empty()
+    }
+}
+
+/// Tree of all known blocks, including competing branches.
+/// The active chain is the valid branch with the most cumulative work,
+/// the UTXO set of `chainstate` always matches its tip.
+/// With a block storage, every block added to the tree is also written to disk
+#[derive(Debug)]
+pub struct BlockTree {
+    params: ChainParams,
+    blocks: HashMap<sha256::Hash, BlockEntry>,
+    /// Hashes of the active chain blocks, indexed by height
+    active_chain: Vec<sha256::Hash>,
+    /// Headers of the active chain blocks, validation borrows them instead of collecting them for every block
+    active_headers: Vec<BlockHeader>,
+    /// UTXO set and undo data of the active chain
+    chainstate: Chainstate,
+    store: Option<BlockStore>,
+}
+
+impl BlockTree {
+    pub fn new(params: ChainParams) -> BlockTree {
+        BlockTree {
+            params,
+            blocks: HashMap::new(),
+            active_chain: vec![],
+            active_headers: vec![],
+            chainstate: Chainstate::new(),
+            store: None,
+        }
+    }
+
+    /// Rebuilds the tree from the blocks of `store` and continues from the state saved in `chainstate`,
+    /// new blocks are written to the store. The stored chain has to start with the genesis block of `params`.
+    /// Blocks that failed validation before are skipped together with their descendants.
+    /// Stored blocks with more work than the chainstate's best block (e.g. connected after its last flush)
+    /// are connected again. If the best block isn't stored or its undo data is incomplete
+    /// (the node wasn't shut down cleanly), the chainstate is rebuilt by connecting all stored blocks from the genesis block
+    pub fn open(params: ChainParams, store: BlockStore, chainstate: Chainstate) -> Result<BlockTree, ChainError> {
+        let mut tree = BlockTree::new(params);
+        // stored blocks in the order they were loaded, earlier blocks win ties in work
+        let mut stored = vec![];
+        for (block, status) in store.load_blocks().map_err(ChainError::Store)? {
+            if status == BlockStatus::Invalid {
+                continue;
+            }
+            let hash = block.hash_block();
+            let (height, parent_work) = match block.header.previous_block_hash {
+                Some(previous) => match tree.blocks.get(&previous) {
+                    Some(parent) => (parent.height + 1, parent.chain_work),
+                    // a descendant of an invalid block
+                    None => continue,
+                },
+                None if hash == tree.params.genesis_hash() => (0, U256::ZERO),
+                // the stored chain doesn't follow these params
+                None => return Err(ChainError::UnexpectedGenesis),
+            };
+            let chain_work = parent_work + block_work(block.header.difficulty_target);
+            tree.blocks.insert(hash, BlockEntry { block, height, chain_work, invalid: false });
+            stored.push(hash);
+        }
+
+        tree.chainstate = chainstate;
+        match tree.chainstate_branch() {
+            Some(branch) => {
+                tree.active_headers = branch.iter().map(|hash| tree.blocks[hash].block.header.clone()).collect();
+                tree.active_chain = branch;
+            }
+            None => {
+                println!("The chainstate doesn't match the stored blocks, connecting all blocks again");
+                tree.chainstate.reset();
+            }
+        }
+        tree.store = Some(store);
+
+        let mut connected = 0;
+        loop {
+            let mut best: Option<&BlockEntry> = None;
+            for hash in &stored {
+                let entry = &tree.blocks[hash];
+                if !entry.invalid && best.is_none_or(|best| entry.chain_work > best.chain_work) {
+                    best = Some(entry);
+                }
+            }
+            let Some(best) = best else { break };
+            if tree.tip().is_some_and(|tip| best.chain_work <= tip.chain_work) {
+                break;
+            }
+            let hash = best.block.hash_block();
+            match tree.activate_branch(hash) {
+                Ok(update) => connected += update.connected.len(),
+                Err(error) => println!("Stored block can't be connected: {}", error),
+            }
+        }
+        if connected > 0 {
+            println!("Connected {} stored blocks", connected);
+        }
+        tree.chainstate.flush().map_err(ChainError::Store)?;
+        Ok(tree)
+    }
+
+    /// Branch ending with the chainstate's best block, None if the best block isn't in the tree
+    /// or a block of the branch has no undo data
+    fn chainstate_branch(&self) -> Option<Vec<sha256::Hash>> {
+        let Some(best_block) = self.chainstate.best_block() else {
+            return self.chainstate.utxo_set().is_empty().then(Vec::new);
+        };
+        let mut branch = vec![];
+        let mut current = Some(best_block);
+        while let Some(hash) = current {
+            self.chainstate.undo(&hash)?;
+            current = self.blocks.get(&hash)?.block.header.previous_block_hash;
+            branch.push(hash);
+        }
+        branch.reverse();
+        Some(branch)
+    }
+
+    #[cfg(test)]
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Number of blocks in the active chain
+    pub fn len(&self) -> usize {
+        self.active_chain.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active_chain.is_empty()
+    }
+
+    pub fn tip(&self) -> Option<&BlockEntry> {
+        self.active_chain.last().map(|hash| &self.blocks[hash])
+    }
+
+    pub fn get(&self, hash: &sha256::Hash) -> Option<&BlockEntry> {
+        self.blocks.get(hash)
+    }
+
+    pub fn contains(&self, hash: &sha256::Hash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn utxo_set(&self) -> &UtxoSet {
+        self.chainstate.utxo_set()
+    }
+
+    /// Writes the unflushed chainstate changes to disk
+    #[cfg(test)]
+    pub fn flush(&mut self) -> Result<(), StoreError> {
+        self.chainstate.flush()
+    }
+
+    /// Headers of the active chain, from the genesis block to the tip
+    pub fn active_headers(&self) -> &[BlockHeader] {
+        &self.active_headers
+    }
+
+    /// Headers of the active chain after the first block of `locator` that's in the active chain
+    /// (after the genesis block if there is none), up to the block `stop_hash` and at most `max_headers` of them
+    pub fn locate_headers(&self, locator: &[sha256::Hash], stop_hash: Option<sha256::Hash>, max_headers: usize) -> Vec<BlockHeader> {
+        let fork_height = locator.iter()
+            .filter_map(|hash| self.blocks.get(hash).filter(|entry| self.is_active(hash, entry.height)))
+            .map(|entry| entry.height as usize)
+            .next()
+            .unwrap_or(0);
+        let mut headers = vec![];
+        for hash in self.active_chain.iter().skip(fork_height + 1).take(max_headers) {
+            headers.push(self.blocks[hash].block.header.clone());
+            if Some(*hash) == stop_hash {
+                break;
+            }
+        }
+        headers
+    }
+
+    fn is_active(&self, hash: &sha256::Hash, height: u64) -> bool {
+        self.active_chain.get(height as usize) == Some(hash)
+    }
+
+    /// Headers of the branch ending with `hash`, from the genesis block to that block.
+    /// A branch of the active chain is borrowed, otherwise the active headers up to the fork point are copied
+    fn branch_headers(&self, hash: &sha256::Hash) -> Cow<'_, [BlockHeader]> {
+        let mut branch = vec![];
+        let mut current = Some(*hash);
+        while let Some(hash) = current {
+            let Some(entry) = self.blocks.get(&hash) else { break };
+            if self.is_active(&hash, entry.height) {
+                let active = &self.active_headers[..=entry.height as usize];
+                if branch.is_empty() {
+                    return Cow::Borrowed(active);
+                }
+                branch.extend(active.iter().rev().cloned());
+                break;
+            }
+            branch.push(entry.block.header.clone());
+            current = entry.block.header.previous_block_hash;
+        }
+        branch.reverse();
+        Cow::Owned(branch)
+    }
+
+    /// Adds a block to the tree. Its proof of work is checked against its own branch,
+    /// the rest of the validation happens when the block is connected to the active chain.
+    /// If the block makes its branch the one with the most work, the active chain is reorganized:
+    /// blocks after the fork point are disconnected and the new branch is connected.
+    /// If a block of the new branch is invalid, the previous active chain is restored
+    pub fn accept_block(&mut self, block: Block) -> Result<ChainUpdate, ChainError> {
+        let hash = block.hash_block();
+        if self.contains(&hash) {
+            return Err(ChainError::Duplicate);
+        }
+        let (height, parent_work, previous_headers) = match block.header.previous_block_hash {
+            Some(previous) => {
+                let parent = self.blocks.get(&previous).ok_or(ChainError::UnknownParent(previous))?;
+                if parent.invalid {
+                    return Err(ChainError::InvalidParent(previous));
+                }
+                (parent.height + 1, parent.chain_work, self.branch_headers(&previous))
+            }
+            // the chain has to start with the genesis block of the network
+            None if self.blocks.is_empty() && hash != self.params.genesis_hash() => {
+                return Err(ChainError::InvalidBlock { hash, error: ValidationError::BadGenesisBlock(hash) });
+            }
+            None if self.blocks.is_empty() => (0, U256::ZERO, Cow::Borrowed(&[][..])),
+            None => return Err(ChainError::UnexpectedGenesis),
+        };
+        check_proof_of_work(&block, &previous_headers, &self.params).map_err(|error| ChainError::InvalidBlock { hash, error })?;
+
+        if let Some(store) = &mut self.store {
+            store.write_block(&block, height).map_err(ChainError::Store)?;
+        }
+        let chain_work = parent_work + block_work(block.header.difficulty_target);
+        self.blocks.insert(hash, BlockEntry { block, height, chain_work, invalid: false });
+        let tip_work = self.tip().map(|tip| tip.chain_work);
+        if tip_work.is_some_and(|tip_work| chain_work <= tip_work) {
+            return Ok(ChainUpdate::default());
+        }
+        let update = self.activate_branch(hash);
+        if let Err(error) = self.chainstate.flush_if_needed() {
+            println!("Can't flush the chainstate: {}", error);
+        }
+        update
+    }
+
+    /// Makes the branch ending with `hash` the active chain
+    fn activate_branch(&mut self, hash: sha256::Hash) -> Result<ChainUpdate, ChainError> {
+        // blocks of the new branch that are not in the active chain, from the tip back to the fork point
+        let mut branch = vec![];
+        let mut current = hash;
+        loop {
+            let entry = &self.blocks[&current];
+            if self.is_active(&current, entry.height) {
+                break;
+            }
+            branch.push(current);
+            match entry.block.header.previous_block_hash {
+                Some(previous) => current = previous,
+                None => break,
+            }
+        }
+        branch.reverse();
+        let fork_height = self.blocks[&branch[0]].height as usize;
+
+        let mut update = ChainUpdate::default();
+        while self.active_chain.len() > fork_height {
+            update.disconnected.push(self.disconnect_tip());
+        }
+        for (i, block_hash) in branch.iter().enumerate() {
+            let Err(error) = self.connect_block(block_hash) else {
+                update.connected.push(self.blocks[block_hash].block.clone());
+                self.set_status(block_hash, BlockStatus::Valid);
+                continue;
+            };
+            // the new branch is invalid from this block on, go back to the previous active chain
+            for invalid_hash in &branch[i..] {
+                if let Some(entry) = self.blocks.get_mut(invalid_hash) {
+                    entry.invalid = true;
+                }
+                self.set_status(invalid_hash, BlockStatus::Invalid);
+            }
+            for _ in 0..update.connected.len() {
+                self.disconnect_tip();
+            }
+            for block in update.disconnected.iter().rev() {
+                self.connect_block(&block.hash_block()).expect("previously active block connects again");
+            }
+            return Err(ChainError::InvalidBlock { hash: *block_hash, error });
+        }
+        Ok(update)
+    }
+
+    /// Validates a block on top of the active chain and applies it to the UTXO set
+    fn connect_block(&mut self, hash: &sha256::Hash) -> Result<(), ValidationError> {
+        let block = &self.blocks[hash].block;
+        Node::validate_block(block, &self.active_headers, self.chainstate.utxo_set(), &self.params)?;
+        self.chainstate.connect_block(block, self.active_chain.len() as u64)?;
+        self.active_chain.push(*hash);
+        self.active_headers.push(block.header.clone());
+        Ok(())
+    }
+
+    /// Records the validation state of a stored block. The state only saves work after a restart,
+    /// so a failed write is reported without failing the block
+    fn set_status(&mut self, hash: &sha256::Hash, status: BlockStatus) {
+        if let Some(store) = &mut self.store {
+            if let Err(error) = store.set_status(hash, status) {
+                println!("Can't store the status of block {}: {}", hash, error);
+            }
+        }
+    }
+
+    /// Removes the tip of the active chain and restores the UTXO set from its undo data
+    fn disconnect_tip(&mut self) -> Block {
+        let hash = self.active_chain.pop().expect("active chain isn't empty");
+        self.active_headers.pop();
+        let block = self.blocks[&hash].block.clone();
+        self.chainstate.disconnect_block(&block).expect("undo data matches the block");
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::PublicKey;
+    use crate::core::block_template::BlockTemplate;
+    use crate::core::chainstate::Chainstate;
+    use crate::core::mining::mine_block;
+    use crate::core::utxo::OutPoint;
+    use crate::utils::hash::sha256_hash;
+    use crate::utils::temp_dir::TempDir;
+    use crate::utils::wallets::generate_keypair;
+
+    fn mine_on(tree: &BlockTree, parent: Option<&Block>, fees: u128) -> Block {
+        let (_, pub_key): (_, PublicKey) = generate_keypair();
+        let previous_headers = parent.map(|parent| tree.branch_headers(&parent.hash_block())).unwrap_or_default();
+        mine_block(BlockTemplate::new(pub_key, &previous_headers, vec![], fees, tree.params()).block, || false).unwrap()
+    }
+
+    fn coinbase_outpoint(block: &Block) -> OutPoint {
+        OutPoint { txid: block.coinbase_transaction.hash(), index: 0 }
+    }
+
+    #[test]
+    fn test_extend_active_chain() {
+        let mut tree = BlockTree::new(ChainParams::regtest());
+        let genesis = tree.params().genesis_block();
+        assert_eq!(tree.accept_block(genesis.clone()).unwrap().connected, vec![genesis.clone()]);
+        let block = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(block.clone()).unwrap();
+
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.tip().unwrap().block, block);
+        assert_eq!(tree.tip().unwrap().height, 1);
+        assert_eq!(tree.active_headers(), vec![genesis.header.clone(), block.header.clone()]);
+        assert!(tree.utxo_set().contains(&coinbase_outpoint(&block)));
+        assert_eq!(tree.accept_block(block), Err(ChainError::Duplicate));
+    }
+
+    #[test]
+    fn test_reject_unconnected_blocks() {
+        let mut tree = BlockTree::new(ChainParams::regtest());
+        tree.accept_block(tree.params().genesis_block()).unwrap();
+        let block = mine_on(&tree, None, 0);
+
+        assert_eq!(tree.accept_block(block.clone()), Err(ChainError::UnexpectedGenesis));
+        let mut orphan = block;
+        orphan.header.previous_block_hash = Some(sha256_hash("unknown"));
+        assert!(matches!(tree.accept_block(orphan), Err(ChainError::UnknownParent(_))));
+    }
+
+    #[test]
+    fn test_side_branch_and_reorg() {
+        let mut tree = BlockTree::new(ChainParams::regtest());
+        let genesis = tree.params().genesis_block();
+        tree.accept_block(genesis.clone()).unwrap();
+        let a1 = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(a1.clone()).unwrap();
+
+        // a competing block with the same work doesn't replace the first one
+        let b1 = mine_on(&tree, Some(&genesis), 0);
+        assert!(tree.accept_block(b1.clone()).unwrap().is_empty());
+        assert_eq!(tree.tip().unwrap().block, a1);
+        assert!(tree.contains(&b1.hash_block()));
+
+        let b2 = mine_on(&tree, Some(&b1), 0);
+        let update = tree.accept_block(b2.clone()).unwrap();
+        assert_eq!(update.disconnected, vec![a1.clone()]);
+        assert_eq!(update.connected, vec![b1.clone(), b2.clone()]);
+        assert_eq!(tree.tip().unwrap().block, b2);
+        assert_eq!(tree.len(), 3);
+        assert!(!tree.utxo_set().contains(&coinbase_outpoint(&a1)));
+        assert!(tree.utxo_set().contains(&coinbase_outpoint(&b1)));
+        assert_eq!(tree.utxo_set().len(), 3);
+    }
+
+    #[test]
+    fn test_invalid_branch_is_rolled_back() {
+        let mut tree = BlockTree::new(ChainParams::regtest());
+        let genesis = tree.params().genesis_block();
+        tree.accept_block(genesis.clone()).unwrap();
+        let a1 = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(a1.clone()).unwrap();
+        let b1 = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(b1.clone()).unwrap();
+
+        // the coinbase claims fees that no transaction pays
+        let b2 = mine_on(&tree, Some(&b1), 1);
+        let subsidy = tree.params().initial_subsidy;
+        let error = ValidationError::CoinbaseValueTooHigh { claimed: subsidy + 1, allowed: subsidy };
+        assert_eq!(tree.accept_block(b2.clone()), Err(ChainError::InvalidBlock { hash: b2.hash_block(), error }));
+        assert_eq!(tree.tip().unwrap().block, a1);
+        assert!(tree.utxo_set().contains(&coinbase_outpoint(&a1)));
+        assert!(!tree.utxo_set().contains(&coinbase_outpoint(&b1)));
+        assert!(tree.get(&b2.hash_block()).unwrap().invalid);
+
+        let b3 = mine_on(&tree, Some(&b2), 0);
+        assert_eq!(tree.accept_block(b3), Err(ChainError::InvalidParent(b2.hash_block())));
+    }
+
+    #[test]
+    fn test_locate_headers() {
+        let mut tree = BlockTree::new(ChainParams::regtest());
+        let genesis = tree.params().genesis_block();
+        tree.accept_block(genesis.clone()).unwrap();
+        let mut blocks = vec![genesis];
+        for _ in 0..3 {
+            let block = mine_on(&tree, blocks.last(), 0);
+            tree.accept_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+        let side_block = mine_on(&tree, Some(&blocks[0]), 0);
+        tree.accept_block(side_block.clone()).unwrap();
+        let headers = |range: std::ops::Range<usize>| blocks[range].iter().map(|block| block.header.clone()).collect::<Vec<_>>();
+
+        assert_eq!(tree.locate_headers(&[blocks[1].hash_block()], None, 10), headers(2..4));
+        // blocks of side branches and unknown blocks are skipped
+        let locator = [sha256_hash("unknown"), side_block.hash_block(), blocks[2].hash_block()];
+        assert_eq!(tree.locate_headers(&locator, None, 10), headers(3..4));
+        assert_eq!(tree.locate_headers(&[], None, 10), headers(1..4));
+        assert_eq!(tree.locate_headers(&[], Some(blocks[2].hash_block()), 10), headers(1..3));
+        assert_eq!(tree.locate_headers(&[], None, 1), headers(1..2));
+        assert!(tree.locate_headers(&[blocks[3].hash_block()], None, 10).is_empty());
+    }
+
+    #[test]
+    fn test_reject_other_genesis_block() {
+        let mut tree = BlockTree::new(ChainParams::regtest());
+        let genesis = mine_on(&tree, None, 0);
+        let error = ValidationError::BadGenesisBlock(genesis.hash_block());
+        assert_eq!(tree.accept_block(genesis.clone()), Err(ChainError::InvalidBlock { hash: genesis.hash_block(), error }));
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_reject_invalid_proof_of_work() {
+        let mut tree = BlockTree::new(ChainParams::regtest());
+        let genesis = tree.params().genesis_block();
+        tree.accept_block(genesis.clone()).unwrap();
+        let mut block = mine_on(&tree, Some(&genesis), 0);
+        let expected = block.header.difficulty_target;
+        block.header.difficulty_target = 0;
+        let error = ValidationError::BadDifficultyTarget { expected, found: 0 };
+        assert_eq!(tree.accept_block(block.clone()), Err(ChainError::InvalidBlock { hash: block.hash_block(), error }));
+        assert_eq!(tree.len(), 1);
+    }
+
+    fn open_tree(params: &ChainParams, dir: &TempDir, chainstate_dir: &str) -> BlockTree {
+        let store = BlockStore::open(&dir.path().join("blocks"), params.magic).unwrap();
+        let chainstate = Chainstate::open(&dir.path().join(chainstate_dir)).unwrap();
+        BlockTree::open(params.clone(), store, chainstate).unwrap()
+    }
+
+    #[test]
+    fn test_reopen_from_block_store() {
+        let dir = TempDir::new();
+        let params = ChainParams::regtest();
+        let mut tree = open_tree(&params, &dir, "chainstate");
+        let genesis = params.genesis_block();
+        tree.accept_block(genesis.clone()).unwrap();
+        let a1 = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(a1.clone()).unwrap();
+        let b1 = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(b1.clone()).unwrap();
+        let b2 = mine_on(&tree, Some(&b1), 1);
+        assert!(tree.accept_block(b2.clone()).is_err());
+        drop(tree);
+
+        let store = BlockStore::open(&dir.path().join("blocks"), params.magic).unwrap();
+        // b1 was connected before b2 turned out to be invalid
+        assert_eq!(store.get(&b1.hash_block()).unwrap().status, BlockStatus::Valid);
+        assert_eq!(store.get(&b2.hash_block()).unwrap().status, BlockStatus::Invalid);
+        drop(store);
+        let tree = open_tree(&params, &dir, "chainstate");
+        assert_eq!(tree.active_headers(), vec![genesis.header, a1.header.clone()]);
+        assert!(tree.utxo_set().contains(&coinbase_outpoint(&a1)));
+        assert!(tree.contains(&b1.hash_block()));
+        // the invalid block isn't validated again
+        assert!(!tree.contains(&b2.hash_block()));
+    }
+
+    #[test]
+    fn test_reopen_connects_blocks_after_best_block() {
+        let dir = TempDir::new();
+        let params = ChainParams::regtest();
+        let mut tree = open_tree(&params, &dir, "chainstate");
+        let genesis = params.genesis_block();
+        tree.accept_block(genesis.clone()).unwrap();
+        let a1 = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(a1.clone()).unwrap();
+        tree.flush().unwrap();
+        let a2 = mine_on(&tree, Some(&a1), 0);
+        tree.accept_block(a2.clone()).unwrap();
+        // the node stops without flushing the chainstate, a2 is only in the block store
+        std::mem::forget(tree);
+
+        let tree = open_tree(&params, &dir, "chainstate");
+        assert_eq!(tree.tip().unwrap().block, a2);
+        assert_eq!(tree.utxo_set().len(), 3);
+        assert_eq!(tree.chainstate.best_block(), Some(a2.hash_block()));
+    }
+
+    #[test]
+    fn test_inconsistent_chainstate_is_rebuilt() {
+        let dir = TempDir::new();
+        let params = ChainParams::regtest();
+        let mut tree = open_tree(&params, &dir, "chainstate");
+        let genesis = params.genesis_block();
+        tree.accept_block(genesis.clone()).unwrap();
+        let a1 = mine_on(&tree, Some(&genesis), 0);
+        tree.accept_block(a1.clone()).unwrap();
+        drop(tree);
+
+        // a chainstate whose best block isn't stored
+        let unknown_block = mine_on(&BlockTree::new(params.clone()), None, 0);
+        let mut chainstate = Chainstate::open(&dir.path().join("other_chainstate")).unwrap();
+        chainstate.connect_block(&unknown_block, 0).unwrap();
+        drop(chainstate);
+
+        let tree = open_tree(&params, &dir, "other_chainstate");
+        assert_eq!(tree.active_headers(), vec![genesis.header.clone(), a1.header.clone()]);
+        assert_eq!(tree.utxo_set().len(), 2);
+        assert!(!tree.utxo_set().contains(&coinbase_outpoint(&unknown_block)));
+    }
+}
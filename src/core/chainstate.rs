@@ -0,0 +1,530 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use secp256k1::hashes::sha256;
+
+use crate::constants::{CHAINSTATE_FLUSH_INTERVAL_MS, MAX_CHAINSTATE_CACHE_SIZE, MAX_CHAINSTATE_JOURNAL_SIZE};
+use crate::core::block::Block;
+use crate::core::block_store::StoreError;
+use crate::core::serialize::{write_vec, DecodeError, Decodable, Encodable, Reader};
+use crate::core::utxo::{BlockUndo, OutPoint, UtxoEntry, UtxoError, UtxoSet};
+use crate::utils::hash::hash256;
+use crate::utils::time::get_current_timestamp_ms;
+
+/// Snapshot of the whole chainstate
+const SNAPSHOT_FILE_NAME: &str = "utxo.dat";
+
+/// New snapshots are written to this file first and renamed over the old one once they are complete
+const SNAPSHOT_TMP_FILE_NAME: &str = "utxo.dat.tmp";
+
+/// Changes made after the snapshot, one record per flush
+const JOURNAL_FILE_NAME: &str = "journal.dat";
+
+/// Size of a record header in the chainstate files: payload size (u32)
+const RECORD_HEADER_SIZE: usize = 4;
+
+/// Size of a record checksum: the first 4 bytes of the hash256 of the payload
+const CHECKSUM_SIZE: usize = 4;
+
+/// Changes written by one flush. Outputs and undo data are stored as their new value (None if removed),
+/// so replaying a batch twice gives the same state
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct ChainstateBatch {
+    /// Number of the write, journal records that aren't newer than the snapshot are already part of it
+    sequence: u64,
+    /// Tip of the chain the UTXO set matches after the batch
+    best_block: Option<sha256::Hash>,
+    outputs: Vec<(OutPoint, Option<UtxoEntry>)>,
+    undo: Vec<(sha256::Hash, Option<BlockUndo>)>,
+}
+
+/// Batch encoding: sequence number (u64), best block (optional hash), CompactSize prefixed list of changed outputs
+/// and CompactSize prefixed list of changed undo data
+impl Encodable for ChainstateBatch {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(&self.sequence.to_le_bytes());
+        self.best_block.encode_to(writer);
+        write_vec(writer, &self.outputs);
+        write_vec(writer, &self.undo);
+    }
+}
+
+impl Decodable for ChainstateBatch {
+    fn decode_from(reader: &mut Reader) -> Result<ChainstateBatch, DecodeError> {
+        Ok(ChainstateBatch {
+            sequence: reader.read_u64()?,
+            best_block: Option::decode_from(reader)?,
+            outputs: reader.read_vec()?,
+            undo: reader.read_vec()?,
+        })
+    }
+}
+
+/// On-disk part of a persisted chainstate together with the changes that weren't flushed yet
+#[derive(Debug)]
+struct ChainstateDb {
+    dir: PathBuf,
+    journal: File,
+    journal_size: u64,
+    /// Sequence number of the last snapshot or journal record written
+    sequence: u64,
+    /// Outputs changed since the last flush, None if the output was removed
+    dirty_outputs: HashMap<OutPoint, Option<UtxoEntry>>,
+    /// Undo data changed since the last flush, None if it was removed
+    dirty_undo: HashMap<sha256::Hash, Option<BlockUndo>>,
+    /// Best block written by the last flush
+    flushed_best_block: Option<sha256::Hash>,
+    /// Set when the stored state can't be updated with a journal record and has to be rewritten (e.g. after a reset)
+    needs_snapshot: bool,
+    last_flush: u128,
+}
+
+/// UTXO set of the active chain together with the undo data of its blocks
+/// and the best block, the tip of the chain the set matches.
+/// A persisted chainstate keeps the whole set in memory as a cache and writes the changes to disk when it's flushed:
+/// a flush appends one checksummed record (changed outputs, changed undo data and the new best block) to the journal,
+/// so a crash leaves the state of the last complete flush. Once the journal grows too big,
+/// the whole state is written to a new snapshot that atomically replaces the old one and the journal is emptied
+#[derive(Debug, Default)]
+pub struct Chainstate {
+    utxo_set: UtxoSet,
+    undo: HashMap<sha256::Hash, BlockUndo>,
+    best_block: Option<sha256::Hash>,
+    db: Option<ChainstateDb>,
+}
+
+impl Chainstate {
+    /// Creates an empty chainstate kept only in memory
+    pub fn new() -> Chainstate {
+        Chainstate::default()
+    }
+
+    /// Opens the chainstate stored in `dir` (created if it doesn't exist).
+    /// A journal record cut short by a crash is dropped, the state is the one of the last complete flush.
+    /// Journal records written before the snapshot (left behind by a crash during `compact`) are skipped
+    pub fn open(dir: &Path) -> Result<Chainstate, StoreError> {
+        fs::create_dir_all(dir)?;
+        let mut chainstate = Chainstate::new();
+        let mut needs_snapshot = false;
+        let mut sequence = 0;
+        match fs::read(dir.join(SNAPSHOT_FILE_NAME)) {
+            Ok(bytes) => match read_record(&bytes) {
+                Some((batch, size)) if size == bytes.len() => {
+                    sequence = batch.sequence;
+                    chainstate.apply_batch(batch);
+                }
+                // snapshots are renamed into place once complete, so this is real damage: start over
+                _ => {
+                    println!("Ignoring the damaged chainstate snapshot in {}", dir.display());
+                    needs_snapshot = true;
+                }
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        let mut journal_bytes = vec![];
+        let mut journal = OpenOptions::new().read(true).append(true).create(true).open(dir.join(JOURNAL_FILE_NAME))?;
+        journal.read_to_end(&mut journal_bytes)?;
+        let mut position = 0;
+        while let Some((batch, size)) = read_record(&journal_bytes[position..]) {
+            if batch.sequence > sequence {
+                sequence = batch.sequence;
+                if !needs_snapshot {
+                    chainstate.apply_batch(batch);
+                }
+            }
+            position += size;
+        }
+        if position < journal_bytes.len() {
+            println!("Dropping {} bytes of a partially written chainstate journal record", journal_bytes.len() - position);
+            journal.set_len(position as u64)?;
+        }
+        if needs_snapshot {
+            chainstate = Chainstate::new();
+        }
+
+        chainstate.db = Some(ChainstateDb {
+            dir: dir.to_path_buf(),
+            journal,
+            journal_size: position as u64,
+            sequence,
+            dirty_outputs: HashMap::new(),
+            dirty_undo: HashMap::new(),
+            flushed_best_block: chainstate.best_block,
+            needs_snapshot,
+            last_flush: get_current_timestamp_ms(),
+        });
+        Ok(chainstate)
+    }
+
+    pub fn utxo_set(&self) -> &UtxoSet {
+        &self.utxo_set
+    }
+
+    /// Tip of the chain the UTXO set matches, None for an empty chain
+    pub fn best_block(&self) -> Option<sha256::Hash> {
+        self.best_block
+    }
+
+    /// Undo data of a connected block
+    pub fn undo(&self, hash: &sha256::Hash) -> Option<&BlockUndo> {
+        self.undo.get(hash)
+    }
+
+    /// Applies a block at `height` on top of the best block and keeps its undo data
+    pub fn connect_block(&mut self, block: &Block, height: u64) -> Result<(), UtxoError> {
+        let hash = block.hash_block();
+        let undo = self.utxo_set.apply_block(block, height)?;
+        self.mark_outputs_dirty(block, &undo);
+        if let Some(db) = &mut self.db {
+            db.dirty_undo.insert(hash, Some(undo.clone()));
+        }
+        self.undo.insert(hash, undo);
+        self.best_block = Some(hash);
+        Ok(())
+    }
+
+    /// Rolls back the best block with its undo data, its previous block becomes the best block
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<(), UtxoError> {
+        let hash = block.hash_block();
+        let undo = self.undo.remove(&hash).expect("connected blocks have undo data");
+        let spent_undo = undo.clone();
+        self.utxo_set.undo_block(block, undo)?;
+        self.mark_outputs_dirty(block, &spent_undo);
+        if let Some(db) = &mut self.db {
+            db.dirty_undo.insert(hash, None);
+        }
+        self.best_block = block.header.previous_block_hash;
+        Ok(())
+    }
+
+    /// Removes everything, so the chain can be connected again from the genesis block
+    pub fn reset(&mut self) {
+        self.utxo_set = UtxoSet::new();
+        self.undo.clear();
+        self.best_block = None;
+        if let Some(db) = &mut self.db {
+            db.dirty_outputs.clear();
+            db.dirty_undo.clear();
+            db.needs_snapshot = true;
+        }
+    }
+
+    /// Writes the changes made since the last flush to disk
+    pub fn flush(&mut self) -> Result<(), StoreError> {
+        let Some(db) = &mut self.db else {
+            return Ok(());
+        };
+        if db.needs_snapshot || db.journal_size > MAX_CHAINSTATE_JOURNAL_SIZE {
+            return self.compact();
+        }
+        db.last_flush = get_current_timestamp_ms();
+        if db.dirty_outputs.is_empty() && db.dirty_undo.is_empty() && db.flushed_best_block == self.best_block {
+            return Ok(());
+        }
+        db.sequence += 1;
+        let batch = ChainstateBatch {
+            sequence: db.sequence,
+            best_block: self.best_block,
+            outputs: db.dirty_outputs.drain().collect(),
+            undo: db.dirty_undo.drain().collect(),
+        };
+        let record = encode_record(&batch.encode());
+        db.journal.write_all(&record)?;
+        db.journal.sync_data()?;
+        db.journal_size += record.len() as u64;
+        db.flushed_best_block = self.best_block;
+        Ok(())
+    }
+
+    /// Flushes if there are too many unflushed changes or the last flush was too long ago
+    pub fn flush_if_needed(&mut self) -> Result<(), StoreError> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let cache_size = db.dirty_outputs.len() + db.dirty_undo.len();
+        if cache_size >= MAX_CHAINSTATE_CACHE_SIZE || get_current_timestamp_ms().saturating_sub(db.last_flush) >= CHAINSTATE_FLUSH_INTERVAL_MS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the whole state to a new snapshot and empties the journal.
+    /// Until the snapshot is renamed into place the old snapshot and journal stay valid,
+    /// the records of a journal left behind by a crash after the rename are older than the snapshot and get skipped
+    pub fn compact(&mut self) -> Result<(), StoreError> {
+        let Some(db) = &mut self.db else {
+            return Ok(());
+        };
+        db.sequence += 1;
+        let batch = ChainstateBatch {
+            sequence: db.sequence,
+            best_block: self.best_block,
+            outputs: self.utxo_set.iter().map(|(outpoint, entry)| (*outpoint, Some(entry.clone()))).collect(),
+            undo: self.undo.iter().map(|(hash, undo)| (*hash, Some(undo.clone()))).collect(),
+        };
+        let tmp_path = db.dir.join(SNAPSHOT_TMP_FILE_NAME);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encode_record(&batch.encode()))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, db.dir.join(SNAPSHOT_FILE_NAME))?;
+        File::open(&db.dir)?.sync_all()?;
+
+        db.journal.set_len(0)?;
+        db.journal.sync_data()?;
+        db.journal_size = 0;
+        db.dirty_outputs.clear();
+        db.dirty_undo.clear();
+        db.flushed_best_block = self.best_block;
+        db.needs_snapshot = false;
+        db.last_flush = get_current_timestamp_ms();
+        Ok(())
+    }
+
+    /// Records the current state of every output a block created or spent
+    fn mark_outputs_dirty(&mut self, block: &Block, undo: &BlockUndo) {
+        let Some(db) = &mut self.db else {
+            return;
+        };
+        let mut outpoints: HashSet<OutPoint> = undo.spent_outputs.iter().map(|(outpoint, _)| *outpoint).collect();
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            outpoints.extend((0..transaction.outputs.len() as u32).map(|index| OutPoint { txid, index }));
+        }
+        for outpoint in outpoints {
+            db.dirty_outputs.insert(outpoint, self.utxo_set.get(&outpoint).cloned());
+        }
+    }
+
+    fn apply_batch(&mut self, batch: ChainstateBatch) {
+        for (outpoint, entry) in batch.outputs {
+            match entry {
+                Some(entry) => self.utxo_set.insert(outpoint, entry),
+                None => {
+                    self.utxo_set.remove(&outpoint);
+                }
+            }
+        }
+        for (hash, undo) in batch.undo {
+            match undo {
+                Some(undo) => self.undo.insert(hash, undo),
+                None => self.undo.remove(&hash),
+            };
+        }
+        self.best_block = batch.best_block;
+    }
+}
+
+impl Drop for Chainstate {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            println!("Can't flush the chainstate: {}", error);
+        }
+    }
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len() + CHECKSUM_SIZE);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(payload);
+    record.extend_from_slice(&hash256(payload)[..CHECKSUM_SIZE]);
+    record
+}
+
+/// Reads the record at the start of `bytes` and returns its batch and size,
+/// None if the record is cut short, its checksum doesn't match or it can't be decoded
+fn read_record(bytes: &[u8]) -> Option<(ChainstateBatch, usize)> {
+    let size = u32::from_le_bytes(bytes.get(..RECORD_HEADER_SIZE)?.try_into().unwrap()) as usize;
+    let payload = bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + size)?;
+    let checksum = bytes.get(RECORD_HEADER_SIZE + size..RECORD_HEADER_SIZE + size + CHECKSUM_SIZE)?;
+    if checksum != &hash256(payload)[..CHECKSUM_SIZE] {
+        return None;
+    }
+    let batch = ChainstateBatch::decode(payload).ok()?;
+    Some((batch, RECORD_HEADER_SIZE + size + CHECKSUM_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::PublicKey;
+    use crate::constants::TX_VERSION;
+    use crate::core::test_utils::mine_genesis_block;
+    use crate::core::params::ChainParams;
+    use crate::core::script::Script;
+    use crate::core::transaction::{Transaction, TransactionInput, TransactionOutput};
+    use crate::utils::temp_dir::TempDir;
+    use crate::utils::wallets::generate_keypair;
+
+    fn create_block(transactions: Vec<Transaction>) -> Block {
+        let (_, pub_key) = generate_keypair();
+        let mut block = mine_genesis_block(pub_key, &ChainParams::regtest());
+        block.transactions.extend(transactions);
+        block
+    }
+
+    fn create_spending_transaction(spent: OutPoint, recipient_pub_key: PublicKey) -> Transaction {
+        Transaction {
+            transaction_version: TX_VERSION,
+            input_count: 1,
+            inputs: vec![TransactionInput {
+                previous_transaction_hash: spent.txid,
+                previous_transaction_index: spent.index,
+                script_length: 0,
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+            }],
+            output_count: 1,
+            outputs: vec![TransactionOutput { value: 10, script_length: 0, script_pub_key: Script::new(), recipient_pub_key }],
+            lock_time: 0,
+        }
+    }
+
+    /// Two blocks, the second one spends the coinbase output of the first one
+    fn create_blocks() -> (Block, Block) {
+        let first_block = create_block(vec![]);
+        let (_, recipient) = generate_keypair();
+        let spent = OutPoint { txid: first_block.transactions[0].hash(), index: 0 };
+        let mut second_block = create_block(vec![create_spending_transaction(spent, recipient)]);
+        second_block.header.previous_block_hash = Some(first_block.hash_block());
+        (first_block, second_block)
+    }
+
+    fn outputs(chainstate: &Chainstate) -> HashMap<OutPoint, UtxoEntry> {
+        chainstate.utxo_set().iter().map(|(outpoint, entry)| (*outpoint, entry.clone())).collect()
+    }
+
+    #[test]
+    fn test_batch_encoding_roundtrip() {
+        let (first_block, _) = create_blocks();
+        let mut chainstate = Chainstate::new();
+        chainstate.connect_block(&first_block, 0).unwrap();
+        let (outpoint, entry) = chainstate.utxo_set().iter().next().unwrap();
+        let batch = ChainstateBatch {
+            sequence: 7,
+            best_block: Some(first_block.hash_block()),
+            outputs: vec![(*outpoint, Some(entry.clone())), (OutPoint::null(), None)],
+            undo: vec![(first_block.hash_block(), Some(BlockUndo { spent_outputs: vec![(*outpoint, entry.clone())] }))],
+        };
+        assert_eq!(ChainstateBatch::decode(&batch.encode()), Ok(batch));
+        assert_eq!(ChainstateBatch::decode(&ChainstateBatch::default().encode()), Ok(ChainstateBatch::default()));
+    }
+
+    #[test]
+    fn test_flush_and_reopen() {
+        let dir = TempDir::new();
+        let (first_block, second_block) = create_blocks();
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        chainstate.connect_block(&first_block, 0).unwrap();
+        chainstate.flush().unwrap();
+        chainstate.connect_block(&second_block, 1).unwrap();
+        let expected_outputs = outputs(&chainstate);
+        // dropping the chainstate flushes it
+        drop(chainstate);
+
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), Some(second_block.hash_block()));
+        assert_eq!(outputs(&chainstate), expected_outputs);
+        assert_eq!(chainstate.undo(&second_block.hash_block()).unwrap().spent_outputs.len(), 1);
+
+        chainstate.disconnect_block(&second_block).unwrap();
+        drop(chainstate);
+        let chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), Some(first_block.hash_block()));
+        assert_eq!(chainstate.utxo_set().len(), 1);
+        assert!(chainstate.undo(&second_block.hash_block()).is_none());
+    }
+
+    #[test]
+    fn test_unflushed_changes_are_lost_in_a_crash() {
+        let dir = TempDir::new();
+        let (first_block, second_block) = create_blocks();
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        chainstate.connect_block(&first_block, 0).unwrap();
+        chainstate.flush().unwrap();
+        chainstate.connect_block(&second_block, 1).unwrap();
+        // the process dies without flushing
+        std::mem::forget(chainstate);
+
+        let chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), Some(first_block.hash_block()));
+        assert_eq!(chainstate.utxo_set().len(), 1);
+    }
+
+    #[test]
+    fn test_torn_journal_record() {
+        let dir = TempDir::new();
+        let (first_block, second_block) = create_blocks();
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        chainstate.connect_block(&first_block, 0).unwrap();
+        chainstate.flush().unwrap();
+        let journal_path = dir.path().join(JOURNAL_FILE_NAME);
+        let first_record_size = fs::metadata(&journal_path).unwrap().len();
+        chainstate.connect_block(&second_block, 1).unwrap();
+        drop(chainstate);
+
+        // the second record was only partially written
+        let journal_size = fs::metadata(&journal_path).unwrap().len();
+        OpenOptions::new().write(true).open(&journal_path).unwrap().set_len(journal_size - 3).unwrap();
+
+        let chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), Some(first_block.hash_block()));
+        assert_eq!(fs::metadata(&journal_path).unwrap().len(), first_record_size);
+    }
+
+    #[test]
+    fn test_compact_and_reset() {
+        let dir = TempDir::new();
+        let (first_block, second_block) = create_blocks();
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        chainstate.connect_block(&first_block, 0).unwrap();
+        chainstate.connect_block(&second_block, 1).unwrap();
+        chainstate.compact().unwrap();
+        let expected_outputs = outputs(&chainstate);
+        assert_eq!(fs::metadata(dir.path().join(JOURNAL_FILE_NAME)).unwrap().len(), 0);
+        assert!(!dir.path().join(SNAPSHOT_TMP_FILE_NAME).exists());
+        drop(chainstate);
+
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), Some(second_block.hash_block()));
+        assert_eq!(outputs(&chainstate), expected_outputs);
+
+        chainstate.reset();
+        drop(chainstate);
+        let chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), None);
+        assert!(chainstate.utxo_set().is_empty());
+    }
+
+    #[test]
+    fn test_journal_left_behind_by_compact() {
+        let dir = TempDir::new();
+        let (first_block, second_block) = create_blocks();
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        chainstate.connect_block(&first_block, 0).unwrap();
+        chainstate.flush().unwrap();
+        chainstate.connect_block(&second_block, 1).unwrap();
+        chainstate.flush().unwrap();
+        chainstate.disconnect_block(&second_block).unwrap();
+        let journal_path = dir.path().join(JOURNAL_FILE_NAME);
+        let journal = fs::read(&journal_path).unwrap();
+        chainstate.compact().unwrap();
+        let expected_outputs = outputs(&chainstate);
+        // the process died after the snapshot was renamed into place but before the journal was emptied
+        fs::write(&journal_path, &journal).unwrap();
+        std::mem::forget(chainstate);
+
+        let mut chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), Some(first_block.hash_block()));
+        assert_eq!(outputs(&chainstate), expected_outputs);
+        // new records follow the skipped ones
+        chainstate.connect_block(&second_block, 1).unwrap();
+        chainstate.flush().unwrap();
+        std::mem::forget(chainstate);
+        let chainstate = Chainstate::open(dir.path()).unwrap();
+        assert_eq!(chainstate.best_block(), Some(second_block.hash_block()));
+    }
+}
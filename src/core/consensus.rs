@@ -1,176 +1,480 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use secp256k1::hashes::sha256;
-use secp256k1::{PublicKey, SecretKey};
+use secp256k1::PublicKey;
 
-use crate::constants::{NUMBER_OF_NODES, SOFTWARE_VERSION};
-use crate::core::block::Block;
+use crate::constants::{MAX_BLOCK_SIZE, MAX_HEADERS_RESULTS, PING_INTERVAL_MS};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::block_store::BlockStore;
+use crate::core::block_template::BlockTemplate;
+use crate::core::chain::{BlockTree, ChainError, ChainUpdate};
+use crate::core::chainstate::Chainstate;
+use crate::core::mempool::{Mempool, MempoolError};
+use crate::core::mining::mine_block;
+use crate::core::orphans::OrphanPool;
+use crate::core::params::ChainParams;
+use crate::core::pow::check_proof_of_work;
+use crate::core::utxo::{UtxoOverlay, UtxoSet};
+use crate::core::serialize::Encodable;
+use crate::core::sync::BlockSync;
+use crate::core::validation::{check_block_timestamp, check_coinbase, check_transaction, check_transaction_inputs_with, ValidationError};
+use crate::network::message::{GetHeadersMessage, Inventory, InventoryType, NetworkMessage};
+use crate::network::transport::{PeerEvent, PeerId, Transport};
 use crate::utils;
-use crate::utils::hash::sha256_hash;
 use crate::utils::time::get_current_timestamp_ms;
 use super::transaction::{calculate_merkle_root, Transaction};
 
+/// When a running node mines blocks
+pub enum Mining {
+    Disabled,
+    /// Mines one block for every request, the simulation picks the node that mines the next block
+    OnRequest(Receiver<()>),
+    /// Keeps mining on top of the active chain
+    Continuous,
+}
+
 /// Node struct represents a node in the network
 pub struct Node {
     pub id: u32,
     pub pub_key: PublicKey,
-    secret_key: SecretKey,
-    blockchain: Mutex<Vec<Block>>,
+    /// Consensus parameters of the network the node runs on
+    pub params: ChainParams,
+    /// Known blocks and the active chain with its unspent outputs
+    chain: Mutex<BlockTree>,
+    /// Unconfirmed transactions valid on top of the active chain, always locked after `chain`
+    mempool: Mutex<Mempool>,
+    /// Received blocks waiting for their previous block, never locked together with `chain`
+    orphans: Mutex<OrphanPool>,
+    /// Best header chain and the blocks being downloaded for it, always locked after `chain` and `mempool`
+    sync: Mutex<BlockSync>,
 }
 
 impl Node {
-    pub fn new(id: u32) -> Node {
-        let (secret_key, public_key) = utils::wallets::generate_keypair();
+    /// Creates a node that keeps its blocks in `store` and its UTXO set in `chainstate`
+    /// and reloads the chain stored there. An empty store starts with the genesis block of `params`
+    pub fn with_storage(id: u32, params: ChainParams, store: BlockStore, chainstate: Chainstate) -> Result<Node, ChainError> {
+        let mut chain = BlockTree::open(params.clone(), store, chainstate)?;
+        if chain.is_empty() {
+            chain.accept_block(params.genesis_block())?;
+        }
+        Ok(Node::new(id, params, chain))
+    }
+
+    /// Creates a node that continues the chain of `chain`
+    pub fn new(id: u32, params: ChainParams, chain: BlockTree) -> Node {
+        // the node doesn't spend its coinbase outputs yet, so only the public key is kept
+        let (_, public_key) = utils::wallets::generate_keypair();
+        let sync = BlockSync::new(params.clone(), chain.active_headers());
         Node {
             id,
             pub_key: public_key,
-            secret_key: secret_key,
-            blockchain: Mutex::new(vec![]),
+            params,
+            chain: Mutex::new(chain),
+            mempool: Mutex::new(Mempool::default()),
+            orphans: Mutex::new(OrphanPool::default()),
+            sync: Mutex::new(sync),
         }
     }
 
-    /// Start the node (thread) and listen for incoming messages
-    /// handles being a miner and receiving blocks from other nodes
-    pub fn start_node(self: Arc<Self>, rx: Receiver<u32>, tx_rx_channels_clone: Arc<Mutex<Vec<(Sender<Block>, Receiver<Block>)>>>) {
+    /// Start the node (thread) and listen for the messages of its peers on `transport`,
+    /// blocks are mined in another thread as `mining` says
+    pub fn start_node(self: Arc<Self>, transport: Arc<dyn Transport>, mining: Mining) -> JoinHandle<()> {
+        transport.set_best_height(self.best_height());
+        if !matches!(mining, Mining::Disabled) {
+            let node = Arc::clone(&self);
+            let transport = Arc::clone(&transport);
+            std::thread::spawn(move || node.mine(mining, transport.as_ref()));
+        }
         std::thread::spawn(move || {
+            let mut last_ping = get_current_timestamp_ms();
             loop {
-                // waiting for a message from the main thread (random node id)
-                if let Ok(_) = rx.try_recv() {
-                    let mut blockchain = self.blockchain.lock().unwrap();
-
-                    if blockchain.is_empty() {
-                        let genesis_block = Self::init_genesis_block(self.pub_key);
-                        blockchain.push(genesis_block.clone());
-                        println!("#{} ({}) (Genesis block) -> mined by #{} node (pubKey: {})", blockchain.len(),genesis_block.hash_block(), self.id, self.pub_key);
-                    } else {
-                        let previous_block_hash = blockchain.last().unwrap().hash_block();
-                        let new_transactions = get_list_of_transactions();
-                        let new_block = Self::mine_new_block(self.pub_key, previous_block_hash, new_transactions);
-                        blockchain.push(new_block.clone());
-                        println!("#{} block ({}) -> mined by #{} node (pubKey: {})", blockchain.len(), new_block.hash_block(), self.id, self.pub_key);
+                if let Some(event) = transport.receive_timeout(Duration::from_millis(100)) {
+                    self.handle_peer_event(event, transport.as_ref());
+                }
+                // blocks whose requests timed out are asked from other peers
+                self.request_blocks(transport.as_ref());
+                // pings keep idle connections open and reveal dead ones
+                if get_current_timestamp_ms().saturating_sub(last_ping) >= PING_INTERVAL_MS {
+                    transport.broadcast(&NetworkMessage::Ping(rand::random()), None);
+                    last_ping = get_current_timestamp_ms();
+                }
+            }
+        })
+    }
+
+    /// Mines blocks on the tip of the active chain and announces them to all peers.
+    /// The current block is abandoned as soon as the tip changes (e.g. a block from another node arrives)
+    fn mine(&self, mining: Mining, transport: &dyn Transport) {
+        loop {
+            if let Mining::OnRequest(requests) = &mining {
+                // the simulation stopped
+                if requests.recv().is_err() {
+                    return;
+                }
+            }
+            let Some(new_block) = self.mine_new_block() else {
+                println!("#{} node stopped mining, a competing block arrived", self.id);
+                continue;
+            };
+            let hash = new_block.hash_block();
+            match self.accept_block(new_block) {
+                Ok(_) => {
+                    println!("#{} block ({}) -> mined by #{} node (pubKey: {})", self.chain.lock().unwrap().len(), hash, self.id, self.pub_key);
+                    transport.set_best_height(self.best_height());
+                    transport.broadcast(&NetworkMessage::Inv(vec![Inventory::block(hash)]), None);
+                }
+                Err(error) => println!("#{} node mined a rejected block: {}", self.id, error),
+            }
+        }
+    }
+
+    fn handle_peer_event(&self, event: PeerEvent, transport: &dyn Transport) {
+        match event {
+            PeerEvent::Connected(peer, version) => {
+                println!("#{} node connected to peer {} ({}, {} blocks)", self.id, peer, version.user_agent, version.start_height + 1);
+                let is_behind = {
+                    let mut sync = self.sync.lock().unwrap();
+                    sync.add_peer(peer, version.start_height);
+                    version.start_height > sync.best_height()
+                };
+                if is_behind {
+                    self.send_get_headers(transport, peer);
+                }
+            }
+            PeerEvent::Message(peer, message) => self.handle_network_message(peer, message, transport),
+            PeerEvent::Disconnected(peer) => {
+                println!("#{} node lost peer {}", self.id, peer);
+                self.sync.lock().unwrap().remove_peer(peer);
+            }
+        }
+    }
+
+    /// Handles a message from a peer: answers pings and requests,
+    /// fetches announced blocks and transactions it doesn't know and relays the new ones to the other peers
+    fn handle_network_message(&self, peer: PeerId, message: NetworkMessage, transport: &dyn Transport) {
+        match message {
+            NetworkMessage::Ping(nonce) => self.send_to_peer(transport, peer, NetworkMessage::Pong(nonce)),
+            NetworkMessage::Pong(_) => {}
+            NetworkMessage::Inv(items) => {
+                let wanted: Vec<Inventory> = items.into_iter().filter(|item| !self.has_inventory(item)).collect();
+                if !wanted.is_empty() {
+                    self.send_to_peer(transport, peer, NetworkMessage::GetData(wanted));
+                }
+            }
+            NetworkMessage::GetData(items) => {
+                for item in items {
+                    let message = match item.kind {
+                        InventoryType::Block => self.chain.lock().unwrap().get(&item.hash).map(|entry| NetworkMessage::Block(entry.block.clone())),
+                        InventoryType::Transaction => self.mempool.lock().unwrap().get(&item.hash).map(|entry| NetworkMessage::Tx(entry.transaction.clone())),
+                    };
+                    if let Some(message) = message {
+                        self.send_to_peer(transport, peer, message);
                     }
-                    // sending block to all other nodes
-                    let tx_rx_channels = tx_rx_channels_clone.lock().unwrap();
-                    for i in 0..NUMBER_OF_NODES {
-                        if i != self.id {
-                            let (tx_block, _) = &tx_rx_channels[i as usize];
-                            tx_block.send(blockchain.last().unwrap().clone()).unwrap();
+                }
+            }
+            NetworkMessage::Block(block) => {
+                let downloaded = self.sync.lock().unwrap().block_received(&block);
+                match downloaded {
+                    Ok(true) => self.connect_downloaded_blocks(transport),
+                    Ok(false) => {
+                        let hash = block.hash_block();
+                        let was_known = self.chain.lock().unwrap().contains(&hash);
+                        if self.receive_block(block).is_some() {
+                            // the node is missing blocks of the peer's chain, sync its headers
+                            self.send_get_headers(transport, peer);
                         }
+                        if !was_known && self.chain.lock().unwrap().contains(&hash) {
+                            transport.set_best_height(self.best_height());
+                            transport.broadcast(&NetworkMessage::Inv(vec![Inventory::block(hash)]), Some(peer));
+                        }
+                    }
+                    Err(error) => {
+                        println!("Peer {} sent an invalid block: {}", peer, error);
+                        transport.disconnect(peer);
                     }
                 }
-    
-                // waiting for a "block" from another node
-                let tx_rx_channels = tx_rx_channels_clone.lock().unwrap();
-                let (_, rx_block) = &tx_rx_channels[self.id as usize];
-                if let Ok(new_block) = rx_block.try_recv() {
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    // copy the blockchain and add new block to the copied blockchain
-                    let mut new_blockchain = blockchain.clone();
-                    new_blockchain.push(new_block.clone());
-                    if Node::validate_blockchain(&new_blockchain.clone()) {
-                        blockchain.push(new_block.clone());
-                        println!("New block got accepted by #{} node", self.id);
-                    } else {
-                        println!("Received block is invalid!");
+            }
+            NetworkMessage::Tx(transaction) => match self.submit_transaction(transaction) {
+                Ok(txid) => transport.broadcast(&NetworkMessage::Inv(vec![Inventory::transaction(txid)]), Some(peer)),
+                Err(error) => println!("Received transaction is rejected: {}", error),
+            },
+            NetworkMessage::GetHeaders(request) => {
+                let headers = self.chain.lock().unwrap().locate_headers(&request.locator, request.stop_hash, MAX_HEADERS_RESULTS);
+                self.send_to_peer(transport, peer, NetworkMessage::Headers(headers));
+            }
+            NetworkMessage::Headers(headers) => {
+                let added = self.sync.lock().unwrap().add_headers(peer, &headers, get_current_timestamp_ms());
+                match added {
+                    Ok(0) => {}
+                    Ok(added) => {
+                        println!("#{} node received {} new headers from peer {}", self.id, added, peer);
+                        // a full message means the peer has more headers
+                        if headers.len() == MAX_HEADERS_RESULTS {
+                            self.send_get_headers(transport, peer);
+                        }
+                        self.request_blocks(transport);
+                    }
+                    // the headers fork from a block the node doesn't know, ask for the headers after its own chain
+                    Err(ChainError::UnknownParent(_)) => self.send_get_headers(transport, peer),
+                    Err(error) => {
+                        println!("Peer {} sent invalid headers: {}", peer, error);
+                        transport.disconnect(peer);
                     }
                 }
             }
-        });
-    }
-
-    /// Initializes the genesis block
-    pub fn init_genesis_block(miner_pub_key: PublicKey) -> Block {
-        let script_pub_key = miner_pub_key.to_string();
-        let coinbase_transaction = Transaction::new_coinbase_transaction(script_pub_key, miner_pub_key);
-        let transactions = vec![coinbase_transaction.clone()];
-        let merkle_root = calculate_merkle_root(&transactions);
-        let genesis_block = Block::new(
-            SOFTWARE_VERSION.to_string(), 
-            None, 
-            merkle_root, 
-            get_current_timestamp_ms(), 
-            0, 
-            0, 
-            transactions, 
-            coinbase_transaction
-        );
-        
-        genesis_block
-    }
-
-    /// Mines a new block by creating a new block with a coinbase transaction
-    pub fn mine_new_block(miner_pub_key: PublicKey, previous_block_hash: sha256::Hash, transactions: Vec<Transaction>) -> Block{
-        let script_pub_key = miner_pub_key.to_string();
-        let coinbase_transaction = Transaction::new_coinbase_transaction(script_pub_key, miner_pub_key);       
-        let mut all_transactions = vec![coinbase_transaction.clone()];
-        all_transactions.extend(transactions);
-        let merkle_root = calculate_merkle_root(&all_transactions);
-        let new_block = Block::new(
-            SOFTWARE_VERSION.to_string(), 
-            Some(previous_block_hash), 
-            merkle_root, 
-            get_current_timestamp_ms(), 
-            0, 
-            0, 
-            all_transactions, 
-            coinbase_transaction
-        );
-    
-        new_block
-    }
-
-    /// Validates a block by checking if the hash of the block is correct
-    /// and if the merkle root of the block is correct
-    /// and if the timestamp of the block is in the past
-    /// and if the difficulty target of the block is correct
-    /// and if each transaction in the block is valid
-    pub fn validate_block(block: &Block) -> bool {
-        let block_hash = sha256_hash(block.header.to_string().as_str());
-        let transactions = &block.transactions;
-        let merkle_root = calculate_merkle_root(transactions);
-        if block.hash_block() != block_hash {
-            return false;
+            NetworkMessage::Version(_) | NetworkMessage::Verack => {
+                println!("Peer {} repeated the handshake, disconnecting", peer);
+                transport.disconnect(peer);
+            }
         }
-        // Check if the merkle root of the block is correct
-        if merkle_root != block.header.merkle_root {
-            return false;
+    }
+
+    /// True if the announced block or transaction is already known
+    fn has_inventory(&self, item: &Inventory) -> bool {
+        match item.kind {
+            InventoryType::Block => {
+                let in_chain = self.chain.lock().unwrap().contains(&item.hash);
+                in_chain || self.orphans.lock().unwrap().contains(&item.hash)
+            }
+            InventoryType::Transaction => self.mempool.lock().unwrap().contains(&item.hash),
         }
-        // Check if the timestamp of the block is in the past
-        if block.header.timestamp > get_current_timestamp_ms() {
-            return false;
+    }
+
+    /// Asks a peer for the headers that follow the best header chain of the node
+    fn send_get_headers(&self, transport: &dyn Transport, peer: PeerId) {
+        let locator = self.sync.lock().unwrap().locator();
+        self.send_to_peer(transport, peer, NetworkMessage::GetHeaders(GetHeadersMessage { locator, stop_hash: None }));
+    }
+
+    /// Asks peers for the missing blocks of the best header chain, spread over all peers that have them
+    fn request_blocks(&self, transport: &dyn Transport) {
+        let requests = {
+            let chain = self.chain.lock().unwrap();
+            let mut sync = self.sync.lock().unwrap();
+            sync.request_blocks(|hash| chain.contains(hash), get_current_timestamp_ms())
+        };
+        for (peer, hashes) in requests {
+            self.send_to_peer(transport, peer, NetworkMessage::GetData(hashes.into_iter().map(Inventory::block).collect()));
         }
-        
-        // TODO: difficulty target check
-        // TODO: validate each transaction in the block
-        // TODO: add other checks
-        true
     }
 
-    /// Validates a blockchain by checking if each block in the blockchain is valid
-    /// starts from the last block in the blockchain
-    pub fn validate_blockchain(blockchain: &Vec<Block>) -> bool {
-            for i in (1..blockchain.len()).rev() {
-                if !Node::validate_block(&blockchain[i]) {
-                    return false;
+    /// Adds the downloaded blocks to the block tree in the order of the best header chain,
+    /// stopping at the first block that hasn't arrived yet. An invalid block is dropped from the header chain
+    fn connect_downloaded_blocks(&self, transport: &dyn Transport) {
+        let mut added = 0;
+        loop {
+            let next_block = {
+                let chain = self.chain.lock().unwrap();
+                let mut sync = self.sync.lock().unwrap();
+                sync.next_block(|hash| chain.contains(hash))
+            };
+            let Some(block) = next_block else { break };
+            let hash = block.hash_block();
+            match self.accept_block(block) {
+                Ok(_) => {
+                    added += 1;
+                    self.connect_orphans(hash);
+                }
+                Err(ChainError::InvalidBlock { hash, error }) => {
+                    println!("Downloaded block {} is invalid: {}", hash, error);
+                    self.sync.lock().unwrap().invalidate(&hash);
+                }
+                Err(ChainError::InvalidParent(parent)) => self.sync.lock().unwrap().invalidate(&parent),
+                Err(error) => {
+                    // the block is downloaded again later
+                    println!("Downloaded block {} is rejected: {}", hash, error);
+                    break;
                 }
             }
-        true
+        }
+        if added > 0 {
+            println!("#{} node added {} downloaded blocks, height {}", self.id, added, self.best_height());
+            transport.set_best_height(self.best_height());
+        }
+    }
+
+    fn send_to_peer(&self, transport: &dyn Transport, peer: PeerId, message: NetworkMessage) {
+        if let Err(error) = transport.send(peer, &message) {
+            println!("#{} node can't send {} to peer {}: {}", self.id, message.command(), peer, error);
+        }
+    }
+
+    /// Height of the tip of the active chain
+    pub fn best_height(&self) -> u64 {
+        self.chain.lock().unwrap().len().saturating_sub(1) as u64
+    }
+
+    /// Adds a block received from another node to the block tree, then connects the orphans waiting for it.
+    /// A block whose previous block is unknown goes to the orphan pool,
+    /// the hash of the block missing for it to connect is returned so it can be requested from other nodes
+    fn receive_block(&self, new_block: Block) -> Option<sha256::Hash> {
+        let hash = new_block.hash_block();
+        match self.accept_block(new_block.clone()) {
+            Ok(update) if update.is_empty() => println!("#{} node stored a block on a side branch", self.id),
+            Ok(update) if !update.disconnected.is_empty() => {
+                println!("#{} node reorganized its chain: {} blocks disconnected, {} connected", self.id, update.disconnected.len(), update.connected.len());
+            }
+            Ok(_) => println!("New block got accepted by #{} node", self.id),
+            Err(ChainError::UnknownParent(_)) => return self.add_orphan(new_block),
+            Err(error) => {
+                println!("Received block is rejected: {}", error);
+                return None;
+            }
+        }
+        self.connect_orphans(hash);
+        None
+    }
+
+    /// Keeps a block with an unknown previous block in the orphan pool,
+    /// returns the hash of the block missing for it to connect
+    fn add_orphan(&self, block: Block) -> Option<sha256::Hash> {
+        // cheap check so the pool can't be filled with blocks that took no work to create
+        if !block.has_valid_proof_of_work() {
+            println!("Received orphan block has an invalid proof of work");
+            return None;
+        }
+        let hash = block.hash_block();
+        let mut orphans = self.orphans.lock().unwrap();
+        if !orphans.add_orphan(block, get_current_timestamp_ms()) {
+            return None;
+        }
+        println!("#{} node stored orphan block {}", self.id, hash);
+        orphans.missing_ancestor(&hash)
+    }
+
+    /// Adds the orphans descending from the block `parent` to the block tree
+    fn connect_orphans(&self, parent: sha256::Hash) {
+        let mut parents = vec![parent];
+        while let Some(parent) = parents.pop() {
+            let children = self.orphans.lock().unwrap().take_children(&parent);
+            for child in children {
+                let hash = child.hash_block();
+                match self.accept_block(child) {
+                    Ok(_) => {
+                        println!("Orphan block {} got accepted by #{} node", hash, self.id);
+                        parents.push(hash);
+                    }
+                    Err(error) => println!("Orphan block {} is rejected: {}", hash, error),
+                }
+            }
+        }
+    }
+
+    /// Adds a block to the block tree and updates the mempool with the changes of the active chain:
+    /// transactions of disconnected blocks go back to the mempool and the ones of connected blocks are removed
+    fn accept_block(&self, block: Block) -> Result<ChainUpdate, ChainError> {
+        let mut chain = self.chain.lock().unwrap();
+        let update = chain.accept_block(block)?;
+        let mut mempool = self.mempool.lock().unwrap();
+        for block in &update.disconnected {
+            mempool.add_disconnected_block(block, chain.utxo_set());
+        }
+        for block in &update.connected {
+            mempool.remove_for_block(block);
+        }
+        // blocks connected by any means extend the header chain the node syncs
+        let mut sync = self.sync.lock().unwrap();
+        for block in &update.connected {
+            sync.add_connected_header(&block.header);
+        }
+        Ok(update)
+    }
+
+    /// Validates a transaction against the UTXO set of the active chain and adds it to the mempool
+    pub fn submit_transaction(&self, transaction: Transaction) -> Result<sha256::Hash, MempoolError> {
+        let chain = self.chain.lock().unwrap();
+        self.mempool.lock().unwrap().add_transaction(transaction, chain.utxo_set())
     }
-}
 
-/// Get available transactions to be included in a block
-/// Temporary function to return an empty list of transactions
-fn get_list_of_transactions() -> Vec<Transaction> {
-    vec![]
+    /// Mines a new block on top of the active chain by creating a block template with the best paying
+    /// mempool transactions (the coinbase pays the node the block subsidy and their fees)
+    /// and searching for a nonce that satisfies the difficulty target expected by the chain.
+    /// Returns None if the tip changed before a block was found (e.g. a block from another node arrived)
+    pub fn mine_new_block(&self) -> Option<Block> {
+        let (template, tip) = {
+            let chain = self.chain.lock().unwrap();
+            let template = BlockTemplate::from_mempool(self.pub_key, chain.active_headers(), &self.mempool.lock().unwrap(), MAX_BLOCK_SIZE, &self.params);
+            (template, chain.tip().map(|tip| tip.block.hash_block()))
+        };
+        // neither the chain nor the mempool is kept locked while mining
+        let tip_changed = || self.chain.lock().unwrap().tip().map(|tip| tip.block.hash_block()) != tip;
+        mine_block(template.block, tip_changed)
+    }
+
+    /// Validates a block by checking if the block isn't larger than `MAX_BLOCK_SIZE`
+    /// and if the block points to the last of `previous_headers`
+    /// and if the merkle root of the block is correct
+    /// and if the timestamp of the block is later than the median time past and not too far in the future
+    /// and if the difficulty target of the block is the one expected after `previous_headers`
+    /// and if the block hash satisfies the difficulty target
+    /// and if each transaction in the block is valid against `utxo_set` (the unspent outputs before the block)
+    /// and if the coinbase transaction follows the coinbase rules
+    pub fn validate_block(block: &Block, previous_headers: &[BlockHeader], utxo_set: &UtxoSet, params: &ChainParams) -> Result<(), ValidationError> {
+        let size = block.encode().len();
+        if size > MAX_BLOCK_SIZE {
+            return Err(ValidationError::BlockTooLarge(size));
+        }
+        // Check if the block is linked to the previous block
+        let expected_previous_block_hash = previous_headers.last().map(|header| header.hash());
+        if block.header.previous_block_hash != expected_previous_block_hash {
+            return Err(ValidationError::BadPreviousBlock { expected: expected_previous_block_hash, found: block.header.previous_block_hash });
+        }
+        let transactions = &block.transactions;
+        let merkle_root = calculate_merkle_root(transactions);
+        // Check if the merkle root of the block is correct
+        if merkle_root != block.header.merkle_root {
+            return Err(ValidationError::BadMerkleRoot);
+        }
+        // Check if the timestamp of the block is in the allowed range
+        check_block_timestamp(&block.header, previous_headers, get_current_timestamp_ms())?;
+        // Check if the block has a valid proof of work for its height
+        check_proof_of_work(block, previous_headers, params)?;
+        // Check if each transaction is valid, a transaction can spend outputs of earlier transactions in the block
+        let mut block_utxos = UtxoOverlay::new(utxo_set);
+        let height = previous_headers.len() as u64;
+        let mut fees: u128 = 0;
+        for transaction in transactions {
+            check_transaction(transaction)?;
+            if !transaction.is_coinbase() {
+                match check_transaction_inputs_with(transaction, |outpoint| block_utxos.get(outpoint).map(|entry| entry.output.clone())) {
+                    Ok(fee) => fees += fee,
+                    // the output exists before the block, so an earlier transaction of the block spent it
+                    Err(ValidationError::MissingInput(outpoint)) if utxo_set.contains(&outpoint) => {
+                        return Err(ValidationError::DoubleSpend(outpoint));
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            block_utxos.apply_transaction(transaction, height)?;
+        }
+        // Check if the coinbase transaction is in place and doesn't claim more than allowed
+        check_coinbase(block, height, fees, params)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use secp256k1::{hashes::Hash, Secp256k1};
+    use crate::constants::{COIN, MAX_FUTURE_BLOCK_TIME_MS, TX_VERSION};
+    use crate::core::pow::expected_difficulty_target;
+    use crate::core::script::Script;
+    use crate::core::transaction::{TransactionInput, TransactionOutput};
+    use crate::core::utxo::OutPoint;
+    use crate::core::sighash::SigHashType;
+    use crate::core::test_utils::{create_node, mine_genesis_block};
+    use crate::network::channel::ChannelTransport;
+    use crate::network::message::GetHeadersMessage;
+    use crate::network::tcp::TcpTransport;
+    use crate::utils::temp_dir::TempDir;
+    use crate::utils::wallets::generate_keypair;
+    use secp256k1::{hashes::Hash, Secp256k1, SecretKey};
     use secp256k1::rand::rngs::OsRng;
-    use std::sync::{Arc, Mutex};
+
+    const COINBASE_VALUE: u128 = 50 * COIN;
 
     fn generate_public_key() -> PublicKey {
         let secp = Secp256k1::new();
@@ -178,55 +482,444 @@ mod tests {
         public_key
     }
 
+    /// Regtest node whose chain has a block paying `pub_key` on top of the genesis block,
+    /// returns the node, the headers of its chain and the funding block
+    fn create_funded_node(pub_key: PublicKey) -> (Node, Vec<BlockHeader>, Block) {
+        let node = create_node(1, ChainParams::regtest());
+        let genesis_headers = vec![node.params.genesis_block().header];
+        let funding_block = mine_with_transactions(pub_key, &genesis_headers, vec![], 0);
+        node.receive_block(funding_block.clone());
+        let headers = vec![genesis_headers[0].clone(), funding_block.header.clone()];
+        (node, headers, funding_block)
+    }
+
     #[test]
     fn test_node_initialization() {
-        let node = Node::new(1);
+        let params = ChainParams::regtest();
+        let node = create_node(1, params.clone());
         assert_eq!(node.id, 1);
-        // blockchain should be empty
-        assert!(node.blockchain.lock().unwrap().is_empty());
+        // blockchain only has the genesis block of the network
+        let chain = node.chain.lock().unwrap();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain.tip().unwrap().block, params.genesis_block());
+        assert_eq!(chain.utxo_set().len(), 1);
     }
 
     #[test]
     fn test_genesis_block_creation() {
+        let params = ChainParams::regtest();
         let pub_key = generate_public_key();
-        let genesis_block = Node::init_genesis_block(pub_key.clone());
+        let genesis_block = mine_genesis_block(pub_key, &params);
 
         assert_eq!(genesis_block.transactions.len(), 1);
         assert_eq!(genesis_block.header.previous_block_hash, None);
         assert_eq!(genesis_block.header.merkle_root.as_byte_array().len(), 32);
+        assert!(genesis_block.has_valid_proof_of_work());
     }
 
     #[test]
     fn test_mine_new_block() {
+        let params = ChainParams::regtest();
+        let node = create_node(1, params.clone());
+        let genesis_block = params.genesis_block();
+        let previous_headers = vec![genesis_block.header.clone()];
+        let new_block = node.mine_new_block().unwrap();
+
+        assert_eq!(new_block.transactions.len(), 1);
+        assert_eq!(new_block.coinbase_transaction.outputs[0].recipient_pub_key, node.pub_key);
+        assert_eq!(new_block.header.previous_block_hash.unwrap(), genesis_block.hash_block());
+        assert_eq!(new_block.header.difficulty_target, expected_difficulty_target(&previous_headers, &params));
+        assert!(new_block.has_valid_proof_of_work());
+    }
+
+    #[test]
+    fn test_receive_block_updates_utxo_set() {
+        let params = ChainParams::regtest();
+        let node = create_node(1, params.clone());
+        let genesis_block = params.genesis_block();
+        let new_block = mine_with_transactions(generate_public_key(), std::slice::from_ref(&genesis_block.header), vec![], 0);
+        node.receive_block(new_block.clone());
+
+        let chain = node.chain.lock().unwrap();
+        assert_eq!(chain.len(), 2);
+        let utxo_set = chain.utxo_set();
+        assert_eq!(utxo_set.len(), 2);
+        let outpoint = OutPoint { txid: new_block.coinbase_transaction.hash(), index: 0 };
+        assert_eq!(utxo_set.get(&outpoint).unwrap().height, 1);
+    }
+
+    #[test]
+    fn test_mempool_transactions_are_mined() {
+        let (secret_key, pub_key) = generate_keypair();
+        let (node, _, funding_block) = create_funded_node(pub_key);
+        let spend = create_coinbase_spend(&funding_block, &secret_key, COINBASE_VALUE - 1_000);
+        let txid = node.submit_transaction(spend.clone()).unwrap();
+        assert_eq!(node.submit_transaction(spend.clone()), Err(MempoolError::AlreadyInMempool));
+
+        let new_block = node.mine_new_block().unwrap();
+        assert_eq!(new_block.coinbase_transaction.outputs[0].value, COINBASE_VALUE + 1_000);
+        node.receive_block(new_block.clone());
+
+        assert_eq!(node.chain.lock().unwrap().len(), 3);
+        assert_eq!(new_block.transactions[1].hash(), txid);
+        assert!(node.mempool.lock().unwrap().is_empty());
+        assert_eq!(node.submit_transaction(spend), Err(MempoolError::MissingInputs(OutPoint { txid: funding_block.coinbase_transaction.hash(), index: 0 })));
+    }
+
+    #[test]
+    fn test_reorg_returns_transactions_to_mempool() {
+        let (secret_key, pub_key) = generate_keypair();
+        let (node, headers, funding_block) = create_funded_node(pub_key);
+
+        let spend = create_coinbase_spend(&funding_block, &secret_key, COINBASE_VALUE - 1_000);
+        let block = mine_with_transactions(pub_key, &headers, vec![spend.clone()], 1_000);
+        node.receive_block(block);
+        assert!(node.mempool.lock().unwrap().is_empty());
+
+        // a heavier branch without the transaction replaces the block
+        let fork_block = mine_with_transactions(generate_public_key(), &headers, vec![], 0);
+        let fork_headers = [headers.as_slice(), std::slice::from_ref(&fork_block.header)].concat();
+        let next_block = mine_with_transactions(generate_public_key(), &fork_headers, vec![], 0);
+        node.receive_block(fork_block);
+        node.receive_block(next_block.clone());
+
+        assert_eq!(node.chain.lock().unwrap().tip().unwrap().block, next_block);
+        assert!(node.mempool.lock().unwrap().contains(&spend.hash()));
+    }
+
+    #[test]
+    fn test_orphan_blocks_connect_when_parent_arrives() {
+        let params = ChainParams::regtest();
+        let node = create_node(1, params.clone());
         let pub_key = generate_public_key();
-        let previous_block_hash = sha256_hash("dummy_previous_block_hash");
-        let transactions = vec![];
+        let genesis_block = params.genesis_block();
+        let first_block = mine_with_transactions(pub_key, std::slice::from_ref(&genesis_block.header), vec![], 0);
+        let second_block = mine_with_transactions(pub_key, &[genesis_block.header.clone(), first_block.header.clone()], vec![], 0);
 
-        let new_block = Node::mine_new_block(pub_key.clone(), previous_block_hash.clone(), transactions.clone());
+        assert_eq!(node.receive_block(second_block.clone()), Some(first_block.hash_block()));
+        assert_eq!(node.chain.lock().unwrap().len(), 1);
+        assert_eq!(node.orphans.lock().unwrap().len(), 1);
 
-        assert_eq!(new_block.transactions.len(), 1);
-        assert_eq!(new_block.header.previous_block_hash.unwrap(), previous_block_hash);
+        assert_eq!(node.receive_block(first_block), None);
+        assert_eq!(node.chain.lock().unwrap().tip().unwrap().block, second_block);
+        assert!(node.orphans.lock().unwrap().is_empty());
+    }
+
+    fn open_stored_node(params: &ChainParams, dir: &std::path::Path) -> Result<Node, ChainError> {
+        let store = BlockStore::open(&dir.join("blocks"), params.magic).unwrap();
+        let chainstate = Chainstate::open(&dir.join("chainstate")).unwrap();
+        Node::with_storage(1, params.clone(), store, chainstate)
+    }
+
+    #[test]
+    fn test_node_reloads_stored_chain() {
+        let params = ChainParams::regtest();
+        let dir = TempDir::new();
+        let mut headers = vec![params.genesis_block().header];
+        let side_block = mine_with_transactions(generate_public_key(), &headers, vec![], 0);
+        {
+            let node = open_stored_node(&params, dir.path()).unwrap();
+            for _ in 0..2 {
+                let block = mine_with_transactions(generate_public_key(), &headers, vec![], 0);
+                headers.push(block.header.clone());
+                node.receive_block(block);
+            }
+            // a block on a side branch is stored too
+            node.receive_block(side_block.clone());
+        }
+
+        let node = open_stored_node(&params, dir.path()).unwrap();
+        let chain = node.chain.lock().unwrap();
+        assert_eq!(chain.active_headers(), headers);
+        assert_eq!(chain.utxo_set().len(), 3);
+        assert!(chain.contains(&side_block.hash_block()));
+    }
+
+    #[test]
+    fn test_block_store_of_another_network() {
+        let dir = TempDir::new();
+        let params = ChainParams::regtest();
+        open_stored_node(&params, dir.path()).unwrap();
+
+        let other_params = ChainParams { genesis: ChainParams::main().genesis, ..params.clone() };
+        assert!(matches!(open_stored_node(&other_params, dir.path()), Err(ChainError::UnexpectedGenesis)));
+    }
+
+    #[test]
+    fn test_get_block_request() {
+        let params = ChainParams::main();
+        let node = create_node(1, params.clone());
+        let genesis_block = params.genesis_block();
+        let transports = ChannelTransport::connect_all(2);
+
+        node.handle_network_message(1, NetworkMessage::GetData(vec![Inventory::block(genesis_block.hash_block())]), &transports[0]);
+        match transports[1].receive_timeout(Duration::from_millis(100)) {
+            Some(PeerEvent::Message(0, NetworkMessage::Block(block))) => assert_eq!(block, genesis_block),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    /// Receives events until one matches, panics after a few seconds without it
+    fn wait_for_event<F: Fn(&PeerEvent) -> bool>(transport: &dyn Transport, matches: F) -> PeerEvent {
+        let deadline = get_current_timestamp_ms() + 5000;
+        while get_current_timestamp_ms() < deadline {
+            if let Some(event) = transport.receive_timeout(Duration::from_millis(100)) {
+                if matches(&event) {
+                    return event;
+                }
+            }
+        }
+        panic!("no matching event");
+    }
+
+    /// Waits a few seconds for the node to reach `height`
+    fn wait_for_height(node: &Node, height: u64) {
+        let deadline = get_current_timestamp_ms() + 5000;
+        while node.best_height() < height && get_current_timestamp_ms() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_simulated_nodes_share_mined_blocks() {
+        let params = ChainParams::regtest();
+        let mut transports = ChannelTransport::connect_all(2).into_iter();
+        let (mining_requests, requests) = std::sync::mpsc::channel();
+        let miner = Arc::new(create_node(0, params.clone()));
+        Arc::clone(&miner).start_node(Arc::new(transports.next().unwrap()), Mining::OnRequest(requests));
+        let node = Arc::new(create_node(1, params.clone()));
+        Arc::clone(&node).start_node(Arc::new(transports.next().unwrap()), Mining::Disabled);
+
+        mining_requests.send(()).unwrap();
+        wait_for_height(&node, 1);
+        assert_eq!(node.chain.lock().unwrap().tip().unwrap().block, miner.chain.lock().unwrap().tip().unwrap().block);
+        // only one block is mined per request
+        assert_eq!(miner.best_height(), 1);
+    }
+
+    #[test]
+    fn test_blocks_relay_between_tcp_peers() {
+        let params = ChainParams::regtest();
+        let local_addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let first_node = Arc::new(create_node(1, params.clone()));
+        let first_transport = TcpTransport::new(params.magic);
+        let first_addr = first_transport.listen(local_addr).unwrap();
+        Arc::clone(&first_node).start_node(first_transport, Mining::Disabled);
+        let second_node = Arc::new(create_node(2, params.clone()));
+        let second_transport = TcpTransport::new(params.magic);
+        Arc::clone(&second_node).start_node(Arc::clone(&second_transport) as Arc<dyn Transport>, Mining::Disabled);
+        second_transport.connect(first_addr).unwrap();
+
+        // a third peer hands a new block to the first node, which relays it to the second one
+        let client = TcpTransport::new(params.magic);
+        let peer = client.connect(first_addr).unwrap();
+        let block = mine_with_transactions(generate_public_key(), &[params.genesis_block().header], vec![], 0);
+        client.send(peer, &NetworkMessage::Block(block.clone())).unwrap();
+        wait_for_height(&second_node, 1);
+        assert_eq!(second_node.chain.lock().unwrap().tip().unwrap().block, block);
+        assert_eq!(first_node.best_height(), 1);
+
+        // the first node serves headers and pings
+        let locator = vec![params.genesis_hash()];
+        client.send(peer, &NetworkMessage::GetHeaders(GetHeadersMessage { locator, stop_hash: None })).unwrap();
+        let event = wait_for_event(client.as_ref(), |event| matches!(event, PeerEvent::Message(_, NetworkMessage::Headers(_))));
+        assert_eq!(event, PeerEvent::Message(peer, NetworkMessage::Headers(vec![block.header])));
+        client.send(peer, &NetworkMessage::Ping(3)).unwrap();
+        let event = wait_for_event(client.as_ref(), |event| matches!(event, PeerEvent::Message(_, NetworkMessage::Pong(_))));
+        assert_eq!(event, PeerEvent::Message(peer, NetworkMessage::Pong(3)));
+    }
+
+    #[test]
+    fn test_new_node_downloads_chain_headers_first() {
+        let params = ChainParams::regtest();
+        let local_addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut headers = vec![params.genesis_block().header];
+        let mut blocks = vec![];
+        for _ in 0..30 {
+            let block = mine_with_transactions(generate_public_key(), &headers, vec![], 0);
+            headers.push(block.header.clone());
+            blocks.push(block);
+        }
+        // two peers have the chain, one of them only its first part
+        let mut addrs = vec![];
+        for (id, length) in [(1, 30), (2, 20)] {
+            let node = Arc::new(create_node(id, params.clone()));
+            for block in &blocks[..length] {
+                node.receive_block(block.clone());
+            }
+            let transport = TcpTransport::new(params.magic);
+            addrs.push(transport.listen(local_addr).unwrap());
+            node.start_node(transport, Mining::Disabled);
+        }
+
+        let node = Arc::new(create_node(3, params.clone()));
+        let transport = TcpTransport::new(params.magic);
+        Arc::clone(&node).start_node(Arc::clone(&transport) as Arc<dyn Transport>, Mining::Disabled);
+        for addr in addrs {
+            transport.connect(addr).unwrap();
+        }
+        wait_for_height(&node, 30);
+        assert_eq!(node.chain.lock().unwrap().active_headers(), headers);
+        assert_eq!(node.sync.lock().unwrap().best_height(), 30);
     }
 
     #[test]
     fn test_block_validation() {
+        let params = ChainParams::regtest();
+        let pub_key = generate_public_key();
+        let genesis_block = mine_genesis_block(pub_key, &params);
+
+        assert_eq!(Node::validate_block(&genesis_block, &[], &UtxoSet::new(), &params), Ok(()));
+    }
+
+    #[test]
+    fn test_block_validation_too_large() {
+        let params = ChainParams::regtest();
+        let mut genesis_block = mine_genesis_block(generate_public_key(), &params);
+        genesis_block.transactions[0].outputs[0].script_pub_key = Script::from(vec![0; MAX_BLOCK_SIZE]);
+
+        let result = Node::validate_block(&genesis_block, &[], &UtxoSet::new(), &params);
+        assert!(matches!(result, Err(ValidationError::BlockTooLarge(size)) if size > MAX_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn test_block_validation_invalid_proof_of_work() {
+        let params = ChainParams::regtest();
         let pub_key = generate_public_key();
-        let genesis_block = Node::init_genesis_block(pub_key.clone());
+        let mut genesis_block = mine_genesis_block(pub_key, &params);
+        genesis_block.header.difficulty_target = 0;
+
+        let error = ValidationError::BadDifficultyTarget { expected: expected_difficulty_target(&[], &params), found: 0 };
+        assert_eq!(Node::validate_block(&genesis_block, &[], &UtxoSet::new(), &params), Err(error));
+    }
+
+    /// Mines a block containing exactly `transactions`, even if they are invalid
+    fn mine_with_transactions(miner_pub_key: PublicKey, previous_headers: &[BlockHeader], transactions: Vec<Transaction>, fees: u128) -> Block {
+        mine_block(BlockTemplate::new(miner_pub_key, previous_headers, transactions, fees, &ChainParams::regtest()).block, || false).unwrap()
+    }
+
+    /// Transaction spending the coinbase output of `block`, signed by `secret_key`
+    fn create_coinbase_spend(block: &Block, secret_key: &SecretKey, value: u128) -> Transaction {
+        let mut transaction = Transaction {
+            transaction_version: TX_VERSION,
+            input_count: 1,
+            inputs: vec![TransactionInput {
+                previous_transaction_hash: block.coinbase_transaction.hash(),
+                previous_transaction_index: 0,
+                script_length: 0,
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+            }],
+            output_count: 1,
+            outputs: vec![TransactionOutput {
+                value,
+                script_length: 0,
+                script_pub_key: Script::new(),
+                recipient_pub_key: generate_public_key(),
+            }],
+            lock_time: 0,
+        };
+        let spent_script_pub_key = block.coinbase_transaction.outputs[0].script_pub_key.clone();
+        transaction.sign_input(0, secret_key, &spent_script_pub_key, SigHashType::All).unwrap();
+        transaction
+    }
+
+    #[test]
+    fn test_block_validation_with_transactions() {
+        let params = ChainParams::regtest();
+        let (secret_key, pub_key) = generate_keypair();
+        let genesis_block = mine_genesis_block(pub_key, &params);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.apply_block(&genesis_block, 0).unwrap();
+        let previous_headers = vec![genesis_block.header.clone()];
+
+        let spend = create_coinbase_spend(&genesis_block, &secret_key, COINBASE_VALUE - 1_000);
+        let new_block = mine_with_transactions(pub_key, &previous_headers, vec![spend], 1_000);
+        assert_eq!(Node::validate_block(&new_block, &previous_headers, &utxo_set, &params), Ok(()));
+    }
+
+    #[test]
+    fn test_block_validation_coinbase_claims_unpaid_fees() {
+        let params = ChainParams::regtest();
+        let (secret_key, pub_key) = generate_keypair();
+        let genesis_block = mine_genesis_block(pub_key, &params);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.apply_block(&genesis_block, 0).unwrap();
+        let previous_headers = vec![genesis_block.header.clone()];
+
+        let spend = create_coinbase_spend(&genesis_block, &secret_key, COINBASE_VALUE - 1_000);
+        let new_block = mine_with_transactions(pub_key, &previous_headers, vec![spend], 1_001);
+        let error = ValidationError::CoinbaseValueTooHigh { claimed: COINBASE_VALUE + 1_001, allowed: COINBASE_VALUE + 1_000 };
+        assert_eq!(Node::validate_block(&new_block, &previous_headers, &utxo_set, &params), Err(error));
+    }
+
+    #[test]
+    fn test_block_validation_double_spend() {
+        let params = ChainParams::regtest();
+        let (secret_key, pub_key) = generate_keypair();
+        let genesis_block = mine_genesis_block(pub_key, &params);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.apply_block(&genesis_block, 0).unwrap();
+        let previous_headers = vec![genesis_block.header.clone()];
+
+        let first_spend = create_coinbase_spend(&genesis_block, &secret_key, 1_000);
+        let second_spend = create_coinbase_spend(&genesis_block, &secret_key, 2_000);
+        let new_block = mine_with_transactions(pub_key, &previous_headers, vec![first_spend, second_spend], 0);
+        let spent = OutPoint { txid: genesis_block.coinbase_transaction.hash(), index: 0 };
+        assert_eq!(Node::validate_block(&new_block, &previous_headers, &utxo_set, &params), Err(ValidationError::DoubleSpend(spent)));
+    }
+
+    #[test]
+    fn test_block_validation_unsigned_transaction() {
+        let params = ChainParams::regtest();
+        let (_, pub_key) = generate_keypair();
+        let genesis_block = mine_genesis_block(pub_key, &params);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.apply_block(&genesis_block, 0).unwrap();
+        let previous_headers = vec![genesis_block.header.clone()];
 
-        let is_valid = Node::validate_block(&genesis_block);
-        assert!(is_valid);
+        let (other_secret_key, _) = generate_keypair();
+        let spend = create_coinbase_spend(&genesis_block, &other_secret_key, 1_000);
+        let error = ValidationError::BadSignature { txid: spend.hash(), input_index: 0 };
+        let new_block = mine_with_transactions(pub_key, &previous_headers, vec![spend], 1_000);
+        assert_eq!(Node::validate_block(&new_block, &previous_headers, &utxo_set, &params), Err(error));
     }
 
     #[test]
-    fn test_blockchain_validation() {
+    fn test_block_validation_linkage_and_median_time_past() {
         let pub_key = generate_public_key();
-        let genesis_block = Node::init_genesis_block(pub_key.clone());
-        let mut blockchain = vec![genesis_block.clone()];
+        let params = ChainParams::regtest();
+        let genesis_block = params.genesis_block();
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.apply_block(&genesis_block, 0).unwrap();
+        let mut headers = vec![genesis_block.header.clone()];
+        for height in 1..3 {
+            let block = mine_with_transactions(pub_key, &headers, vec![], 0);
+            utxo_set.apply_block(&block, height).unwrap();
+            headers.push(block.header.clone());
+        }
+
+        // a block mined on another parent doesn't extend the chain
+        let unlinked = mine_with_transactions(pub_key, &headers[..1], vec![], 0);
+        let error = ValidationError::BadPreviousBlock { expected: Some(headers[2].hash()), found: Some(genesis_block.hash_block()) };
+        assert_eq!(Node::validate_block(&unlinked, &headers, &utxo_set, &params), Err(error));
 
-        let new_block = Node::mine_new_block(pub_key.clone(), genesis_block.hash_block(), vec![]);
-        blockchain.push(new_block);
+        // a block that isn't later than the median time past
+        let mut early_block = BlockTemplate::new(pub_key, &headers, vec![], 0, &params).block;
+        early_block.header.timestamp = headers[1].timestamp;
+        let early_block = mine_block(early_block, || false).unwrap();
+        let error = ValidationError::TimestampTooOld { timestamp: headers[1].timestamp, median_time_past: headers[1].timestamp };
+        assert_eq!(Node::validate_block(&early_block, &headers, &utxo_set, &params), Err(error));
+    }
+
+    #[test]
+    fn test_block_validation_timestamp_too_far_in_future() {
+        let params = ChainParams::regtest();
+        let mut block = BlockTemplate::new(generate_public_key(), &[], vec![], 0, &params).block;
+        block.header.timestamp = get_current_timestamp_ms() + MAX_FUTURE_BLOCK_TIME_MS + 60_000;
+        let block = mine_block(block, || false).unwrap();
 
-        let is_valid = Node::validate_blockchain(&blockchain);
-        assert!(is_valid);
+        let result = Node::validate_block(&block, &[], &UtxoSet::new(), &params);
+        assert!(matches!(result, Err(ValidationError::TimestampTooNew { .. })));
     }
 }
@@ -0,0 +1,207 @@
+use crate::core::block::BlockHeader;
+use crate::core::params::ChainParams;
+use crate::core::pow::{compact_to_target, target_to_compact};
+use crate::utils::u256::U256;
+
+/// Maximum factor the difficulty can change by in one adjustment (same as in Bitcoin)
+const MAX_ADJUSTMENT_FACTOR: u128 = 4;
+
+/// Solve times are clamped to this many block intervals in LWMA mode
+const LWMA_MAX_SOLVE_TIME_FACTOR: u128 = 6;
+
+/// Algorithm used to adjust the difficulty target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetargetMode {
+    /// Bitcoin style: the difficulty changes once every `retarget_interval` blocks
+    /// based on the time it took to mine the last window of blocks
+    Window,
+    /// Linearly weighted moving average: the difficulty changes on every block,
+    /// recent solve times weigh more than older ones
+    Lwma,
+}
+
+/// Computes the difficulty target (compact "bits") for the block built on top of `previous_headers`
+/// so that blocks are mined every `target_spacing_ms` on average
+pub fn next_difficulty_target(previous_headers: &[BlockHeader], params: &ChainParams) -> u32 {
+    if previous_headers.is_empty() {
+        return params.genesis.difficulty_target;
+    }
+    match params.retarget_mode {
+        RetargetMode::Window => next_difficulty_target_window(previous_headers, params),
+        RetargetMode::Lwma => next_difficulty_target_lwma(previous_headers, params),
+    }
+}
+
+fn next_difficulty_target_window(previous_headers: &[BlockHeader], params: &ChainParams) -> u32 {
+    let height = previous_headers.len();
+    let last_header = previous_headers.last().unwrap();
+    if !height.is_multiple_of(params.retarget_interval) {
+        return last_header.difficulty_target;
+    }
+
+    let first_header = &previous_headers[height - params.retarget_interval];
+    let expected_timespan = (params.retarget_interval as u128 - 1) * params.target_spacing_ms as u128;
+    let actual_timespan = last_header.timestamp.saturating_sub(first_header.timestamp)
+        .clamp(expected_timespan / MAX_ADJUSTMENT_FACTOR, expected_timespan * MAX_ADJUSTMENT_FACTOR);
+
+    let pow_limit = pow_limit(params);
+    let last_target = compact_to_target(last_header.difficulty_target).unwrap_or(pow_limit);
+    scale_target(&last_target, actual_timespan, expected_timespan, &pow_limit)
+}
+
+fn next_difficulty_target_lwma(previous_headers: &[BlockHeader], params: &ChainParams) -> u32 {
+    let window = params.lwma_window.min(previous_headers.len() - 1);
+    if window == 0 {
+        return previous_headers[0].difficulty_target;
+    }
+
+    let headers = &previous_headers[previous_headers.len() - window - 1..];
+    let target_spacing = params.target_spacing_ms as u128;
+    let max_solve_time = LWMA_MAX_SOLVE_TIME_FACTOR * target_spacing;
+    let pow_limit = pow_limit(params);
+    let mut weighted_solve_times: u128 = 0;
+    let mut target_sum = U256::ZERO;
+    for i in 1..=window {
+        let solve_time = headers[i].timestamp.saturating_sub(headers[i - 1].timestamp).clamp(1, max_solve_time);
+        weighted_solve_times += solve_time * i as u128;
+        target_sum = target_sum + compact_to_target(headers[i].difficulty_target).unwrap_or(pow_limit);
+    }
+    let weights_sum = (window * (window + 1) / 2) as u128;
+    let average_target = target_sum.div_u64(window as u64);
+
+    // the average solve time is bounded by the clamping above, no need to limit the adjustment further
+    scale_target(&average_target, weighted_solve_times, target_spacing * weights_sum, &pow_limit)
+}
+
+/// Returns `target * actual / expected` in the compact format, never easier than the proof of work limit
+fn scale_target(target: &U256, actual: u128, expected: u128, pow_limit: &U256) -> u32 {
+    let (actual, expected) = (actual as u64, expected as u64);
+    // multiply first to keep the precision, unless the multiplication would overflow
+    let new_target = match target.checked_mul_u64(actual) {
+        Some(product) => product.div_u64(expected),
+        None => target.div_u64(expected).checked_mul_u64(actual).unwrap_or(U256::MAX),
+    };
+    target_to_compact(&new_target.min(*pow_limit))
+}
+
+fn pow_limit(params: &ChainParams) -> U256 {
+    compact_to_target(params.pow_limit_bits).expect("valid proof of work limit")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::SOFTWARE_VERSION;
+    use crate::utils::hash::sha256_hash;
+
+    const TEST_BITS: u32 = 0x1e0fffff;
+    // retarget settings of the test params
+    const SPACING: u128 = 5000;
+    const INTERVAL: usize = 10;
+    const WINDOW: usize = 10;
+    const POW_LIMIT: u32 = 0x2000ffff;
+
+    fn params(retarget_mode: RetargetMode) -> ChainParams {
+        ChainParams {
+            retarget_mode,
+            target_spacing_ms: SPACING as u64,
+            retarget_interval: INTERVAL,
+            lwma_window: WINDOW,
+            pow_limit_bits: POW_LIMIT,
+            ..ChainParams::main()
+        }
+    }
+
+    /// Creates `count` headers mined every `spacing_ms` at a fixed difficulty target
+    fn create_headers(count: usize, spacing_ms: u128, difficulty_target: u32) -> Vec<BlockHeader> {
+        (0..count).map(|i| BlockHeader {
+            software_version: SOFTWARE_VERSION.to_string(),
+            previous_block_hash: None,
+            merkle_root: sha256_hash("dummy_merkle_root"),
+            timestamp: 1_000_000 + i as u128 * spacing_ms,
+            difficulty_target,
+            nonce: 0,
+        }).collect()
+    }
+
+    fn target(bits: u32) -> U256 {
+        compact_to_target(bits).unwrap()
+    }
+
+    /// Compact encoding of the test target multiplied by `numerator / denominator`
+    fn scaled_bits(numerator: u64, denominator: u64) -> u32 {
+        target_to_compact(&target(TEST_BITS).checked_mul_u64(numerator).unwrap().div_u64(denominator))
+    }
+
+    #[test]
+    fn test_genesis_difficulty_target() {
+        let genesis_bits = ChainParams::main().genesis.difficulty_target;
+        assert_eq!(next_difficulty_target(&[], &params(RetargetMode::Window)), genesis_bits);
+        assert_eq!(next_difficulty_target(&[], &params(RetargetMode::Lwma)), genesis_bits);
+    }
+
+    #[test]
+    fn test_window_keeps_target_between_retargets() {
+        let headers = create_headers(INTERVAL - 1, 1, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Window)), TEST_BITS);
+    }
+
+    #[test]
+    fn test_window_on_schedule() {
+        let headers = create_headers(INTERVAL, SPACING, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Window)), TEST_BITS);
+    }
+
+    #[test]
+    fn test_window_fast_blocks_increase_difficulty() {
+        // blocks twice as fast as expected -> half the target
+        let headers = create_headers(INTERVAL, SPACING / 2, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Window)), scaled_bits(1, 2));
+    }
+
+    #[test]
+    fn test_window_slow_blocks_decrease_difficulty() {
+        let headers = create_headers(INTERVAL, SPACING * 2, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Window)), scaled_bits(2, 1));
+    }
+
+    #[test]
+    fn test_window_adjustment_is_limited() {
+        let headers = create_headers(INTERVAL, 1, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Window)), scaled_bits(1, 4));
+        let headers = create_headers(INTERVAL, SPACING * 100, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Window)), scaled_bits(4, 1));
+    }
+
+    #[test]
+    fn test_difficulty_target_limit() {
+        let headers = create_headers(INTERVAL, SPACING * 100, POW_LIMIT);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Window)), POW_LIMIT);
+    }
+
+    #[test]
+    fn test_lwma_on_schedule() {
+        let headers = create_headers(WINDOW + 1, SPACING, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Lwma)), TEST_BITS);
+    }
+
+    #[test]
+    fn test_lwma_adjusts_every_block() {
+        let headers = create_headers(3, SPACING / 2, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Lwma)), scaled_bits(1, 2));
+        let headers = create_headers(3, SPACING * 2, TEST_BITS);
+        assert_eq!(next_difficulty_target(&headers, &params(RetargetMode::Lwma)), scaled_bits(2, 1));
+    }
+
+    #[test]
+    fn test_lwma_weighs_recent_blocks_more() {
+        // old blocks were on schedule, but the recent ones are 4 times faster than expected
+        let mut headers = create_headers(WINDOW + 1, SPACING, TEST_BITS);
+        for i in WINDOW / 2..headers.len() {
+            headers[i].timestamp = headers[i - 1].timestamp + SPACING / 4;
+        }
+        let new_target = target(next_difficulty_target(&headers, &params(RetargetMode::Lwma)));
+        // a simple average of the solve times would give (4 * 1 + 6 * 1/4) / 10 = 0.55 of the old target
+        assert!(new_target < target(scaled_bits(55, 100)));
+    }
+}
@@ -0,0 +1,742 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use secp256k1::hashes::sha256;
+
+use crate::constants::{INCREMENTAL_RELAY_FEE_RATE, MAX_MEMPOOL_ANCESTORS, MAX_MEMPOOL_DESCENDANTS, MAX_MEMPOOL_SIZE, MAX_REPLACEMENT_EVICTIONS};
+use crate::core::block::Block;
+use crate::core::serialize::Encodable;
+use crate::core::transaction::{Transaction, TransactionOutput};
+use crate::core::utxo::{OutPoint, UtxoSet};
+use crate::core::validation::{check_transaction, check_transaction_inputs_with, ValidationError};
+
+/// Unconfirmed transaction waiting in the mempool
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    pub txid: sha256::Hash,
+    /// Inputs minus outputs, paid to the miner
+    pub fee: u128,
+    /// Size of the encoded transaction in bytes
+    pub size: usize,
+    /// Arrival order, a transaction always arrives after its mempool parents
+    sequence: u64,
+    /// Mempool transactions whose outputs this transaction spends
+    parents: HashSet<sha256::Hash>,
+    /// Mempool transactions spending outputs of this transaction
+    children: HashSet<sha256::Hash>,
+}
+
+impl MempoolEntry {
+    #[cfg(test)]
+    pub fn parents(&self) -> &HashSet<sha256::Hash> {
+        &self.parents
+    }
+
+    #[cfg(test)]
+    pub fn children(&self) -> &HashSet<sha256::Hash> {
+        &self.children
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    /// Coinbase transactions are only valid in blocks
+    Coinbase,
+    /// The transaction fails the context-free checks
+    InvalidTransaction(ValidationError),
+    AlreadyInMempool,
+    /// The spent output is neither in the UTXO set nor created by a mempool transaction
+    MissingInputs(OutPoint),
+    /// The output is already spent by a mempool transaction that can't be replaced
+    Conflict(OutPoint),
+    /// The transaction conflicts with replaceable mempool transactions but can't replace them
+    ReplacementRejected(ReplacementError),
+    /// A signature is invalid or the outputs are worth more than the inputs
+    InvalidInputs(ValidationError),
+    TooManyAncestors,
+    TooManyDescendants,
+    /// The transaction was evicted right away because its fee rate is too low
+    MempoolFull,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Coinbase => write!(f, "coinbase transactions can't be relayed"),
+            MempoolError::InvalidTransaction(error) => write!(f, "transaction is invalid: {}", error),
+            MempoolError::AlreadyInMempool => write!(f, "transaction is already in the mempool"),
+            MempoolError::MissingInputs(outpoint) => write!(f, "output {} is missing", outpoint),
+            MempoolError::Conflict(outpoint) => write!(f, "output {} is already spent by a mempool transaction", outpoint),
+            MempoolError::ReplacementRejected(error) => write!(f, "replacement rejected: {}", error),
+            MempoolError::InvalidInputs(error) => write!(f, "transaction inputs are invalid: {}", error),
+            MempoolError::TooManyAncestors => write!(f, "more than {} unconfirmed ancestors", MAX_MEMPOOL_ANCESTORS),
+            MempoolError::TooManyDescendants => write!(f, "more than {} unconfirmed descendants", MAX_MEMPOOL_DESCENDANTS),
+            MempoolError::MempoolFull => write!(f, "mempool is full"),
+        }
+    }
+}
+
+/// Reason why a transaction can't replace the mempool transactions it conflicts with (BIP125)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplacementError {
+    /// The conflicting transactions and their descendants are more than `MAX_REPLACEMENT_EVICTIONS`
+    TooManyReplacements(usize),
+    /// The replacement spends an output of a transaction it would replace
+    SpendsConflictingTransaction(sha256::Hash),
+    /// The replacement spends an unconfirmed output the replaced transactions didn't spend
+    NewUnconfirmedInput(OutPoint),
+    /// The replacement fee rate isn't higher than the one of a directly conflicting transaction
+    LowerFeeRate(sha256::Hash),
+    /// The replacement pays less than all replaced transactions together
+    LowerAbsoluteFee { replaced_fees: u128, fee: u128 },
+    /// The fee paid on top of the replaced fees doesn't cover the replacement size at `INCREMENTAL_RELAY_FEE_RATE`
+    InsufficientAdditionalFee { additional_fee: u128, required: u128 },
+}
+
+impl fmt::Display for ReplacementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplacementError::TooManyReplacements(count) => write!(f, "{} transactions would be evicted, at most {} are allowed", count, MAX_REPLACEMENT_EVICTIONS),
+            ReplacementError::SpendsConflictingTransaction(txid) => write!(f, "spends an output of the replaced transaction {}", txid),
+            ReplacementError::NewUnconfirmedInput(outpoint) => write!(f, "spends the new unconfirmed output {}", outpoint),
+            ReplacementError::LowerFeeRate(txid) => write!(f, "fee rate isn't higher than the one of {}", txid),
+            ReplacementError::LowerAbsoluteFee { replaced_fees, fee } => write!(f, "fee {} is lower than the replaced fees {}", fee, replaced_fees),
+            ReplacementError::InsufficientAdditionalFee { additional_fee, required } => write!(f, "additional fee {} is lower than {}", additional_fee, required),
+        }
+    }
+}
+
+/// Pool of valid unconfirmed transactions, they spend outputs of the UTXO set
+/// or of other mempool transactions and never conflict with each other
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<sha256::Hash, MempoolEntry>,
+    /// Mempool transaction spending each outpoint
+    spent_outpoints: HashMap<OutPoint, sha256::Hash>,
+    /// Sum of the sizes of all entries
+    total_size: usize,
+    max_size: usize,
+    next_sequence: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Mempool {
+        Mempool::new(MAX_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            spent_outpoints: HashMap::new(),
+            total_size: 0,
+            max_size,
+            next_sequence: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn contains(&self, txid: &sha256::Hash) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &sha256::Hash) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    /// Mempool transaction spending `outpoint`
+    #[cfg(test)]
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&sha256::Hash> {
+        self.spent_outpoints.get(outpoint)
+    }
+
+    /// All entries in arrival order, so parents always come before their children
+    pub fn entries(&self) -> Vec<&MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries
+    }
+
+    /// Output `outpoint` as seen by a new transaction:
+    /// unspent in the UTXO set or created by a mempool transaction
+    fn get_output(&self, outpoint: &OutPoint, utxo_set: &UtxoSet) -> Option<TransactionOutput> {
+        if let Some(entry) = utxo_set.get(outpoint) {
+            return Some(entry.output.clone());
+        }
+        self.entries.get(&outpoint.txid)?.transaction.outputs.get(outpoint.index as usize).cloned()
+    }
+
+    /// Validates `transaction` against the UTXO set extended with the mempool and adds it.
+    /// A transaction spending outputs already spent in the mempool replaces the conflicting transactions
+    /// (and their descendants) if they signal replace-by-fee and it pays more (BIP125).
+    /// If the mempool grows over its maximum size, the transactions with the lowest fee rate are evicted.
+    /// Returns the id of the added transaction
+    pub fn add_transaction(&mut self, transaction: Transaction, utxo_set: &UtxoSet) -> Result<sha256::Hash, MempoolError> {
+        if transaction.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+        check_transaction(&transaction).map_err(MempoolError::InvalidTransaction)?;
+        let txid = transaction.hash();
+        if self.contains(&txid) {
+            return Err(MempoolError::AlreadyInMempool);
+        }
+
+        let mut parents = HashSet::new();
+        let mut conflicts = HashSet::new();
+        for input in &transaction.inputs {
+            let outpoint = input.outpoint();
+            if let Some(conflict) = self.spent_outpoints.get(&outpoint) {
+                if !self.signals_replacement(conflict) {
+                    return Err(MempoolError::Conflict(outpoint));
+                }
+                conflicts.insert(*conflict);
+            }
+            if self.get_output(&outpoint, utxo_set).is_none() {
+                return Err(MempoolError::MissingInputs(outpoint));
+            }
+            if self.contains(&outpoint.txid) {
+                parents.insert(outpoint.txid);
+            }
+        }
+        let fee = check_transaction_inputs_with(&transaction, |outpoint| self.get_output(outpoint, utxo_set))
+            .map_err(MempoolError::InvalidInputs)?;
+        let replaced = if conflicts.is_empty() {
+            HashSet::new()
+        } else {
+            self.check_replacement(&transaction, fee, &conflicts).map_err(MempoolError::ReplacementRejected)?
+        };
+
+        let mut ancestors = HashSet::new();
+        for parent in &parents {
+            ancestors.insert(*parent);
+            ancestors.extend(self.ancestors(parent));
+        }
+        if ancestors.len() > MAX_MEMPOOL_ANCESTORS {
+            return Err(MempoolError::TooManyAncestors);
+        }
+        // the new transaction becomes a descendant of all its ancestors
+        if ancestors.iter().any(|ancestor| self.descendants(ancestor).len() + 1 > MAX_MEMPOOL_DESCENDANTS) {
+            return Err(MempoolError::TooManyDescendants);
+        }
+
+        let mut evicted: Vec<MempoolEntry> = replaced.iter()
+            .filter_map(|replaced_txid| self.remove_entry(replaced_txid))
+            .collect();
+        self.insert_entry(transaction, txid, fee, parents);
+        evicted.extend(self.trim_to_size());
+        if !self.contains(&txid) {
+            // the transaction didn't stay, everything it replaced or pushed out goes back
+            evicted.retain(|entry| entry.txid != txid);
+            self.restore_entries(evicted);
+            return Err(MempoolError::MempoolFull);
+        }
+        Ok(txid)
+    }
+
+    /// True if the transaction or one of its mempool ancestors signals replace-by-fee (BIP125)
+    pub fn signals_replacement(&self, txid: &sha256::Hash) -> bool {
+        let Some(entry) = self.entries.get(txid) else {
+            return false;
+        };
+        entry.transaction.signals_replacement() || self.ancestors(txid).iter()
+            .filter_map(|ancestor| self.entries.get(ancestor))
+            .any(|ancestor| ancestor.transaction.signals_replacement())
+    }
+
+    /// Checks the BIP125 rules for `transaction` (paying `fee`) replacing the mempool transactions
+    /// it directly conflicts with. Returns all transactions to evict: the conflicts and their descendants
+    fn check_replacement(&self, transaction: &Transaction, fee: u128, conflicts: &HashSet<sha256::Hash>) -> Result<HashSet<sha256::Hash>, ReplacementError> {
+        let mut replaced = conflicts.clone();
+        for conflict in conflicts {
+            replaced.extend(self.descendants(conflict));
+        }
+        if replaced.len() > MAX_REPLACEMENT_EVICTIONS {
+            return Err(ReplacementError::TooManyReplacements(replaced.len()));
+        }
+
+        // unconfirmed outputs spent by the replaced transactions
+        let conflict_parents: HashSet<sha256::Hash> = conflicts.iter()
+            .filter_map(|conflict| self.entries.get(conflict))
+            .flat_map(|entry| entry.parents.iter().copied())
+            .collect();
+        for input in &transaction.inputs {
+            let outpoint = input.outpoint();
+            if replaced.contains(&outpoint.txid) {
+                return Err(ReplacementError::SpendsConflictingTransaction(outpoint.txid));
+            }
+            if self.contains(&outpoint.txid) && !conflict_parents.contains(&outpoint.txid) {
+                return Err(ReplacementError::NewUnconfirmedInput(outpoint));
+            }
+        }
+
+        let size = transaction.encode().len();
+        for conflict in conflicts {
+            let entry = &self.entries[conflict];
+            if fee * entry.size as u128 <= entry.fee * size as u128 {
+                return Err(ReplacementError::LowerFeeRate(*conflict));
+            }
+        }
+        let replaced_fees: u128 = replaced.iter().map(|txid| self.entries[txid].fee).sum();
+        if fee < replaced_fees {
+            return Err(ReplacementError::LowerAbsoluteFee { replaced_fees, fee });
+        }
+        let required = INCREMENTAL_RELAY_FEE_RATE * size as u128;
+        if fee - replaced_fees < required {
+            return Err(ReplacementError::InsufficientAdditionalFee { additional_fee: fee - replaced_fees, required });
+        }
+        Ok(replaced)
+    }
+
+    fn insert_entry(&mut self, transaction: Transaction, txid: sha256::Hash, fee: u128, parents: HashSet<sha256::Hash>) {
+        for input in &transaction.inputs {
+            self.spent_outpoints.insert(input.outpoint(), txid);
+        }
+        for parent in &parents {
+            if let Some(parent_entry) = self.entries.get_mut(parent) {
+                parent_entry.children.insert(txid);
+            }
+        }
+        let size = transaction.encode().len();
+        self.total_size += size;
+        self.entries.insert(txid, MempoolEntry {
+            transaction,
+            txid,
+            fee,
+            size,
+            sequence: self.next_sequence,
+            parents,
+            children: HashSet::new(),
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Puts back entries removed together with all their mempool descendants. They keep their arrival order,
+    /// so parents are back before their children (the links are rebuilt, removing an entry unlinks the others)
+    fn restore_entries(&mut self, mut entries: Vec<MempoolEntry>) {
+        entries.sort_by_key(|entry| entry.sequence);
+        for mut entry in entries {
+            entry.parents.clear();
+            for input in &entry.transaction.inputs {
+                let outpoint = input.outpoint();
+                self.spent_outpoints.insert(outpoint, entry.txid);
+                if self.contains(&outpoint.txid) {
+                    entry.parents.insert(outpoint.txid);
+                }
+            }
+            for parent in &entry.parents {
+                if let Some(parent_entry) = self.entries.get_mut(parent) {
+                    parent_entry.children.insert(entry.txid);
+                }
+            }
+            entry.children.clear();
+            self.total_size += entry.size;
+            self.entries.insert(entry.txid, entry);
+        }
+    }
+
+    /// Removes a single entry, its children stay in the mempool
+    fn remove_entry(&mut self, txid: &sha256::Hash) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        for input in &entry.transaction.inputs {
+            self.spent_outpoints.remove(&input.outpoint());
+        }
+        for parent in &entry.parents {
+            if let Some(parent_entry) = self.entries.get_mut(parent) {
+                parent_entry.children.remove(txid);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child_entry) = self.entries.get_mut(child) {
+                child_entry.parents.remove(txid);
+            }
+        }
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+
+    /// Removes a transaction and everything spending its outputs
+    pub fn remove_with_descendants(&mut self, txid: &sha256::Hash) {
+        if !self.contains(txid) {
+            return;
+        }
+        let mut removed = self.descendants(txid);
+        removed.insert(*txid);
+        for txid in removed {
+            self.remove_entry(&txid);
+        }
+    }
+
+    /// All in-mempool transactions the given transaction depends on (not including itself)
+    pub fn ancestors(&self, txid: &sha256::Hash) -> HashSet<sha256::Hash> {
+        self.walk(txid, |entry| &entry.parents)
+    }
+
+    /// All in-mempool transactions depending on the given transaction (not including itself)
+    pub fn descendants(&self, txid: &sha256::Hash) -> HashSet<sha256::Hash> {
+        self.walk(txid, |entry| &entry.children)
+    }
+
+    fn walk<F: Fn(&MempoolEntry) -> &HashSet<sha256::Hash>>(&self, txid: &sha256::Hash, next: F) -> HashSet<sha256::Hash> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![*txid];
+        while let Some(current) = stack.pop() {
+            let Some(entry) = self.entries.get(&current) else {
+                continue;
+            };
+            for linked in next(entry) {
+                if visited.insert(*linked) {
+                    stack.push(*linked);
+                }
+            }
+        }
+        visited
+    }
+
+    /// Fee and size of a transaction together with all its descendants
+    fn descendant_package(&self, txid: &sha256::Hash) -> (u128, usize) {
+        let mut package = self.descendants(txid);
+        package.insert(*txid);
+        package.iter()
+            .filter_map(|txid| self.entries.get(txid))
+            .fold((0, 0), |(fee, size), entry| (fee + entry.fee, size + entry.size))
+    }
+
+    /// Evicts the transactions whose descendant package has the lowest fee rate
+    /// (together with their descendants) until the mempool fits in its maximum size, returns the evicted entries
+    fn trim_to_size(&mut self) -> Vec<MempoolEntry> {
+        let mut evicted = vec![];
+        while self.total_size > self.max_size {
+            let worst = self.entries.keys()
+                .map(|txid| (*txid, self.descendant_package(txid)))
+                .min_by(|(_, (fee_a, size_a)), (_, (fee_b, size_b))| (fee_a * *size_b as u128).cmp(&(fee_b * *size_a as u128)))
+                .map(|(txid, _)| txid);
+            let Some(txid) = worst else { break };
+            let mut removed = self.descendants(&txid);
+            removed.insert(txid);
+            evicted.extend(removed.iter().filter_map(|txid| self.remove_entry(txid)));
+        }
+        evicted
+    }
+
+    /// Updates the mempool after `block` was connected: its transactions are confirmed
+    /// and mempool transactions spending the same outputs are removed with their descendants
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            if self.remove_entry(&txid).is_some() {
+                continue;
+            }
+            if transaction.is_coinbase() {
+                continue;
+            }
+            for input in &transaction.inputs {
+                if let Some(conflict) = self.spent_outpoints.get(&input.outpoint()).copied() {
+                    self.remove_with_descendants(&conflict);
+                }
+            }
+        }
+    }
+
+    /// Updates the mempool after `block` was disconnected, `utxo_set` is the set of the current active chain
+    /// (without the block): the block transactions go back to the mempool and the existing entries are validated again,
+    /// the ones that are no longer valid are dropped. When a reorg disconnects several blocks they are added
+    /// starting with the old tip, so the transactions of earlier blocks end up before the ones spending them
+    pub fn add_disconnected_block(&mut self, block: &Block, utxo_set: &UtxoSet) {
+        let previous_transactions: Vec<Transaction> = self.entries().into_iter()
+            .map(|entry| entry.transaction.clone())
+            .collect();
+        self.entries.clear();
+        self.spent_outpoints.clear();
+        self.total_size = 0;
+        let block_transactions = block.transactions.iter().filter(|transaction| !transaction.is_coinbase());
+        for transaction in block_transactions.cloned().chain(previous_transactions) {
+            let _ = self.add_transaction(transaction, utxo_set);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::SecretKey;
+    use crate::constants::MAX_BIP125_RBF_SEQUENCE;
+    use crate::core::sighash::SigHashType;
+    use crate::core::test_utils::{create_funded_utxo_set, create_spend, COINBASE_VALUE};
+    use crate::core::transaction::p2pkh_script;
+    use crate::utils::wallets::generate_keypair;
+
+    /// Sets all input sequences to signal replace-by-fee and signs the transaction again
+    fn signal_replacement(mut transaction: Transaction, secret_key: &SecretKey) -> Transaction {
+        let public_key = secp256k1::PublicKey::from_secret_key(&secp256k1::Secp256k1::new(), secret_key);
+        for input in transaction.inputs.iter_mut() {
+            input.sequence = MAX_BIP125_RBF_SEQUENCE;
+        }
+        for index in 0..transaction.inputs.len() {
+            transaction.sign_input(index, secret_key, &p2pkh_script(&public_key), SigHashType::All).unwrap();
+        }
+        transaction
+    }
+
+    fn block_with(transactions: Vec<Transaction>) -> Block {
+        let (_, public_key) = generate_keypair();
+        let mut block = crate::core::test_utils::mine_genesis_block(public_key, &crate::core::params::ChainParams::regtest());
+        block.transactions.extend(transactions);
+        block
+    }
+
+    #[test]
+    fn test_add_transaction() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let mut mempool = Mempool::default();
+        let transaction = create_spend(&outpoints, COINBASE_VALUE, 1_000, 1, &secret_key);
+        let txid = mempool.add_transaction(transaction.clone(), &utxo_set).unwrap();
+
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.get(&txid).unwrap().fee, 1_000);
+        assert_eq!(mempool.total_size(), transaction.encode().len());
+        assert_eq!(mempool.spender(&outpoints[0]), Some(&txid));
+        assert_eq!(mempool.add_transaction(transaction, &utxo_set), Err(MempoolError::AlreadyInMempool));
+    }
+
+    #[test]
+    fn test_reject_invalid_transactions() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let mut mempool = Mempool::default();
+        let (other_secret_key, public_key) = generate_keypair();
+
+        let coinbase = Transaction::new_coinbase_transaction(p2pkh_script(&public_key), public_key, 5, COINBASE_VALUE);
+        assert_eq!(mempool.add_transaction(coinbase, &utxo_set), Err(MempoolError::Coinbase));
+
+        let missing = OutPoint { txid: outpoints[0].txid, index: 1 };
+        let transaction = create_spend(&[missing], COINBASE_VALUE, 0, 1, &secret_key);
+        assert_eq!(mempool.add_transaction(transaction, &utxo_set), Err(MempoolError::MissingInputs(missing)));
+
+        let transaction = create_spend(&outpoints, COINBASE_VALUE, 0, 1, &other_secret_key);
+        let bad_signature = ValidationError::BadSignature { txid: transaction.hash(), input_index: 0 };
+        assert_eq!(mempool.add_transaction(transaction, &utxo_set), Err(MempoolError::InvalidInputs(bad_signature)));
+
+        let mut transaction = create_spend(&outpoints, COINBASE_VALUE, 0, 1, &secret_key);
+        transaction.outputs[0].value += 1;
+        let bad_signature = ValidationError::BadSignature { txid: transaction.hash(), input_index: 0 };
+        assert_eq!(mempool.add_transaction(transaction, &utxo_set), Err(MempoolError::InvalidInputs(bad_signature)));
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_reject_conflicts() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let mut mempool = Mempool::default();
+        mempool.add_transaction(create_spend(&outpoints, COINBASE_VALUE, 1_000, 1, &secret_key), &utxo_set).unwrap();
+
+        let double_spend = create_spend(&outpoints, COINBASE_VALUE, 2_000, 1, &secret_key);
+        assert_eq!(mempool.add_transaction(double_spend, &utxo_set), Err(MempoolError::Conflict(outpoints[0])));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let mut mempool = Mempool::default();
+        let original = signal_replacement(create_spend(&outpoints, COINBASE_VALUE, 1_000, 2, &secret_key), &secret_key);
+        let original_txid = mempool.add_transaction(original.clone(), &utxo_set).unwrap();
+        let child = create_spend(&[OutPoint { txid: original_txid, index: 0 }], original.outputs[0].value, 1_000, 1, &secret_key);
+        let child_txid = mempool.add_transaction(child, &utxo_set).unwrap();
+
+        // the child inherits the signal of its parent
+        assert!(mempool.signals_replacement(&child_txid));
+        let replacement = create_spend(&outpoints, COINBASE_VALUE, 5_000, 1, &secret_key);
+        let replacement_txid = mempool.add_transaction(replacement, &utxo_set).unwrap();
+
+        assert!(!mempool.contains(&original_txid) && !mempool.contains(&child_txid));
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.spender(&outpoints[0]), Some(&replacement_txid));
+        assert!(!mempool.signals_replacement(&replacement_txid));
+    }
+
+    #[test]
+    fn test_replacement_fee_rules() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let mut mempool = Mempool::default();
+        let original = signal_replacement(create_spend(&outpoints, COINBASE_VALUE, 1_000, 2, &secret_key), &secret_key);
+        let original_txid = mempool.add_transaction(original, &utxo_set).unwrap();
+
+        let lower_fee_rate = create_spend(&outpoints, COINBASE_VALUE, 500, 2, &secret_key);
+        assert_eq!(mempool.add_transaction(lower_fee_rate, &utxo_set),
+            Err(MempoolError::ReplacementRejected(ReplacementError::LowerFeeRate(original_txid))));
+        // a smaller transaction can have a higher fee rate but still pay less
+        let lower_fee = create_spend(&outpoints, COINBASE_VALUE, 990, 1, &secret_key);
+        assert_eq!(mempool.add_transaction(lower_fee, &utxo_set),
+            Err(MempoolError::ReplacementRejected(ReplacementError::LowerAbsoluteFee { replaced_fees: 1_000, fee: 990 })));
+        let small_bump = create_spend(&outpoints, COINBASE_VALUE, 1_010, 2, &secret_key);
+        let required = INCREMENTAL_RELAY_FEE_RATE * small_bump.encode().len() as u128;
+        assert_eq!(mempool.add_transaction(small_bump, &utxo_set),
+            Err(MempoolError::ReplacementRejected(ReplacementError::InsufficientAdditionalFee { additional_fee: 10, required })));
+        assert!(mempool.contains(&original_txid));
+    }
+
+    #[test]
+    fn test_replacement_input_rules() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(2);
+        let mut mempool = Mempool::default();
+        let original = signal_replacement(create_spend(&outpoints[..1], COINBASE_VALUE, 1_000, 1, &secret_key), &secret_key);
+        let original_txid = mempool.add_transaction(original.clone(), &utxo_set).unwrap();
+        let other_txid = mempool.add_transaction(create_spend(&outpoints[1..], COINBASE_VALUE, 1_000, 1, &secret_key), &utxo_set).unwrap();
+
+        let original_output = OutPoint { txid: original_txid, index: 0 };
+        let input_value = (COINBASE_VALUE + original.outputs[0].value) / 2;
+        let spends_original = create_spend(&[outpoints[0], original_output], input_value, 10_000, 1, &secret_key);
+        assert_eq!(mempool.add_transaction(spends_original, &utxo_set),
+            Err(MempoolError::ReplacementRejected(ReplacementError::SpendsConflictingTransaction(original_txid))));
+
+        let other_output = OutPoint { txid: other_txid, index: 0 };
+        let new_unconfirmed = create_spend(&[outpoints[0], other_output], COINBASE_VALUE - 500, 10_000, 1, &secret_key);
+        assert_eq!(mempool.add_transaction(new_unconfirmed, &utxo_set),
+            Err(MempoolError::ReplacementRejected(ReplacementError::NewUnconfirmedInput(other_output))));
+    }
+
+    #[test]
+    fn test_replacement_eviction_limit() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(5);
+        let mut mempool = Mempool::default();
+        // 5 replaceable transactions with 20 children each
+        for outpoint in &outpoints {
+            let original = signal_replacement(create_spend(&[*outpoint], COINBASE_VALUE, 1_000, 20, &secret_key), &secret_key);
+            let original_txid = mempool.add_transaction(original.clone(), &utxo_set).unwrap();
+            for index in 0..20 {
+                let child = create_spend(&[OutPoint { txid: original_txid, index }], original.outputs[0].value, 1_000, 1, &secret_key);
+                mempool.add_transaction(child, &utxo_set).unwrap();
+            }
+        }
+        let replacement = create_spend(&outpoints, COINBASE_VALUE, 1_000_000, 1, &secret_key);
+        assert_eq!(mempool.add_transaction(replacement, &utxo_set),
+            Err(MempoolError::ReplacementRejected(ReplacementError::TooManyReplacements(105))));
+        assert_eq!(mempool.len(), 105);
+    }
+
+    #[test]
+    fn test_ancestors_and_descendants() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let mut mempool = Mempool::default();
+        let parent = create_spend(&outpoints, COINBASE_VALUE, 1_000, 2, &secret_key);
+        let parent_txid = mempool.add_transaction(parent.clone(), &utxo_set).unwrap();
+        let output_value = parent.outputs[0].value;
+        let child = create_spend(&[OutPoint { txid: parent_txid, index: 0 }], output_value, 1_000, 1, &secret_key);
+        let child_txid = mempool.add_transaction(child, &utxo_set).unwrap();
+        let grandchild = create_spend(&[OutPoint { txid: child_txid, index: 0 }], output_value - 1_000, 1_000, 1, &secret_key);
+        let grandchild_txid = mempool.add_transaction(grandchild, &utxo_set).unwrap();
+
+        assert_eq!(mempool.get(&child_txid).unwrap().parents(), &HashSet::from([parent_txid]));
+        assert_eq!(mempool.ancestors(&grandchild_txid), HashSet::from([parent_txid, child_txid]));
+        assert_eq!(mempool.descendants(&parent_txid), HashSet::from([child_txid, grandchild_txid]));
+        let order: Vec<sha256::Hash> = mempool.entries().iter().map(|entry| entry.txid).collect();
+        assert_eq!(order, vec![parent_txid, child_txid, grandchild_txid]);
+
+        mempool.remove_with_descendants(&child_txid);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&parent_txid).unwrap().children().is_empty());
+    }
+
+    #[test]
+    fn test_ancestor_limit() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let mut mempool = Mempool::default();
+        let mut spent = outpoints[0];
+        let mut value = COINBASE_VALUE;
+        for _ in 0..=MAX_MEMPOOL_ANCESTORS {
+            let transaction = create_spend(&[spent], value, 1_000, 1, &secret_key);
+            value -= 1_000;
+            spent = OutPoint { txid: mempool.add_transaction(transaction, &utxo_set).unwrap(), index: 0 };
+        }
+        let transaction = create_spend(&[spent], value, 1_000, 1, &secret_key);
+        assert_eq!(mempool.add_transaction(transaction, &utxo_set), Err(MempoolError::TooManyAncestors));
+    }
+
+    #[test]
+    fn test_eviction_by_fee_rate() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(3);
+        let transactions: Vec<Transaction> = outpoints.iter().zip([3_000, 1_000, 2_000])
+            .map(|(outpoint, fee)| create_spend(&[*outpoint], COINBASE_VALUE, fee, 1, &secret_key))
+            .collect();
+        let sizes: Vec<usize> = transactions.iter().map(|transaction| transaction.encode().len()).collect();
+        // room for the high fee transaction and one of the others
+        let max_size = sizes[0] + sizes[1].max(sizes[2]);
+        let mut mempool = Mempool::new(max_size);
+
+        let high = mempool.add_transaction(transactions[0].clone(), &utxo_set).unwrap();
+        let low = mempool.add_transaction(transactions[1].clone(), &utxo_set).unwrap();
+        let medium = mempool.add_transaction(transactions[2].clone(), &utxo_set).unwrap();
+        assert!(mempool.contains(&high) && mempool.contains(&medium) && !mempool.contains(&low));
+        assert_eq!(mempool.add_transaction(transactions[1].clone(), &utxo_set), Err(MempoolError::MempoolFull));
+        assert!(mempool.total_size() <= max_size);
+    }
+
+    #[test]
+    fn test_evicted_replacement_restores_originals() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(3);
+        let high = create_spend(&outpoints[..1], COINBASE_VALUE, 50_000, 1, &secret_key);
+        let original = signal_replacement(create_spend(&outpoints[1..2], COINBASE_VALUE, 1_000, 2, &secret_key), &secret_key);
+        let child = create_spend(&[OutPoint { txid: original.hash(), index: 1 }], original.outputs[1].value, 0, 1, &secret_key);
+        let replaced_size = original.encode().len() + child.encode().len();
+        let max_size = high.encode().len() + replaced_size;
+        let mut mempool = Mempool::new(max_size);
+        mempool.add_transaction(high, &utxo_set).unwrap();
+        let original_txid = mempool.add_transaction(original, &utxo_set).unwrap();
+        let child_txid = mempool.add_transaction(child, &utxo_set).unwrap();
+
+        // the larger replacement pays enough to replace both, but it doesn't fit next to the high fee transaction
+        let replacement = create_spend(&outpoints[1..], COINBASE_VALUE, 10_000, 5, &secret_key);
+        assert!(replacement.encode().len() > replaced_size);
+        assert_eq!(mempool.add_transaction(replacement, &utxo_set), Err(MempoolError::MempoolFull));
+
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.total_size(), max_size);
+        assert_eq!(mempool.spender(&outpoints[1]), Some(&original_txid));
+        assert_eq!(mempool.spender(&OutPoint { txid: original_txid, index: 1 }), Some(&child_txid));
+        assert_eq!(mempool.get(&original_txid).unwrap().children(), &HashSet::from([child_txid]));
+        assert_eq!(mempool.get(&child_txid).unwrap().parents(), &HashSet::from([original_txid]));
+    }
+
+    #[test]
+    fn test_remove_for_block() {
+        let (mut utxo_set, outpoints, secret_key) = create_funded_utxo_set(2);
+        let mut mempool = Mempool::default();
+        let confirmed = create_spend(&outpoints[..1], COINBASE_VALUE, 1_000, 1, &secret_key);
+        let confirmed_txid = mempool.add_transaction(confirmed.clone(), &utxo_set).unwrap();
+        let child = create_spend(&[OutPoint { txid: confirmed_txid, index: 0 }], COINBASE_VALUE - 1_000, 1_000, 1, &secret_key);
+        let child_txid = mempool.add_transaction(child, &utxo_set).unwrap();
+        let conflicting = create_spend(&outpoints[1..], COINBASE_VALUE, 1_000, 1, &secret_key);
+        let conflicting_txid = mempool.add_transaction(conflicting, &utxo_set).unwrap();
+
+        // the block confirms the first transaction and double spends the second output
+        let double_spend = create_spend(&outpoints[1..], COINBASE_VALUE, 5_000, 1, &secret_key);
+        let block = block_with(vec![confirmed, double_spend]);
+        let undo = utxo_set.apply_block(&block, 2).unwrap();
+        mempool.remove_for_block(&block);
+
+        assert!(!mempool.contains(&confirmed_txid) && !mempool.contains(&conflicting_txid));
+        assert!(mempool.get(&child_txid).unwrap().parents().is_empty());
+        assert_eq!(mempool.len(), 1);
+
+        // disconnecting the block puts its transactions back in front of the child
+        utxo_set.undo_block(&block, undo).unwrap();
+        mempool.add_disconnected_block(&block, &utxo_set);
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.get(&child_txid).unwrap().parents(), &HashSet::from([confirmed_txid]));
+    }
+}
@@ -0,0 +1,88 @@
+use crate::core::block::Block;
+use crate::utils::time::get_current_timestamp_ms;
+
+/// Number of nonces tried between two calls of the cancellation hook
+const CANCEL_CHECK_INTERVAL: u32 = 1 << 12;
+
+/// Searches for a nonce that makes the block hash satisfy the header's difficulty target.
+/// When the whole nonce space is exhausted, the timestamp is rolled forward and the search starts over.
+/// `should_stop` is polled periodically and returning `true` from it aborts mining
+/// (e.g. when a competing block arrives), in which case `None` is returned.
+pub fn mine_block<F: FnMut() -> bool>(mut block: Block, mut should_stop: F) -> Option<Block> {
+    loop {
+        for nonce in 0..=u32::MAX {
+            if nonce % CANCEL_CHECK_INTERVAL == 0 && should_stop() {
+                return None;
+            }
+            block.header.nonce = nonce;
+            if block.has_valid_proof_of_work() {
+                return Some(block);
+            }
+        }
+        roll_timestamp(&mut block);
+    }
+}
+
+/// Moves the block timestamp forward so that a new nonce space can be searched
+fn roll_timestamp(block: &mut Block) {
+    block.header.timestamp = get_current_timestamp_ms().max(block.header.timestamp + 1);
+    block.header.nonce = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{COIN, SOFTWARE_VERSION};
+    use crate::core::transaction::{calculate_merkle_root, p2pkh_script, Transaction};
+    use crate::utils::wallets::generate_keypair;
+
+    const COINBASE_VALUE: u128 = 50 * COIN;
+
+    fn create_unmined_block(difficulty_target: u32) -> Block {
+        let (_, pub_key) = generate_keypair();
+        let coinbase_transaction = Transaction::new_coinbase_transaction(p2pkh_script(&pub_key), pub_key, 0, COINBASE_VALUE);
+        let transactions = vec![coinbase_transaction.clone()];
+        let merkle_root = calculate_merkle_root(&transactions);
+        Block::new(
+            SOFTWARE_VERSION.to_string(),
+            None,
+            merkle_root,
+            get_current_timestamp_ms(),
+            difficulty_target,
+            0,
+            transactions,
+            coinbase_transaction,
+        )
+    }
+
+    #[test]
+    fn test_mine_block_finds_valid_nonce() {
+        let block = create_unmined_block(0x2000ffff);
+        let mined_block = mine_block(block, || false).unwrap();
+        assert!(mined_block.has_valid_proof_of_work());
+    }
+
+    #[test]
+    fn test_mine_block_can_be_cancelled() {
+        // a target of 1 is practically impossible to reach
+        let block = create_unmined_block(0x03000001);
+        let mut calls = 0;
+        let mined_block = mine_block(block, || {
+            calls += 1;
+            calls > 3
+        });
+        assert!(mined_block.is_none());
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn test_roll_timestamp() {
+        let mut block = create_unmined_block(0x2000ffff);
+        block.header.timestamp = get_current_timestamp_ms() + 60_000;
+        block.header.nonce = 42;
+        let old_timestamp = block.header.timestamp;
+        roll_timestamp(&mut block);
+        assert_eq!(block.header.timestamp, old_timestamp + 1);
+        assert_eq!(block.header.nonce, 0);
+    }
+}
@@ -1,3 +1,21 @@
 pub mod block;
 pub mod transaction;
-pub mod consensus;
\ No newline at end of file
+pub mod consensus;
+pub mod mining;
+pub mod pow;
+pub mod difficulty;
+pub mod serialize;
+pub mod utxo;
+pub mod validation;
+pub mod sighash;
+pub mod script;
+pub mod mempool;
+pub mod block_template;
+pub mod chain;
+pub mod orphans;
+pub mod params;
+pub mod block_store;
+pub mod chainstate;
+pub mod sync;
+#[cfg(test)]
+pub mod test_utils;
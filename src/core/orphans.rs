@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use secp256k1::hashes::sha256;
+
+use crate::constants::{MAX_ORPHAN_BLOCKS, ORPHAN_BLOCK_EXPIRY_MS};
+use crate::core::block::Block;
+
+/// Block received before its previous block
+#[derive(Debug, Clone)]
+pub struct OrphanBlock {
+    pub block: Block,
+    /// Time (ms) when the block was added to the pool
+    pub received_at: u128,
+}
+
+/// Blocks whose previous block is unknown, kept until their parents arrive.
+/// The pool holds at most `max_orphans` blocks, each for at most `expiry_ms`
+#[derive(Debug, Clone)]
+pub struct OrphanPool {
+    orphans: HashMap<sha256::Hash, OrphanBlock>,
+    /// Orphans indexed by their previous block hash
+    children: HashMap<sha256::Hash, Vec<sha256::Hash>>,
+    max_orphans: usize,
+    expiry_ms: u128,
+}
+
+impl Default for OrphanPool {
+    fn default() -> OrphanPool {
+        OrphanPool::new(MAX_ORPHAN_BLOCKS, ORPHAN_BLOCK_EXPIRY_MS)
+    }
+}
+
+impl OrphanPool {
+    pub fn new(max_orphans: usize, expiry_ms: u128) -> OrphanPool {
+        OrphanPool { orphans: HashMap::new(), children: HashMap::new(), max_orphans, expiry_ms }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &sha256::Hash) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, hash: &sha256::Hash) -> Option<&OrphanBlock> {
+        self.orphans.get(hash)
+    }
+
+    /// Adds a block whose previous block is unknown, received at `now` (ms).
+    /// Expired orphans are dropped first, then the oldest ones while the pool is full.
+    /// Returns false if the block is already in the pool or has no previous block
+    pub fn add_orphan(&mut self, block: Block, now: u128) -> bool {
+        let hash = block.hash_block();
+        let Some(previous_block_hash) = block.header.previous_block_hash else {
+            return false;
+        };
+        if self.contains(&hash) || self.max_orphans == 0 {
+            return false;
+        }
+        self.remove_expired(now);
+        while self.orphans.len() >= self.max_orphans {
+            let oldest = self.orphans.iter()
+                .min_by_key(|(hash, orphan)| (orphan.received_at, **hash))
+                .map(|(hash, _)| *hash)
+                .expect("pool is full");
+            self.remove(&oldest);
+        }
+        self.children.entry(previous_block_hash).or_default().push(hash);
+        self.orphans.insert(hash, OrphanBlock { block, received_at: now });
+        true
+    }
+
+    /// Drops the orphans received more than `expiry_ms` before `now`, returns how many were dropped
+    pub fn remove_expired(&mut self, now: u128) -> usize {
+        let expired: Vec<sha256::Hash> = self.orphans.iter()
+            .filter(|(_, orphan)| now.saturating_sub(orphan.received_at) > self.expiry_ms)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in &expired {
+            self.remove(hash);
+        }
+        expired.len()
+    }
+
+    /// Removes and returns the orphans whose previous block is `parent`
+    pub fn take_children(&mut self, parent: &sha256::Hash) -> Vec<Block> {
+        self.children.remove(parent).unwrap_or_default().iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .map(|orphan| orphan.block)
+            .collect()
+    }
+
+    /// Hash of the block missing for the orphan `hash` to connect:
+    /// the previous block of its oldest ancestor in the pool
+    pub fn missing_ancestor(&self, hash: &sha256::Hash) -> Option<sha256::Hash> {
+        let mut missing = self.orphans.get(hash)?.block.header.previous_block_hash?;
+        while let Some(orphan) = self.orphans.get(&missing) {
+            missing = orphan.block.header.previous_block_hash?;
+        }
+        Some(missing)
+    }
+
+    fn remove(&mut self, hash: &sha256::Hash) -> Option<OrphanBlock> {
+        let orphan = self.orphans.remove(hash)?;
+        if let Some(previous_block_hash) = orphan.block.header.previous_block_hash {
+            if let Some(siblings) = self.children.get_mut(&previous_block_hash) {
+                siblings.retain(|sibling| sibling != hash);
+                if siblings.is_empty() {
+                    self.children.remove(&previous_block_hash);
+                }
+            }
+        }
+        Some(orphan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::params::ChainParams;
+    use crate::core::test_utils::mine_blocks;
+
+    /// Mines `count` blocks on top of each other, starting with a genesis block
+    #[test]
+    fn test_add_and_take_children() {
+        let blocks = mine_blocks(&ChainParams::regtest(), &[], 4);
+        let mut pool = OrphanPool::default();
+        assert!(!pool.add_orphan(blocks[0].clone(), 0));
+        assert!(pool.add_orphan(blocks[2].clone(), 0));
+        assert!(pool.add_orphan(blocks[3].clone(), 0));
+        assert!(!pool.add_orphan(blocks[3].clone(), 0));
+        assert_eq!(pool.len(), 2);
+
+        assert_eq!(pool.missing_ancestor(&blocks[3].hash_block()), Some(blocks[1].hash_block()));
+        assert_eq!(pool.missing_ancestor(&blocks[1].hash_block()), None);
+
+        assert!(pool.take_children(&blocks[0].hash_block()).is_empty());
+        assert_eq!(pool.take_children(&blocks[1].hash_block()), vec![blocks[2].clone()]);
+        assert_eq!(pool.take_children(&blocks[2].hash_block()), vec![blocks[3].clone()]);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let blocks = mine_blocks(&ChainParams::regtest(), &[], 5);
+        let mut pool = OrphanPool::new(2, 1_000);
+        pool.add_orphan(blocks[1].clone(), 0);
+        pool.add_orphan(blocks[2].clone(), 100);
+        // the oldest orphan is evicted when the pool is full
+        pool.add_orphan(blocks[3].clone(), 200);
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&blocks[1].hash_block()));
+        assert!(pool.take_children(&blocks[0].hash_block()).is_empty());
+
+        assert_eq!(pool.remove_expired(1_100), 0);
+        assert_eq!(pool.remove_expired(1_150), 1);
+        assert!(pool.contains(&blocks[3].hash_block()));
+        // expired orphans make room before anything is evicted
+        pool.add_orphan(blocks[4].clone(), 1_150);
+        assert_eq!(pool.len(), 2);
+        pool.add_orphan(blocks[1].clone(), 2_300);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.get(&blocks[1].hash_block()).unwrap().received_at, 2_300);
+    }
+}
@@ -0,0 +1,405 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use secp256k1::hashes::sha256;
+use secp256k1::PublicKey;
+
+use crate::constants::COIN;
+use crate::core::block::Block;
+use crate::core::difficulty::RetargetMode;
+use crate::core::pow::compact_to_target;
+use crate::core::script::Script;
+use crate::core::transaction::{calculate_merkle_root, Transaction};
+
+/// Version string of every genesis block, it's part of the hash so it can't follow `SOFTWARE_VERSION`
+const GENESIS_SOFTWARE_VERSION: &str = "0.1.0";
+
+/// Key paid by every genesis coinbase (the key of Bitcoin's genesis block, nobody can spend it)
+const GENESIS_PUB_KEY: &str = "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+
+/// Network a node runs on, each one has its own parameters and genesis block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Network {
+    Main,
+    Testnet,
+    /// Local testing network, blocks are cheap to mine
+    Regtest,
+}
+
+impl FromStr for Network {
+    type Err = ParamsError;
+
+    fn from_str(name: &str) -> Result<Network, ParamsError> {
+        match name {
+            "main" => Ok(Network::Main),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(ParamsError::InvalidValue { key: "network".to_string(), value: name.to_string() }),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::Main => write!(f, "main"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+/// Header fields of a genesis block, the coinbase is the same on every network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenesisParams {
+    /// Time (ms) of the genesis block
+    pub timestamp: u128,
+    pub difficulty_target: u32,
+    pub nonce: u32,
+    /// Expected hash of the genesis block
+    pub hash: sha256::Hash,
+}
+
+/// Consensus parameters of a network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub network: Network,
+    pub genesis: GenesisParams,
+    /// Block subsidy before the first halving
+    pub initial_subsidy: u128,
+    /// Blocks between two halvings of the block subsidy
+    pub halving_interval: u64,
+    /// Expected time between two blocks (ms)
+    pub target_spacing_ms: u64,
+    /// Easiest allowed difficulty target (compact)
+    pub pow_limit_bits: u32,
+    pub retarget_mode: RetargetMode,
+    /// Blocks between two adjustments in Window mode
+    pub retarget_interval: usize,
+    /// Number of solve times averaged in Lwma mode
+    pub lwma_window: usize,
+    /// Version byte of P2PKH addresses
+    pub pubkey_address_prefix: u8,
+    /// Version byte of P2SH addresses
+    pub script_address_prefix: u8,
+    /// Bytes starting every network message
+    pub magic: [u8; 4],
+    /// TCP port nodes listen on by default
+    pub default_port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamsError {
+    /// The params file can't be read
+    Io(String),
+    /// The line isn't a `key = value` pair
+    InvalidLine(usize),
+    UnknownKey(String),
+    InvalidValue { key: String, value: String },
+    /// The genesis block built from the params doesn't have the expected hash
+    GenesisMismatch { expected: sha256::Hash, found: sha256::Hash },
+    /// The genesis block hash doesn't satisfy its difficulty target
+    InvalidGenesisProofOfWork,
+}
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamsError::Io(error) => write!(f, "can't read the params: {}", error),
+            ParamsError::InvalidLine(line) => write!(f, "line {} isn't a key = value pair", line),
+            ParamsError::UnknownKey(key) => write!(f, "unknown key {}", key),
+            ParamsError::InvalidValue { key, value } => write!(f, "invalid value {} for {}", value, key),
+            ParamsError::GenesisMismatch { expected, found } => write!(f, "genesis block hash is {}, expected {}", found, expected),
+            ParamsError::InvalidGenesisProofOfWork => write!(f, "genesis block hash doesn't satisfy its difficulty target"),
+        }
+    }
+}
+
+impl ChainParams {
+    pub fn main() -> ChainParams {
+        ChainParams {
+            network: Network::Main,
+            // 3 Jan 2009, same as Bitcoin
+            genesis: GenesisParams {
+                timestamp: 1_231_006_505_000,
+                difficulty_target: 0x1f00ffff, // ~16 leading zero bits
+                nonce: 166_497,
+                hash: parse_hash("00007fd3cbbe6a1fff81a71c5db46b95ca13d24ab8100639b700ecfcaa573c0c"),
+            },
+            initial_subsidy: 50 * COIN,
+            halving_interval: 210_000,
+            target_spacing_ms: 5000, // 5 seconds
+            pow_limit_bits: 0x2000ffff, // ~8 leading zero bits
+            retarget_mode: RetargetMode::Window,
+            retarget_interval: 10,
+            lwma_window: 10,
+            pubkey_address_prefix: 0x00,
+            script_address_prefix: 0x05,
+            magic: [0xf9, 0xbe, 0xb4, 0xd9],
+            default_port: 8333,
+        }
+    }
+
+    pub fn testnet() -> ChainParams {
+        ChainParams {
+            network: Network::Testnet,
+            // 2 Feb 2011, same as Bitcoin's testnet3
+            genesis: GenesisParams {
+                timestamp: 1_296_688_602_000,
+                difficulty_target: 0x1f00ffff,
+                nonce: 73_669,
+                hash: parse_hash("000065430ecca87683dce1c43c07f5f5f1cc346d8e7253a8309e58b5f6f52b16"),
+            },
+            pubkey_address_prefix: 0x6f,
+            script_address_prefix: 0xc4,
+            magic: [0x0b, 0x11, 0x09, 0x07],
+            default_port: 18333,
+            ..ChainParams::main()
+        }
+    }
+
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            network: Network::Regtest,
+            // 1 Jan 2024
+            genesis: GenesisParams {
+                timestamp: 1_704_067_200_000,
+                difficulty_target: 0x2000ffff,
+                nonce: 119,
+                hash: parse_hash("00646ef33316a97af420fecf226e6eb8511168c52da318099b01d1ceafb49744"),
+            },
+            halving_interval: 150,
+            pubkey_address_prefix: 0x6f,
+            script_address_prefix: 0xc4,
+            magic: [0xfa, 0xbf, 0xb5, 0xda],
+            default_port: 18444,
+            ..ChainParams::main()
+        }
+    }
+
+    pub fn for_network(network: Network) -> ChainParams {
+        match network {
+            Network::Main => ChainParams::main(),
+            Network::Testnet => ChainParams::testnet(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    /// Loads custom params from `key = value` lines (`#` starts a comment).
+    /// The params of the `network` key (main by default) are used for the keys that aren't set,
+    /// the genesis block built from the result has to match `genesis_hash` and its difficulty target
+    /// can't be easier than `pow_limit_bits`
+    pub fn from_config(config: &str) -> Result<ChainParams, ParamsError> {
+        let mut entries = vec![];
+        for (index, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(ParamsError::InvalidLine(index + 1))?;
+            entries.push((key.trim(), value.trim()));
+        }
+
+        let network = match entries.iter().find(|(key, _)| *key == "network") {
+            Some((_, value)) => value.parse()?,
+            None => Network::Main,
+        };
+        let mut params = ChainParams::for_network(network);
+        for (key, value) in entries {
+            let invalid_value = || ParamsError::InvalidValue { key: key.to_string(), value: value.to_string() };
+            match key {
+                "network" => {}
+                "genesis_timestamp" => params.genesis.timestamp = value.parse().map_err(|_| invalid_value())?,
+                "genesis_difficulty_target" => params.genesis.difficulty_target = parse_bits(value).ok_or_else(invalid_value)?,
+                "genesis_nonce" => params.genesis.nonce = value.parse().map_err(|_| invalid_value())?,
+                "genesis_hash" => params.genesis.hash = sha256::Hash::from_str(value).map_err(|_| invalid_value())?,
+                "initial_subsidy" => params.initial_subsidy = value.parse().map_err(|_| invalid_value())?,
+                "halving_interval" => params.halving_interval = value.parse().ok().filter(|interval| *interval > 0).ok_or_else(invalid_value)?,
+                "target_spacing_ms" => params.target_spacing_ms = value.parse().ok().filter(|spacing| *spacing > 0).ok_or_else(invalid_value)?,
+                "pow_limit_bits" => params.pow_limit_bits = parse_bits(value).ok_or_else(invalid_value)?,
+                "retarget_mode" => {
+                    params.retarget_mode = match value {
+                        "window" => RetargetMode::Window,
+                        "lwma" => RetargetMode::Lwma,
+                        _ => return Err(invalid_value()),
+                    }
+                }
+                // the window retarget needs at least one solve time
+                "retarget_interval" => params.retarget_interval = value.parse().ok().filter(|interval| *interval > 1).ok_or_else(invalid_value)?,
+                "lwma_window" => params.lwma_window = value.parse().ok().filter(|window| *window > 0).ok_or_else(invalid_value)?,
+                "pubkey_address_prefix" => params.pubkey_address_prefix = parse_u32(value).and_then(|prefix| prefix.try_into().ok()).ok_or_else(invalid_value)?,
+                "script_address_prefix" => params.script_address_prefix = parse_u32(value).and_then(|prefix| prefix.try_into().ok()).ok_or_else(invalid_value)?,
+                "magic" => params.magic = parse_u32(value).map(u32::to_be_bytes).ok_or_else(invalid_value)?,
+                "default_port" => params.default_port = value.parse().map_err(|_| invalid_value())?,
+                _ => return Err(ParamsError::UnknownKey(key.to_string())),
+            }
+        }
+
+        // the genesis block can't be easier to mine than any later block
+        let genesis_target = compact_to_target(params.genesis.difficulty_target);
+        if genesis_target.is_none_or(|target| Some(target) > compact_to_target(params.pow_limit_bits)) {
+            let value = format!("{:#x}", params.genesis.difficulty_target);
+            return Err(ParamsError::InvalidValue { key: "genesis_difficulty_target".to_string(), value });
+        }
+
+        let genesis_block = params.genesis_block();
+        let found = genesis_block.hash_block();
+        if found != params.genesis.hash {
+            return Err(ParamsError::GenesisMismatch { expected: params.genesis.hash, found });
+        }
+        if !genesis_block.has_valid_proof_of_work() {
+            return Err(ParamsError::InvalidGenesisProofOfWork);
+        }
+        Ok(params)
+    }
+
+    /// Loads custom params from a file (see `from_config`)
+    pub fn from_file(path: &Path) -> Result<ChainParams, ParamsError> {
+        let config = std::fs::read_to_string(path).map_err(|error| ParamsError::Io(error.to_string()))?;
+        ChainParams::from_config(&config)
+    }
+
+    /// Builds the genesis block of the network, every node gets the same block
+    pub fn genesis_block(&self) -> Block {
+        let pub_key = PublicKey::from_str(GENESIS_PUB_KEY).expect("valid genesis key");
+        let coinbase_transaction = Transaction::new_coinbase_transaction(Script::new_p2pk(&pub_key), pub_key, 0, self.initial_subsidy);
+        let transactions = vec![coinbase_transaction.clone()];
+        Block::new(
+            GENESIS_SOFTWARE_VERSION.to_string(),
+            None,
+            calculate_merkle_root(&transactions),
+            self.genesis.timestamp,
+            self.genesis.difficulty_target,
+            self.genesis.nonce,
+            transactions,
+            coinbase_transaction
+        )
+    }
+
+    pub fn genesis_hash(&self) -> sha256::Hash {
+        self.genesis.hash
+    }
+}
+
+fn parse_hash(hex: &str) -> sha256::Hash {
+    sha256::Hash::from_str(hex).expect("valid hash")
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number
+fn parse_u32(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parses a compact difficulty target, it has to decode to a valid target
+fn parse_bits(value: &str) -> Option<u32> {
+    parse_u32(value).filter(|bits| compact_to_target(*bits).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::consensus::Node;
+    use crate::core::mining::mine_block;
+    use crate::core::utxo::UtxoSet;
+
+    #[test]
+    fn test_network_genesis_blocks() {
+        let networks = [Network::Main, Network::Testnet, Network::Regtest];
+        for network in networks {
+            let params = ChainParams::for_network(network);
+            let genesis_block = params.genesis_block();
+            assert_eq!(params.network, network);
+            assert_eq!(genesis_block.hash_block(), params.genesis_hash());
+            assert_eq!(genesis_block.header.previous_block_hash, None);
+            assert_eq!(Node::validate_block(&genesis_block, &[], &UtxoSet::new(), &params), Ok(()));
+        }
+        // each network has its own genesis block and magic bytes
+        let params: Vec<ChainParams> = networks.into_iter().map(ChainParams::for_network).collect();
+        assert_ne!(params[0].genesis_hash(), params[1].genesis_hash());
+        assert_ne!(params[1].genesis_hash(), params[2].genesis_hash());
+        assert_ne!(params[0].magic, params[1].magic);
+        assert_ne!(params[1].magic, params[2].magic);
+    }
+
+    #[test]
+    fn test_network_from_str() {
+        assert_eq!("main".parse(), Ok(Network::Main));
+        assert_eq!("testnet".parse(), Ok(Network::Testnet));
+        assert_eq!("regtest".parse(), Ok(Network::Regtest));
+        assert!("signet".parse::<Network>().is_err());
+        assert_eq!(Network::Testnet.to_string(), "testnet");
+    }
+
+    #[test]
+    fn test_params_from_config() {
+        let config = "
+            # regtest with faster blocks
+            network = regtest
+            target_spacing_ms = 1000
+            retarget_mode = lwma
+            halving_interval = 20   # halve often
+            magic = 0x01020304
+            default_port = 28444
+        ";
+        let params = ChainParams::from_config(config).unwrap();
+        assert_eq!(params.network, Network::Regtest);
+        assert_eq!(params.genesis, ChainParams::regtest().genesis);
+        assert_eq!(params.target_spacing_ms, 1000);
+        assert_eq!(params.retarget_mode, RetargetMode::Lwma);
+        assert_eq!(params.halving_interval, 20);
+        assert_eq!(params.magic, [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(params.default_port, 28444);
+
+        // the main network is used by default
+        assert_eq!(ChainParams::from_config(""), Ok(ChainParams::main()));
+    }
+
+    #[test]
+    fn test_invalid_params_config() {
+        assert_eq!(ChainParams::from_config("network = regtest\nspacing"), Err(ParamsError::InvalidLine(2)));
+        assert_eq!(ChainParams::from_config("block_time = 5"), Err(ParamsError::UnknownKey("block_time".to_string())));
+        let error = ParamsError::InvalidValue { key: "retarget_interval".to_string(), value: "1".to_string() };
+        assert_eq!(ChainParams::from_config("retarget_interval = 1"), Err(error));
+        let error = ParamsError::InvalidValue { key: "pubkey_address_prefix".to_string(), value: "256".to_string() };
+        assert_eq!(ChainParams::from_config("pubkey_address_prefix = 256"), Err(error));
+    }
+
+    #[test]
+    fn test_invalid_difficulty_config() {
+        // zero, negative and overflowing compact targets
+        for bits in ["0", "0x04923456", "0xff123456"] {
+            let error = ParamsError::InvalidValue { key: "pow_limit_bits".to_string(), value: bits.to_string() };
+            assert_eq!(ChainParams::from_config(&format!("pow_limit_bits = {}", bits)), Err(error));
+            let error = ParamsError::InvalidValue { key: "genesis_difficulty_target".to_string(), value: bits.to_string() };
+            assert_eq!(ChainParams::from_config(&format!("genesis_difficulty_target = {}", bits)), Err(error));
+        }
+        // the regtest genesis block is easier than this limit
+        let error = ParamsError::InvalidValue { key: "genesis_difficulty_target".to_string(), value: "0x2000ffff".to_string() };
+        assert_eq!(ChainParams::from_config("network = regtest\npow_limit_bits = 0x1f00ffff"), Err(error));
+    }
+
+    #[test]
+    fn test_params_config_genesis_mismatch() {
+        // changing a genesis field changes the genesis block hash
+        let found = ChainParams { initial_subsidy: 25 * COIN, ..ChainParams::regtest() }.genesis_block().hash_block();
+        let error = ParamsError::GenesisMismatch { expected: ChainParams::regtest().genesis_hash(), found };
+        assert_eq!(ChainParams::from_config("network = regtest\ninitial_subsidy = 25000000000"), Err(error));
+
+        // a custom genesis block has to be mined
+        let mut genesis_block = ChainParams { initial_subsidy: 25 * COIN, ..ChainParams::regtest() }.genesis_block();
+        while genesis_block.has_valid_proof_of_work() {
+            genesis_block.header.nonce += 1;
+        }
+        let config = format!("network = regtest\ninitial_subsidy = 25000000000\ngenesis_nonce = {}\ngenesis_hash = {}", genesis_block.header.nonce, genesis_block.hash_block());
+        assert_eq!(ChainParams::from_config(&config), Err(ParamsError::InvalidGenesisProofOfWork));
+
+        let genesis_block = mine_block(genesis_block, || false).unwrap();
+        let config = format!("network = regtest\ninitial_subsidy = 25000000000\ngenesis_nonce = {}\ngenesis_hash = {}", genesis_block.header.nonce, genesis_block.hash_block());
+        let params = ChainParams::from_config(&config).unwrap();
+        assert_eq!(params.genesis_block(), genesis_block);
+    }
+}
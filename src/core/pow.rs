@@ -0,0 +1,194 @@
+use secp256k1::hashes::{sha256, Hash};
+
+use crate::core::block::{Block, BlockHeader};
+use crate::core::difficulty::next_difficulty_target;
+use crate::core::params::ChainParams;
+use crate::core::validation::ValidationError;
+use crate::utils::u256::U256;
+
+/// Returns the difficulty target (compact "bits") the chain expects
+/// for a block built on top of `previous_headers`
+pub fn expected_difficulty_target(previous_headers: &[BlockHeader], params: &ChainParams) -> u32 {
+    next_difficulty_target(previous_headers, params)
+}
+
+/// Checks the proof of work of a block built on top of `previous_headers`:
+/// the declared difficulty target has to match the one expected by the chain
+/// (so a block can't lowball its own difficulty) and the block hash has to satisfy it
+pub fn check_proof_of_work(block: &Block, previous_headers: &[BlockHeader], params: &ChainParams) -> Result<(), ValidationError> {
+    check_header_proof_of_work(&block.header, previous_headers, params)
+}
+
+/// Same check as `check_proof_of_work` for a header received without its block
+pub fn check_header_proof_of_work(header: &BlockHeader, previous_headers: &[BlockHeader], params: &ChainParams) -> Result<(), ValidationError> {
+    let expected = expected_difficulty_target(previous_headers, params);
+    if header.difficulty_target != expected {
+        return Err(ValidationError::BadDifficultyTarget { expected, found: header.difficulty_target });
+    }
+    if !header.has_valid_proof_of_work() {
+        return Err(ValidationError::BadProofOfWork);
+    }
+    Ok(())
+}
+
+/// Decodes a compact "bits" value into a 256-bit target, the same way Bitcoin's `nBits` is decoded:
+/// the highest byte is the size of the target in bytes and the lower 3 bytes are its most significant bytes.
+/// Returns None for negative, zero or overflowing targets
+pub fn compact_to_target(bits: u32) -> Option<U256> {
+    let size = bits >> 24;
+    let mut word = bits & 0x007fffff;
+    let target = if size <= 3 {
+        word >>= 8 * (3 - size);
+        U256::from_u64(word as u64)
+    } else {
+        if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+            return None;
+        }
+        U256::from_u64(word as u64) << (8 * (size - 3))
+    };
+    let is_negative = word != 0 && (bits & 0x00800000) != 0;
+    if is_negative || target.is_zero() {
+        return None;
+    }
+    Some(target)
+}
+
+/// Encodes a 256-bit target into the compact "bits" representation (loses precision below the top 3 bytes)
+pub fn target_to_compact(target: &U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut compact = if size <= 3 {
+        (target.low_u64() << (8 * (3 - size))) as u32
+    } else {
+        (*target >> (8 * (size - 3))).low_u64() as u32
+    };
+    // the sign bit is set, move the mantissa one byte to the right
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size << 24)
+}
+
+/// Checks if a hash is lower than or equal to the target
+/// (the hash is read as a big-endian number, in the order of its hex representation)
+pub fn hash_meets_target(hash: &sha256::Hash, target: &U256) -> bool {
+    U256::from_be_bytes(hash.to_byte_array()) <= *target
+}
+
+/// Expected number of hashes needed to mine a block with the given difficulty target: 2^256 / (target + 1)
+pub fn block_work(bits: u32) -> U256 {
+    match compact_to_target(bits) {
+        // 2^256 doesn't fit into 256 bits, but 2^256 / (target + 1) == (!target / (target + 1)) + 1
+        Some(target) => (!target).div(&(target + U256::ONE)) + U256::ONE,
+        None => U256::ZERO,
+    }
+}
+
+/// Total work of a chain of headers, used to pick the chain with the most work instead of the longest one
+#[cfg(test)]
+pub fn chain_work(headers: &[BlockHeader]) -> U256 {
+    headers.iter().fold(U256::ZERO, |work, header| work + block_work(header.difficulty_target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_utils::mine_genesis_block;
+    use crate::core::mining::mine_block;
+    use crate::utils::wallets::generate_keypair;
+
+    #[test]
+    fn test_check_proof_of_work() {
+        let (_, pub_key) = generate_keypair();
+        let params = ChainParams::main();
+        let genesis_block = mine_genesis_block(pub_key, &params);
+        assert_eq!(check_proof_of_work(&genesis_block, &[], &params), Ok(()));
+    }
+
+    #[test]
+    fn test_check_proof_of_work_lowballed_target() {
+        let (_, pub_key) = generate_keypair();
+        let params = ChainParams::main();
+        let mut block = mine_genesis_block(pub_key, &params);
+        block.header.difficulty_target = params.pow_limit_bits;
+        let block = mine_block(block, || false).unwrap();
+        // the hash satisfies the declared target, but it's not the one the chain expects
+        assert!(block.has_valid_proof_of_work());
+        let error = ValidationError::BadDifficultyTarget { expected: params.genesis.difficulty_target, found: params.pow_limit_bits };
+        assert_eq!(check_proof_of_work(&block, &[], &params), Err(error));
+    }
+
+    #[test]
+    fn test_check_proof_of_work_invalid_hash() {
+        let (_, pub_key) = generate_keypair();
+        let params = ChainParams::main();
+        let mut block = mine_genesis_block(pub_key, &params);
+        // search for a nonce that doesn't satisfy the target
+        while block.has_valid_proof_of_work() {
+            block.header.nonce += 1;
+        }
+        assert_eq!(check_proof_of_work(&block, &[], &params), Err(ValidationError::BadProofOfWork));
+    }
+
+    #[test]
+    fn test_compact_to_target() {
+        // Bitcoin's genesis block difficulty
+        let target = compact_to_target(0x1d00ffff).unwrap();
+        assert_eq!(target, U256::from_u64(0xffff) << 208);
+        let target = compact_to_target(0x1b0404cb).unwrap();
+        assert_eq!(target, U256::from_u64(0x0404cb) << 192);
+        assert_eq!(compact_to_target(0x03123456).unwrap(), U256::from_u64(0x123456));
+        assert_eq!(compact_to_target(0x02123456).unwrap(), U256::from_u64(0x1234));
+    }
+
+    #[test]
+    fn test_compact_to_target_invalid() {
+        // zero
+        assert!(compact_to_target(0x00000000).is_none());
+        assert!(compact_to_target(0x01003456).is_none());
+        // negative
+        assert!(compact_to_target(0x04923456).is_none());
+        // overflow
+        assert!(compact_to_target(0xff123456).is_none());
+        assert!(compact_to_target(0x2201ffff).is_none());
+    }
+
+    #[test]
+    fn test_target_to_compact() {
+        for bits in [0x1d00ffff, 0x1b0404cb, 0x1f00ffff, 0x2000ffff, 0x03123456, 0x05009234] {
+            let target = compact_to_target(bits).unwrap();
+            assert_eq!(target_to_compact(&target), bits);
+        }
+        // the sign bit of the mantissa is avoided
+        assert_eq!(target_to_compact(&U256::from_u64(0x80)), 0x02008000);
+    }
+
+    #[test]
+    fn test_hash_meets_target() {
+        let mut bytes = [0u8; 32];
+        bytes[2] = 0x01;
+        let hash = sha256::Hash::from_byte_array(bytes);
+        assert!(hash_meets_target(&hash, &compact_to_target(0x1f00ffff).unwrap()));
+        assert!(!hash_meets_target(&hash, &compact_to_target(0x1d00ffff).unwrap()));
+    }
+
+    #[test]
+    fn test_block_work() {
+        // work of Bitcoin's genesis block
+        assert_eq!(block_work(0x1d00ffff), U256::from_u64(0x100010001));
+        assert!(block_work(0x1d00ffff) > block_work(0x1f00ffff));
+        assert_eq!(block_work(0), U256::ZERO);
+    }
+
+    #[test]
+    fn test_chain_work() {
+        let (_, pub_key) = generate_keypair();
+        let genesis_block = mine_genesis_block(pub_key, &ChainParams::main());
+        let mut header = genesis_block.header.clone();
+        let work = chain_work(&[header.clone(), header.clone()]);
+        assert_eq!(work, block_work(header.difficulty_target) + block_work(header.difficulty_target));
+        // a single harder block can outweigh a longer chain
+        header.difficulty_target = 0x1d00ffff;
+        assert!(chain_work(&[header]) > work);
+    }
+}
@@ -0,0 +1,915 @@
+use std::fmt;
+
+use secp256k1::ecdsa::Signature;
+use secp256k1::hashes::{hash160, Hash};
+use secp256k1::PublicKey;
+
+use crate::core::serialize::{write_var_bytes, DecodeError, Decodable, Encodable, Reader};
+use crate::core::sighash::{signature_hash, SigHashType};
+use crate::core::transaction::Transaction;
+use crate::utils::hash::{hash160, hash256, sha256_bytes};
+use crate::utils::wallets::verify_hash;
+
+// Push value
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+
+// Control
+pub const OP_NOP: u8 = 0x61;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_VERIF: u8 = 0x65;
+pub const OP_VERNOTIF: u8 = 0x66;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+
+// Stack
+pub const OP_TOALTSTACK: u8 = 0x6b;
+pub const OP_FROMALTSTACK: u8 = 0x6c;
+pub const OP_2DROP: u8 = 0x6d;
+pub const OP_2DUP: u8 = 0x6e;
+pub const OP_DEPTH: u8 = 0x74;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_NIP: u8 = 0x77;
+pub const OP_OVER: u8 = 0x78;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_SIZE: u8 = 0x82;
+
+// Bitwise logic
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+
+// Arithmetic
+pub const OP_1ADD: u8 = 0x8b;
+pub const OP_1SUB: u8 = 0x8c;
+pub const OP_NOT: u8 = 0x91;
+pub const OP_ADD: u8 = 0x93;
+pub const OP_SUB: u8 = 0x94;
+pub const OP_NUMEQUAL: u8 = 0x9c;
+pub const OP_NUMEQUALVERIFY: u8 = 0x9d;
+pub const OP_LESSTHAN: u8 = 0x9f;
+pub const OP_GREATERTHAN: u8 = 0xa0;
+
+// Crypto
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_HASH256: u8 = 0xaa;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+
+/// Opcodes disabled in Bitcoin (OP_CAT, OP_MUL, ...), a script containing them always fails
+const DISABLED_OPCODES: [u8; 17] = [
+    0x7e, 0x7f, 0x80, 0x81, 0x83, 0x84, 0x85, 0x86, 0x8d, 0x8e, 0x95, 0x96, 0x97, 0x98, 0x99,
+    OP_VERIF, OP_VERNOTIF,
+];
+
+/// Execution limits, the same as in Bitcoin
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+pub const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+/// Numbers used by arithmetic opcodes are at most 4 bytes long
+const MAX_NUM_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptError {
+    /// A push opcode needs more bytes than the script has left
+    TruncatedPush,
+    ScriptSize,
+    PushSize,
+    OpCount,
+    StackSize,
+    PubKeyCount,
+    SigCount,
+    /// An opcode needs more stack elements than there are
+    InvalidStackOperation,
+    UnbalancedConditional,
+    BadOpcode(u8),
+    DisabledOpcode(u8),
+    OpReturn,
+    Verify,
+    EqualVerify,
+    NumEqualVerify,
+    CheckSigVerify,
+    CheckMultiSigVerify,
+    /// An arithmetic operand is longer than 4 bytes
+    NumOverflow,
+    /// The script finished with an empty stack or a false value on top
+    EvalFalse,
+    /// Pay to script hash spends can only push data in their script_sig
+    SigPushOnly,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::TruncatedPush => write!(f, "push past the end of the script"),
+            ScriptError::ScriptSize => write!(f, "script is larger than {} bytes", MAX_SCRIPT_SIZE),
+            ScriptError::PushSize => write!(f, "pushed element is larger than {} bytes", MAX_SCRIPT_ELEMENT_SIZE),
+            ScriptError::OpCount => write!(f, "script has more than {} operations", MAX_OPS_PER_SCRIPT),
+            ScriptError::StackSize => write!(f, "stack has more than {} elements", MAX_STACK_SIZE),
+            ScriptError::PubKeyCount => write!(f, "invalid multisig public key count"),
+            ScriptError::SigCount => write!(f, "invalid multisig signature count"),
+            ScriptError::InvalidStackOperation => write!(f, "not enough elements on the stack"),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced conditional"),
+            ScriptError::BadOpcode(opcode) => write!(f, "unknown opcode 0x{:02x}", opcode),
+            ScriptError::DisabledOpcode(opcode) => write!(f, "disabled opcode 0x{:02x}", opcode),
+            ScriptError::OpReturn => write!(f, "OP_RETURN was executed"),
+            ScriptError::Verify => write!(f, "OP_VERIFY failed"),
+            ScriptError::EqualVerify => write!(f, "OP_EQUALVERIFY failed"),
+            ScriptError::NumEqualVerify => write!(f, "OP_NUMEQUALVERIFY failed"),
+            ScriptError::CheckSigVerify => write!(f, "OP_CHECKSIGVERIFY failed"),
+            ScriptError::CheckMultiSigVerify => write!(f, "OP_CHECKMULTISIGVERIFY failed"),
+            ScriptError::NumOverflow => write!(f, "number is longer than {} bytes", MAX_NUM_SIZE),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+            ScriptError::SigPushOnly => write!(f, "script_sig is not push only"),
+        }
+    }
+}
+
+/// A Bitcoin script: a sequence of opcodes and data pushes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Script(Vec<u8>);
+
+/// A parsed script element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    PushBytes(&'a [u8]),
+    Op(u8),
+}
+
+/// Iterator over the instructions of a script, stops after the first error
+pub struct Instructions<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ScriptError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&opcode, rest) = self.data.split_first()?;
+        self.data = rest;
+        let length = match opcode {
+            0x01..=0x4b => opcode as usize,
+            OP_PUSHDATA1 | OP_PUSHDATA2 | OP_PUSHDATA4 => {
+                let size = match opcode {
+                    OP_PUSHDATA1 => 1,
+                    OP_PUSHDATA2 => 2,
+                    _ => 4,
+                };
+                if self.data.len() < size {
+                    self.data = &[];
+                    return Some(Err(ScriptError::TruncatedPush));
+                }
+                let (length_bytes, rest) = self.data.split_at(size);
+                self.data = rest;
+                length_bytes.iter().rev().fold(0usize, |length, byte| (length << 8) | *byte as usize)
+            }
+            OP_0 => return Some(Ok(Instruction::PushBytes(&[]))),
+            _ => return Some(Ok(Instruction::Op(opcode))),
+        };
+        if self.data.len() < length {
+            self.data = &[];
+            return Some(Err(ScriptError::TruncatedPush));
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(Ok(Instruction::PushBytes(bytes)))
+    }
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script(vec![])
+    }
+
+    #[cfg(test)]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { data: &self.0 }
+    }
+
+    pub fn push_opcode(&mut self, opcode: u8) -> &mut Self {
+        self.0.push(opcode);
+        self
+    }
+
+    /// Pushes `data` with the smallest push opcode
+    pub fn push_data(&mut self, data: &[u8]) -> &mut Self {
+        match data.len() {
+            0 => self.0.push(OP_0),
+            length @ 1..=0x4b => self.0.push(length as u8),
+            length @ 0x4c..=0xff => self.0.extend_from_slice(&[OP_PUSHDATA1, length as u8]),
+            length @ 0x100..=0xffff => {
+                self.0.push(OP_PUSHDATA2);
+                self.0.extend_from_slice(&(length as u16).to_le_bytes());
+            }
+            length => {
+                self.0.push(OP_PUSHDATA4);
+                self.0.extend_from_slice(&(length as u32).to_le_bytes());
+            }
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// Pushes a number, using OP_1NEGATE and OP_0..OP_16 for small values
+    pub fn push_int(&mut self, value: i64) -> &mut Self {
+        match value {
+            -1 => self.push_opcode(OP_1NEGATE),
+            0 => self.push_opcode(OP_0),
+            1..=16 => self.push_opcode(OP_1 + value as u8 - 1),
+            _ => self.push_data(&encode_num(value)),
+        }
+    }
+
+    /// Pay to public key: `<public key> OP_CHECKSIG`
+    pub fn new_p2pk(public_key: &PublicKey) -> Script {
+        let mut script = Script::new();
+        script.push_data(&public_key.serialize()).push_opcode(OP_CHECKSIG);
+        script
+    }
+
+    /// Pay to public key hash: `OP_DUP OP_HASH160 <hash160(public key)> OP_EQUALVERIFY OP_CHECKSIG`
+    pub fn new_p2pkh(public_key_hash: &hash160::Hash) -> Script {
+        let mut script = Script::new();
+        script.push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(public_key_hash.as_byte_array())
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG);
+        script
+    }
+
+    /// Bare multisig: `<required> <public keys...> <key count> OP_CHECKMULTISIG`
+    #[cfg(test)]
+    pub fn new_multisig(required: usize, public_keys: &[PublicKey]) -> Script {
+        let mut script = Script::new();
+        script.push_int(required as i64);
+        for public_key in public_keys {
+            script.push_data(&public_key.serialize());
+        }
+        script.push_int(public_keys.len() as i64).push_opcode(OP_CHECKMULTISIG);
+        script
+    }
+
+    /// Pay to script hash: `OP_HASH160 <hash160(redeem script)> OP_EQUAL`
+    #[cfg(test)]
+    pub fn new_p2sh(script_hash: &hash160::Hash) -> Script {
+        let mut script = Script::new();
+        script.push_opcode(OP_HASH160)
+            .push_data(script_hash.as_byte_array())
+            .push_opcode(OP_EQUAL);
+        script
+    }
+
+    /// Hash committed to by a P2SH output spendable with this redeem script
+    #[cfg(test)]
+    pub fn script_hash(&self) -> hash160::Hash {
+        hash160(&self.0)
+    }
+
+    #[cfg(test)]
+    pub fn is_p2pk(&self) -> bool {
+        self.0.len() == 35 && self.0[0] == 33 && self.0[34] == OP_CHECKSIG
+    }
+
+    #[cfg(test)]
+    pub fn is_p2pkh(&self) -> bool {
+        self.0.len() == 25
+            && self.0[..3] == [OP_DUP, OP_HASH160, 20]
+            && self.0[23..] == [OP_EQUALVERIFY, OP_CHECKSIG]
+    }
+
+    pub fn is_p2sh(&self) -> bool {
+        self.0.len() == 23 && self.0[..2] == [OP_HASH160, 20] && self.0[22] == OP_EQUAL
+    }
+
+    /// True if the script only pushes data (OP_1NEGATE and OP_1..OP_16 count as pushes)
+    pub fn is_push_only(&self) -> bool {
+        self.instructions().all(|instruction| match instruction {
+            Ok(Instruction::PushBytes(_)) => true,
+            Ok(Instruction::Op(opcode)) => opcode <= OP_16,
+            Err(_) => false,
+        })
+    }
+}
+
+impl From<Vec<u8>> for Script {
+    fn from(bytes: Vec<u8>) -> Script {
+        Script(bytes)
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Encodable for Script {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        write_var_bytes(writer, &self.0);
+    }
+}
+
+impl Decodable for Script {
+    fn decode_from(reader: &mut Reader) -> Result<Script, DecodeError> {
+        Ok(Script(reader.read_var_bytes()?.to_vec()))
+    }
+}
+
+/// Checks signatures for OP_CHECKSIG and OP_CHECKMULTISIG
+pub trait SignatureChecker {
+    /// `signature` is a DER signature followed by the sighash type byte,
+    /// `script_code` is the script being executed
+    fn check_signature(&self, signature: &[u8], public_key: &[u8], script_code: &Script) -> bool;
+}
+
+/// Checks signatures of the input `input_index` of `transaction`
+pub struct TransactionSignatureChecker<'a> {
+    transaction: &'a Transaction,
+    input_index: usize,
+}
+
+impl<'a> TransactionSignatureChecker<'a> {
+    pub fn new(transaction: &'a Transaction, input_index: usize) -> TransactionSignatureChecker<'a> {
+        TransactionSignatureChecker { transaction, input_index }
+    }
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    fn check_signature(&self, signature: &[u8], public_key: &[u8], script_code: &Script) -> bool {
+        let Some((&sighash_byte, der_signature)) = signature.split_last() else {
+            return false;
+        };
+        let Some(sighash_type) = SigHashType::from_byte(sighash_byte) else {
+            return false;
+        };
+        let (Ok(signature), Ok(public_key)) = (Signature::from_der(der_signature), PublicKey::from_slice(public_key)) else {
+            return false;
+        };
+        match signature_hash(self.transaction, self.input_index, script_code, sighash_type) {
+            Ok(digest) => verify_hash(&digest, &signature, &public_key),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Script numbers are little-endian with the sign in the highest bit of the last byte
+pub fn encode_num(value: i64) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut magnitude = value.unsigned_abs();
+    while magnitude > 0 {
+        bytes.push((magnitude & 0xff) as u8);
+        magnitude >>= 8;
+    }
+    if let Some(last) = bytes.last_mut() {
+        if *last & 0x80 != 0 {
+            bytes.push(if value < 0 { 0x80 } else { 0x00 });
+        } else if value < 0 {
+            *last |= 0x80;
+        }
+    }
+    bytes
+}
+
+pub fn decode_num(bytes: &[u8]) -> Result<i64, ScriptError> {
+    if bytes.len() > MAX_NUM_SIZE {
+        return Err(ScriptError::NumOverflow);
+    }
+    let Some(&last) = bytes.last() else {
+        return Ok(0);
+    };
+    let magnitude = bytes.iter().rev().fold(0i64, |value, byte| (value << 8) | *byte as i64);
+    let sign_bit = 0x80i64 << (8 * (bytes.len() - 1));
+    if last & 0x80 != 0 {
+        Ok(-(magnitude & !sign_bit))
+    } else {
+        Ok(magnitude)
+    }
+}
+
+/// Any non zero value is true, negative zero is false
+pub fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        Some((&last, rest)) => rest.iter().any(|byte| *byte != 0) || (last != 0 && last != 0x80),
+        None => false,
+    }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::InvalidStackOperation)
+}
+
+fn pop_num(stack: &mut Vec<Vec<u8>>) -> Result<i64, ScriptError> {
+    decode_num(&pop(stack)?)
+}
+
+/// Element `depth` positions below the top of the stack (0 is the top)
+fn peek(stack: &[Vec<u8>], depth: usize) -> Result<&Vec<u8>, ScriptError> {
+    stack.len().checked_sub(depth + 1)
+        .map(|index| &stack[index])
+        .ok_or(ScriptError::InvalidStackOperation)
+}
+
+fn push_bool(stack: &mut Vec<Vec<u8>>, value: bool) {
+    stack.push(if value { vec![1] } else { vec![] });
+}
+
+/// Executes `script` on `stack`
+pub fn eval_script<C: SignatureChecker + ?Sized>(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &C) -> Result<(), ScriptError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+    // one entry per open OP_IF, the branch is executed if all entries are true
+    let mut conditions: Vec<bool> = vec![];
+    let mut alt_stack: Vec<Vec<u8>> = vec![];
+    let mut op_count = 0;
+
+    for instruction in script.instructions() {
+        let executing = conditions.iter().all(|condition| *condition);
+        let opcode = match instruction? {
+            Instruction::PushBytes(data) => {
+                if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(ScriptError::PushSize);
+                }
+                if executing {
+                    stack.push(data.to_vec());
+                }
+                check_stack_size(stack, &alt_stack)?;
+                continue;
+            }
+            Instruction::Op(opcode) => opcode,
+        };
+
+        if opcode > OP_16 {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::OpCount);
+            }
+        }
+        if DISABLED_OPCODES.contains(&opcode) {
+            return Err(ScriptError::DisabledOpcode(opcode));
+        }
+        if !executing && !matches!(opcode, OP_IF | OP_NOTIF | OP_ELSE | OP_ENDIF) {
+            continue;
+        }
+
+        match opcode {
+            OP_1NEGATE => stack.push(encode_num(-1)),
+            OP_1..=OP_16 => stack.push(encode_num((opcode - OP_1 + 1) as i64)),
+            OP_NOP => {}
+            OP_IF | OP_NOTIF => {
+                let mut condition = false;
+                if executing {
+                    condition = cast_to_bool(&pop(stack)?);
+                    if opcode == OP_NOTIF {
+                        condition = !condition;
+                    }
+                }
+                conditions.push(condition);
+            }
+            OP_ELSE => {
+                let condition = conditions.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                *condition = !*condition;
+            }
+            OP_ENDIF => {
+                conditions.pop().ok_or(ScriptError::UnbalancedConditional)?;
+            }
+            OP_VERIFY => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(ScriptError::Verify);
+                }
+            }
+            OP_RETURN => return Err(ScriptError::OpReturn),
+            OP_TOALTSTACK => alt_stack.push(pop(stack)?),
+            OP_FROMALTSTACK => stack.push(pop(&mut alt_stack)?),
+            OP_2DROP => {
+                pop(stack)?;
+                pop(stack)?;
+            }
+            OP_2DUP => {
+                let second = peek(stack, 1)?.clone();
+                let first = peek(stack, 0)?.clone();
+                stack.push(second);
+                stack.push(first);
+            }
+            OP_DEPTH => stack.push(encode_num(stack.len() as i64)),
+            OP_DROP => {
+                pop(stack)?;
+            }
+            OP_DUP => stack.push(peek(stack, 0)?.clone()),
+            OP_NIP => {
+                let top = pop(stack)?;
+                pop(stack)?;
+                stack.push(top);
+            }
+            OP_OVER => stack.push(peek(stack, 1)?.clone()),
+            OP_SWAP => {
+                let top = pop(stack)?;
+                let second = pop(stack)?;
+                stack.push(top);
+                stack.push(second);
+            }
+            OP_SIZE => stack.push(encode_num(peek(stack, 0)?.len() as i64)),
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let equal = pop(stack)? == pop(stack)?;
+                if opcode == OP_EQUALVERIFY {
+                    if !equal {
+                        return Err(ScriptError::EqualVerify);
+                    }
+                } else {
+                    push_bool(stack, equal);
+                }
+            }
+            OP_1ADD => {
+                let value = pop_num(stack)?;
+                stack.push(encode_num(value + 1));
+            }
+            OP_1SUB => {
+                let value = pop_num(stack)?;
+                stack.push(encode_num(value - 1));
+            }
+            OP_NOT => {
+                let value = pop_num(stack)?;
+                push_bool(stack, value == 0);
+            }
+            OP_ADD | OP_SUB | OP_NUMEQUAL | OP_NUMEQUALVERIFY | OP_LESSTHAN | OP_GREATERTHAN => {
+                let b = pop_num(stack)?;
+                let a = pop_num(stack)?;
+                match opcode {
+                    OP_ADD => stack.push(encode_num(a + b)),
+                    OP_SUB => stack.push(encode_num(a - b)),
+                    OP_NUMEQUAL => push_bool(stack, a == b),
+                    OP_NUMEQUALVERIFY => {
+                        if a != b {
+                            return Err(ScriptError::NumEqualVerify);
+                        }
+                    }
+                    OP_LESSTHAN => push_bool(stack, a < b),
+                    _ => push_bool(stack, a > b),
+                }
+            }
+            OP_SHA256 => {
+                let data = pop(stack)?;
+                stack.push(sha256_bytes(&data).to_byte_array().to_vec());
+            }
+            OP_HASH160 => {
+                let data = pop(stack)?;
+                stack.push(hash160(&data).to_byte_array().to_vec());
+            }
+            OP_HASH256 => {
+                let data = pop(stack)?;
+                stack.push(hash256(&data).to_byte_array().to_vec());
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let public_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = checker.check_signature(&signature, &public_key, script);
+                if opcode == OP_CHECKSIGVERIFY {
+                    if !valid {
+                        return Err(ScriptError::CheckSigVerify);
+                    }
+                } else {
+                    push_bool(stack, valid);
+                }
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                let valid = check_multisig(stack, checker, script, &mut op_count)?;
+                if opcode == OP_CHECKMULTISIGVERIFY {
+                    if !valid {
+                        return Err(ScriptError::CheckMultiSigVerify);
+                    }
+                } else {
+                    push_bool(stack, valid);
+                }
+            }
+            _ => return Err(ScriptError::BadOpcode(opcode)),
+        }
+        check_stack_size(stack, &alt_stack)?;
+    }
+
+    if !conditions.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+fn check_stack_size(stack: &[Vec<u8>], alt_stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    if stack.len() + alt_stack.len() > MAX_STACK_SIZE {
+        return Err(ScriptError::StackSize);
+    }
+    Ok(())
+}
+
+/// Pops `<dummy> <signatures...> <m> <public keys...> <n>` and checks that the signatures
+/// match m of the n public keys, in the same order.
+/// Like in Bitcoin an extra (dummy) element is consumed and every public key counts as an operation
+fn check_multisig<C: SignatureChecker + ?Sized>(stack: &mut Vec<Vec<u8>>, checker: &C, script_code: &Script, op_count: &mut usize) -> Result<bool, ScriptError> {
+    let key_count = pop_num(stack)?;
+    if key_count < 0 || key_count as usize > MAX_PUBKEYS_PER_MULTISIG {
+        return Err(ScriptError::PubKeyCount);
+    }
+    *op_count += key_count as usize;
+    if *op_count > MAX_OPS_PER_SCRIPT {
+        return Err(ScriptError::OpCount);
+    }
+    let public_keys = (0..key_count).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+
+    let signature_count = pop_num(stack)?;
+    if signature_count < 0 || signature_count > key_count {
+        return Err(ScriptError::SigCount);
+    }
+    let signatures = (0..signature_count).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+    pop(stack)?;
+
+    // both lists were popped in reverse, so the first key and signature are last
+    let mut public_keys = public_keys.iter();
+    for signature in signatures.iter() {
+        if !public_keys.any(|public_key| checker.check_signature(signature, public_key, script_code)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Verifies that `script_sig` unlocks `script_pub_key`: the script_sig is executed first
+/// and the script_pub_key runs on the resulting stack, it must leave a true value on top.
+/// For P2SH outputs the last element pushed by the script_sig is executed as the redeem script
+pub fn verify_script<C: SignatureChecker + ?Sized>(script_sig: &Script, script_pub_key: &Script, checker: &C) -> Result<(), ScriptError> {
+    let mut stack = vec![];
+    eval_script(script_sig, &mut stack, checker)?;
+    let p2sh_stack = script_pub_key.is_p2sh().then(|| stack.clone());
+    eval_script(script_pub_key, &mut stack, checker)?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(ScriptError::EvalFalse);
+    }
+
+    if let Some(mut stack) = p2sh_stack {
+        if !script_sig.is_push_only() {
+            return Err(ScriptError::SigPushOnly);
+        }
+        let redeem_script = Script::from(pop(&mut stack)?);
+        eval_script(&redeem_script, &mut stack, checker)?;
+        if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+            return Err(ScriptError::EvalFalse);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::TX_VERSION;
+    use crate::core::transaction::{TransactionInput, TransactionOutput};
+    use crate::utils::wallets::{generate_keypair, sign_hash};
+    use secp256k1::SecretKey;
+
+    /// Accepts signatures equal to the public key, for testing scripts without transactions
+    struct EqualChecker;
+
+    impl SignatureChecker for EqualChecker {
+        fn check_signature(&self, signature: &[u8], public_key: &[u8], _script_code: &Script) -> bool {
+            signature == public_key
+        }
+    }
+
+    fn run(script: &Script) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let mut stack = vec![];
+        eval_script(script, &mut stack, &EqualChecker)?;
+        Ok(stack)
+    }
+
+    fn create_spending_transaction() -> Transaction {
+        let (_, public_key) = generate_keypair();
+        Transaction {
+            transaction_version: TX_VERSION,
+            input_count: 1,
+            inputs: vec![TransactionInput {
+                previous_transaction_hash: hash256(b"previous_transaction"),
+                previous_transaction_index: 0,
+                script_length: 0,
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+            }],
+            output_count: 1,
+            outputs: vec![TransactionOutput {
+                value: 1_000,
+                script_length: 0,
+                script_pub_key: Script::new(),
+                recipient_pub_key: public_key,
+            }],
+            lock_time: 0,
+        }
+    }
+
+    fn sign(transaction: &Transaction, script_code: &Script, secret_key: &SecretKey) -> Vec<u8> {
+        let digest = signature_hash(transaction, 0, script_code, SigHashType::All).unwrap();
+        let mut signature = sign_hash(&digest, secret_key).serialize_der().to_vec();
+        signature.push(SigHashType::All.to_byte());
+        signature
+    }
+
+    #[test]
+    fn test_num_encoding() {
+        for (value, bytes) in [
+            (0, vec![]),
+            (1, vec![0x01]),
+            (-1, vec![0x81]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x00]),
+            (-128, vec![0x80, 0x80]),
+            (255, vec![0xff, 0x00]),
+            (256, vec![0x00, 0x01]),
+            (-256, vec![0x00, 0x81]),
+        ] {
+            assert_eq!(encode_num(value), bytes);
+            assert_eq!(decode_num(&bytes), Ok(value));
+        }
+        assert_eq!(decode_num(&[1, 2, 3, 4, 5]), Err(ScriptError::NumOverflow));
+        assert!(!cast_to_bool(&[0x00, 0x80]));
+        assert!(cast_to_bool(&[0x00, 0x01]));
+    }
+
+    #[test]
+    fn test_push_data_and_instructions() {
+        let mut script = Script::new();
+        script.push_data(&[7; 3]).push_data(&[8; 80]).push_data(&[9; 300]).push_int(5).push_int(1000).push_opcode(OP_DUP);
+        let instructions: Vec<Instruction> = script.instructions().collect::<Result<_, _>>().unwrap();
+        assert_eq!(instructions, vec![
+            Instruction::PushBytes(&[7; 3]),
+            Instruction::PushBytes(&[8; 80]),
+            Instruction::PushBytes(&[9; 300]),
+            Instruction::Op(0x55),
+            Instruction::PushBytes(&[0xe8, 0x03]),
+            Instruction::Op(OP_DUP),
+        ]);
+        assert_eq!(script.as_bytes()[4], OP_PUSHDATA1);
+        assert!(!script.is_push_only());
+
+        let truncated = Script::from(vec![0x05, 1, 2]);
+        assert_eq!(truncated.instructions().next(), Some(Err(ScriptError::TruncatedPush)));
+        assert_eq!(Script::decode(&script.encode()).unwrap(), script);
+    }
+
+    #[test]
+    fn test_arithmetic_and_conditionals() {
+        let mut script = Script::new();
+        script.push_int(2).push_int(3).push_opcode(OP_ADD).push_int(5).push_opcode(OP_NUMEQUAL);
+        assert_eq!(run(&script).unwrap(), vec![vec![1]]);
+
+        let mut script = Script::new();
+        script.push_int(0)
+            .push_opcode(OP_IF).push_int(10)
+            .push_opcode(OP_ELSE).push_int(20)
+            .push_opcode(OP_ENDIF);
+        assert_eq!(run(&script).unwrap(), vec![encode_num(20)]);
+
+        let mut script = Script::new();
+        script.push_int(1).push_opcode(OP_IF);
+        assert_eq!(run(&script), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(Script::new().push_opcode(OP_ENDIF)), Err(ScriptError::UnbalancedConditional));
+    }
+
+    #[test]
+    fn test_failing_opcodes() {
+        assert_eq!(run(Script::new().push_opcode(OP_RETURN)), Err(ScriptError::OpReturn));
+        assert_eq!(run(Script::new().push_opcode(OP_DUP)), Err(ScriptError::InvalidStackOperation));
+        assert_eq!(run(Script::new().push_int(0).push_opcode(OP_VERIFY)), Err(ScriptError::Verify));
+        assert_eq!(run(Script::new().push_opcode(0xba)), Err(ScriptError::BadOpcode(0xba)));
+        // disabled opcodes fail even in branches that are not executed
+        let mut script = Script::new();
+        script.push_int(0).push_opcode(OP_IF).push_opcode(0x7e).push_opcode(OP_ENDIF);
+        assert_eq!(run(&script), Err(ScriptError::DisabledOpcode(0x7e)));
+        // unknown opcodes only fail when executed
+        let mut script = Script::new();
+        script.push_int(0).push_opcode(OP_IF).push_opcode(0xba).push_opcode(OP_ENDIF);
+        assert!(run(&script).is_ok());
+    }
+
+    #[test]
+    fn test_execution_limits() {
+        assert_eq!(run(&Script::from(vec![OP_NOP; MAX_SCRIPT_SIZE + 1])), Err(ScriptError::ScriptSize));
+        assert_eq!(run(&Script::from(vec![OP_NOP; MAX_OPS_PER_SCRIPT + 1])), Err(ScriptError::OpCount));
+        assert!(run(&Script::from(vec![OP_NOP; MAX_OPS_PER_SCRIPT])).is_ok());
+        assert_eq!(run(Script::new().push_data(&[0; MAX_SCRIPT_ELEMENT_SIZE + 1])), Err(ScriptError::PushSize));
+        assert_eq!(run(&Script::from(vec![OP_1; MAX_STACK_SIZE + 1])), Err(ScriptError::StackSize));
+    }
+
+    #[test]
+    fn test_templates() {
+        let (_, public_key) = generate_keypair();
+        let public_key_hash = hash160(&public_key.serialize());
+        assert!(Script::new_p2pk(&public_key).is_p2pk());
+        assert!(Script::new_p2pkh(&public_key_hash).is_p2pkh());
+        assert!(Script::new_p2sh(&public_key_hash).is_p2sh());
+        assert!(!Script::new_p2pkh(&public_key_hash).is_p2sh());
+        assert_eq!(Script::new_multisig(2, &[public_key; 3]).len(), 1 + 3 * 34 + 1 + 1);
+    }
+
+    #[test]
+    fn test_p2pk_and_p2pkh_spends() {
+        let (secret_key, public_key) = generate_keypair();
+        let transaction = create_spending_transaction();
+
+        let p2pk = Script::new_p2pk(&public_key);
+        let mut script_sig = Script::new();
+        script_sig.push_data(&sign(&transaction, &p2pk, &secret_key));
+        let checker = TransactionSignatureChecker::new(&transaction, 0);
+        assert_eq!(verify_script(&script_sig, &p2pk, &checker), Ok(()));
+
+        let p2pkh = Script::new_p2pkh(&hash160(&public_key.serialize()));
+        let mut script_sig = Script::new();
+        script_sig.push_data(&sign(&transaction, &p2pkh, &secret_key)).push_data(&public_key.serialize());
+        assert_eq!(verify_script(&script_sig, &p2pkh, &checker), Ok(()));
+
+        // a signature for another script code or another key fails
+        let (other_secret_key, other_public_key) = generate_keypair();
+        let mut script_sig = Script::new();
+        script_sig.push_data(&sign(&transaction, &p2pk, &secret_key)).push_data(&public_key.serialize());
+        assert_eq!(verify_script(&script_sig, &p2pkh, &checker), Err(ScriptError::EvalFalse));
+        let mut script_sig = Script::new();
+        script_sig.push_data(&sign(&transaction, &p2pkh, &other_secret_key)).push_data(&other_public_key.serialize());
+        assert_eq!(verify_script(&script_sig, &p2pkh, &checker), Err(ScriptError::EqualVerify));
+    }
+
+    #[test]
+    fn test_multisig_spend() {
+        let keys: Vec<(SecretKey, PublicKey)> = (0..3).map(|_| generate_keypair()).collect();
+        let public_keys: Vec<PublicKey> = keys.iter().map(|(_, public_key)| *public_key).collect();
+        let multisig = Script::new_multisig(2, &public_keys);
+        let transaction = create_spending_transaction();
+        let checker = TransactionSignatureChecker::new(&transaction, 0);
+
+        let mut script_sig = Script::new();
+        script_sig.push_int(0)
+            .push_data(&sign(&transaction, &multisig, &keys[0].0))
+            .push_data(&sign(&transaction, &multisig, &keys[2].0));
+        assert_eq!(verify_script(&script_sig, &multisig, &checker), Ok(()));
+
+        // signatures must be in the same order as the public keys
+        let mut script_sig = Script::new();
+        script_sig.push_int(0)
+            .push_data(&sign(&transaction, &multisig, &keys[2].0))
+            .push_data(&sign(&transaction, &multisig, &keys[0].0));
+        assert_eq!(verify_script(&script_sig, &multisig, &checker), Err(ScriptError::EvalFalse));
+
+        // the dummy element is required
+        let mut script_sig = Script::new();
+        script_sig.push_data(&sign(&transaction, &multisig, &keys[0].0))
+            .push_data(&sign(&transaction, &multisig, &keys[1].0));
+        assert_eq!(verify_script(&script_sig, &multisig, &checker), Err(ScriptError::InvalidStackOperation));
+    }
+
+    #[test]
+    fn test_p2sh_spend() {
+        let keys: Vec<(SecretKey, PublicKey)> = (0..2).map(|_| generate_keypair()).collect();
+        let public_keys: Vec<PublicKey> = keys.iter().map(|(_, public_key)| *public_key).collect();
+        let redeem_script = Script::new_multisig(1, &public_keys);
+        let p2sh = Script::new_p2sh(&redeem_script.script_hash());
+        let transaction = create_spending_transaction();
+        let checker = TransactionSignatureChecker::new(&transaction, 0);
+
+        let mut script_sig = Script::new();
+        script_sig.push_int(0)
+            .push_data(&sign(&transaction, &redeem_script, &keys[1].0))
+            .push_data(redeem_script.as_bytes());
+        assert_eq!(verify_script(&script_sig, &p2sh, &checker), Ok(()));
+
+        // the redeem script is executed, not only matched against the hash
+        let mut script_sig = Script::new();
+        script_sig.push_int(0).push_data(&[1; 72]).push_data(redeem_script.as_bytes());
+        assert_eq!(verify_script(&script_sig, &p2sh, &checker), Err(ScriptError::EvalFalse));
+
+        // the script_sig of a P2SH spend must be push only
+        let mut script_sig = Script::new();
+        script_sig.push_int(0)
+            .push_data(&sign(&transaction, &redeem_script, &keys[1].0))
+            .push_opcode(OP_NOP)
+            .push_data(redeem_script.as_bytes());
+        assert_eq!(verify_script(&script_sig, &p2sh, &checker), Err(ScriptError::SigPushOnly));
+    }
+}
@@ -0,0 +1,326 @@
+use std::fmt;
+
+use secp256k1::hashes::{sha256, Hash};
+use secp256k1::PublicKey;
+
+/// Maximum number of items preallocated when decoding a vector,
+/// so a forged length can't make the decoder allocate a huge buffer
+const MAX_PREALLOCATED_ITEMS: usize = 1024;
+
+/// Types that have a canonical byte encoding (Bitcoin wire format style:
+/// little-endian integers and CompactSize prefixed vectors)
+pub trait Encodable {
+    /// Appends the encoded value to `writer`
+    fn encode_to(&self, writer: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut writer = vec![];
+        self.encode_to(&mut writer);
+        writer
+    }
+}
+
+/// Types that can be decoded from their canonical byte encoding
+pub trait Decodable: Sized {
+    /// Decodes a value from the current position of `reader`
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError>;
+
+    /// Decodes a value that has to span all of `bytes`
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode_from(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended before the value was fully decoded
+    UnexpectedEnd,
+    /// Bytes are left after the value was decoded
+    TrailingBytes,
+    /// A CompactSize was not encoded in its shortest form
+    NonCanonicalCompactSize,
+    /// A length doesn't fit into memory
+    LengthTooLarge,
+    InvalidUtf8,
+    InvalidPublicKey,
+    /// A block without any transaction (it needs at least the coinbase)
+    EmptyBlock,
+    /// A block index entry with an unknown status byte
+    InvalidBlockStatus,
+    /// A bool that's neither 0 nor 1
+    InvalidBool,
+    /// An inventory item of an unknown type
+    InvalidInventoryType,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::TrailingBytes => write!(f, "trailing bytes after the decoded value"),
+            DecodeError::NonCanonicalCompactSize => write!(f, "non-canonical CompactSize"),
+            DecodeError::LengthTooLarge => write!(f, "length too large"),
+            DecodeError::InvalidUtf8 => write!(f, "invalid UTF-8 string"),
+            DecodeError::InvalidPublicKey => write!(f, "invalid public key"),
+            DecodeError::EmptyBlock => write!(f, "block has no transactions"),
+            DecodeError::InvalidBlockStatus => write!(f, "invalid block status"),
+            DecodeError::InvalidBool => write!(f, "invalid bool"),
+            DecodeError::InvalidInventoryType => write!(f, "invalid inventory type"),
+        }
+    }
+}
+
+/// Cursor over a byte slice used by the decoders
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.position.checked_add(length).ok_or(DecodeError::UnexpectedEnd)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(DecodeError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    /// Reads a bool encoded as a 0 or 1 byte
+    pub fn read_bool(&mut self) -> Result<bool, DecodeError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidBool),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, DecodeError> {
+        Ok(u128::from_le_bytes(self.read_array()?))
+    }
+
+    /// Reads a CompactSize unsigned integer, rejecting non-minimal encodings
+    pub fn read_compact_size(&mut self) -> Result<u64, DecodeError> {
+        let (value, minimum) = match self.read_u8()? {
+            0xfd => (self.read_u16()? as u64, 0xfd),
+            0xfe => (self.read_u32()? as u64, 0x1_0000),
+            0xff => (self.read_u64()?, 0x1_0000_0000),
+            byte => return Ok(byte as u64),
+        };
+        if value < minimum {
+            return Err(DecodeError::NonCanonicalCompactSize);
+        }
+        Ok(value)
+    }
+
+    /// Reads a CompactSize length that has to fit into the remaining input.
+    /// The length is a number of bytes or of items, every item takes at least one byte
+    pub fn read_length(&mut self) -> Result<usize, DecodeError> {
+        let length = self.read_compact_size()?;
+        let length = usize::try_from(length).map_err(|_| DecodeError::LengthTooLarge)?;
+        if length > self.bytes.len() - self.position {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(length)
+    }
+
+    pub fn read_var_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length = self.read_length()?;
+        self.read_bytes(length)
+    }
+
+    pub fn read_var_string(&mut self) -> Result<String, DecodeError> {
+        let bytes = self.read_var_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    pub fn read_hash(&mut self) -> Result<sha256::Hash, DecodeError> {
+        Ok(sha256::Hash::from_byte_array(self.read_array()?))
+    }
+
+    pub fn read_public_key(&mut self) -> Result<PublicKey, DecodeError> {
+        let bytes: [u8; 33] = self.read_array()?;
+        PublicKey::from_slice(&bytes).map_err(|_| DecodeError::InvalidPublicKey)
+    }
+
+    /// Reads a CompactSize prefixed vector of decodable items
+    pub fn read_vec<T: Decodable>(&mut self) -> Result<Vec<T>, DecodeError> {
+        let count = self.read_length()?;
+        let mut items = Vec::with_capacity(count.min(MAX_PREALLOCATED_ITEMS));
+        for _ in 0..count {
+            items.push(T::decode_from(self)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Encodable for sha256::Hash {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(self.as_byte_array());
+    }
+}
+
+impl Decodable for sha256::Hash {
+    fn decode_from(reader: &mut Reader) -> Result<sha256::Hash, DecodeError> {
+        reader.read_hash()
+    }
+}
+
+/// Optional values are encoded as a bool followed by the value if it's present
+impl<T: Encodable> Encodable for Option<T> {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.push(self.is_some() as u8);
+        if let Some(value) = self {
+            value.encode_to(writer);
+        }
+    }
+}
+
+impl<T: Decodable> Decodable for Option<T> {
+    fn decode_from(reader: &mut Reader) -> Result<Option<T>, DecodeError> {
+        if reader.read_bool()? {
+            Ok(Some(T::decode_from(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Pairs are encoded as their two values one after another
+impl<A: Encodable, B: Encodable> Encodable for (A, B) {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        self.0.encode_to(writer);
+        self.1.encode_to(writer);
+    }
+}
+
+impl<A: Decodable, B: Decodable> Decodable for (A, B) {
+    fn decode_from(reader: &mut Reader) -> Result<(A, B), DecodeError> {
+        Ok((A::decode_from(reader)?, B::decode_from(reader)?))
+    }
+}
+
+pub fn write_compact_size(writer: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfc => writer.push(value as u8),
+        0xfd..=0xffff => {
+            writer.push(0xfd);
+            writer.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            writer.push(0xfe);
+            writer.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            writer.push(0xff);
+            writer.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+pub fn write_var_bytes(writer: &mut Vec<u8>, bytes: &[u8]) {
+    write_compact_size(writer, bytes.len() as u64);
+    writer.extend_from_slice(bytes);
+}
+
+pub fn write_vec<T: Encodable>(writer: &mut Vec<u8>, items: &[T]) {
+    write_compact_size(writer, items.len() as u64);
+    for item in items {
+        item.encode_to(writer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_compact_size(value: u64) -> Vec<u8> {
+        let mut writer = vec![];
+        write_compact_size(&mut writer, value);
+        writer
+    }
+
+    #[test]
+    fn test_compact_size_encoding() {
+        assert_eq!(encode_compact_size(0), vec![0x00]);
+        assert_eq!(encode_compact_size(0xfc), vec![0xfc]);
+        assert_eq!(encode_compact_size(0xfd), vec![0xfd, 0xfd, 0x00]);
+        assert_eq!(encode_compact_size(0xffff), vec![0xfd, 0xff, 0xff]);
+        assert_eq!(encode_compact_size(0x10000), vec![0xfe, 0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(encode_compact_size(0x1_0000_0000), vec![0xff, 0, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_compact_size_roundtrip() {
+        for value in [0, 1, 0xfc, 0xfd, 0xffff, 0x10000, 0xffff_ffff, 0x1_0000_0000, u64::MAX] {
+            let bytes = encode_compact_size(value);
+            let mut reader = Reader::new(&bytes);
+            assert_eq!(reader.read_compact_size().unwrap(), value);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_non_canonical_compact_size() {
+        let mut reader = Reader::new(&[0xfd, 0x10, 0x00]);
+        assert_eq!(reader.read_compact_size(), Err(DecodeError::NonCanonicalCompactSize));
+        let mut reader = Reader::new(&[0xfe, 0xff, 0xff, 0x00, 0x00]);
+        assert_eq!(reader.read_compact_size(), Err(DecodeError::NonCanonicalCompactSize));
+    }
+
+    #[test]
+    fn test_reader_unexpected_end() {
+        let mut reader = Reader::new(&[0x01, 0x02]);
+        assert_eq!(reader.read_u32(), Err(DecodeError::UnexpectedEnd));
+        // a length pointing past the end of the input
+        let mut reader = Reader::new(&[0x05, 0x61]);
+        assert_eq!(reader.read_var_bytes(), Err(DecodeError::UnexpectedEnd));
+        // an item count larger than the remaining input
+        let mut reader = Reader::new(&[0x02, 0x00]);
+        assert_eq!(reader.read_length(), Err(DecodeError::UnexpectedEnd));
+        let mut reader = Reader::new(&[0xfe, 0xff, 0xff, 0xff, 0x7f]);
+        assert_eq!(reader.read_vec::<sha256::Hash>(), Err(DecodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_var_string() {
+        let mut writer = vec![];
+        write_var_bytes(&mut writer, "bitcoin".as_bytes());
+        assert_eq!(writer[0], 7);
+        let mut reader = Reader::new(&writer);
+        assert_eq!(reader.read_var_string().unwrap(), "bitcoin");
+        let mut reader = Reader::new(&[0x01, 0xff]);
+        assert_eq!(reader.read_var_string(), Err(DecodeError::InvalidUtf8));
+    }
+}
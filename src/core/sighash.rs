@@ -0,0 +1,283 @@
+use std::fmt;
+
+use secp256k1::hashes::sha256;
+
+use crate::core::script::Script;
+use crate::core::serialize::Encodable;
+use crate::core::transaction::Transaction;
+use crate::utils::hash::hash256;
+
+/// Selects which parts of a transaction a signature commits to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigHashType {
+    /// Signs all inputs and all outputs
+    All,
+    /// Signs all inputs but no outputs, anyone can change where the coins go
+    None,
+    /// Signs all inputs and only the output with the same index as the signed input
+    Single,
+    /// `All`, but only the signed input, other inputs can be added freely
+    AllPlusAnyoneCanPay,
+    /// `None`, but only the signed input
+    NonePlusAnyoneCanPay,
+    /// `Single`, but only the signed input
+    SinglePlusAnyoneCanPay,
+}
+
+const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+impl SigHashType {
+    /// Byte appended to signatures, the same values as in Bitcoin
+    pub fn to_byte(self) -> u8 {
+        match self {
+            SigHashType::All => 0x01,
+            SigHashType::None => 0x02,
+            SigHashType::Single => 0x03,
+            SigHashType::AllPlusAnyoneCanPay => 0x01 | SIGHASH_ANYONECANPAY,
+            SigHashType::NonePlusAnyoneCanPay => 0x02 | SIGHASH_ANYONECANPAY,
+            SigHashType::SinglePlusAnyoneCanPay => 0x03 | SIGHASH_ANYONECANPAY,
+        }
+    }
+
+    /// Parses a sighash byte, unknown values are rejected
+    pub fn from_byte(byte: u8) -> Option<SigHashType> {
+        match byte {
+            0x01 => Some(SigHashType::All),
+            0x02 => Some(SigHashType::None),
+            0x03 => Some(SigHashType::Single),
+            0x81 => Some(SigHashType::AllPlusAnyoneCanPay),
+            0x82 => Some(SigHashType::NonePlusAnyoneCanPay),
+            0x83 => Some(SigHashType::SinglePlusAnyoneCanPay),
+            _ => None,
+        }
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.to_byte() & SIGHASH_ANYONECANPAY != 0
+    }
+
+    fn base_type(self) -> u8 {
+        self.to_byte() & !SIGHASH_ANYONECANPAY
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigHashError {
+    /// The transaction has no input with the given index
+    InputIndexOutOfRange(usize),
+    /// `Single` was used for an input without an output of the same index
+    MissingSingleOutput(usize),
+    /// `Transaction::sign_input` can only build script_sigs for P2PK and P2PKH outputs
+    #[cfg(test)]
+    UnsupportedScript,
+}
+
+impl fmt::Display for SigHashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SigHashError::InputIndexOutOfRange(index) => write!(f, "input {} doesn't exist", index),
+            SigHashError::MissingSingleOutput(index) => write!(f, "no output matches input {} for SIGHASH_SINGLE", index),
+            #[cfg(test)]
+            SigHashError::UnsupportedScript => write!(f, "can't build a script_sig for this script_pub_key"),
+        }
+    }
+}
+
+/// Computes the hash signed by input `input_index`:
+/// the double SHA-256 of the canonical encoding of a modified copy of the transaction,
+/// followed by the sighash type (u32) and, for `Single`, the input index (u32).
+/// In the copy all script_sigs are emptied (a signature can't sign itself),
+/// the signed input gets `script_code` (the script being executed, e.g. the spent script_pub_key) and
+/// - `None` removes all outputs and sets the sequence of the other inputs to 0 so they can be updated,
+/// - `Single` keeps only the output with the same index as the input,
+/// - the `AnyoneCanPay` variants keep only the signed input
+pub fn signature_hash(transaction: &Transaction, input_index: usize, script_code: &Script, sighash_type: SigHashType) -> Result<sha256::Hash, SigHashError> {
+    if input_index >= transaction.inputs.len() {
+        return Err(SigHashError::InputIndexOutOfRange(input_index));
+    }
+
+    let mut unsigned_transaction = transaction.clone();
+    for input in unsigned_transaction.inputs.iter_mut() {
+        input.script_sig = Script::new();
+        input.script_length = 0;
+    }
+    unsigned_transaction.inputs[input_index].script_sig = script_code.clone();
+    unsigned_transaction.inputs[input_index].script_length = script_code.len() as u32;
+
+    match sighash_type.base_type() {
+        0x02 => {
+            unsigned_transaction.outputs.clear();
+            for (i, input) in unsigned_transaction.inputs.iter_mut().enumerate() {
+                if i != input_index {
+                    input.sequence = 0;
+                }
+            }
+        }
+        0x03 => {
+            let output = transaction.outputs.get(input_index).ok_or(SigHashError::MissingSingleOutput(input_index))?;
+            unsigned_transaction.outputs = vec![output.clone()];
+            for (i, input) in unsigned_transaction.inputs.iter_mut().enumerate() {
+                if i != input_index {
+                    input.sequence = 0;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    if sighash_type.anyone_can_pay() {
+        unsigned_transaction.inputs = vec![unsigned_transaction.inputs[input_index].clone()];
+    }
+    unsigned_transaction.input_count = unsigned_transaction.inputs.len() as u32;
+    unsigned_transaction.output_count = unsigned_transaction.outputs.len() as u32;
+
+    let mut preimage = unsigned_transaction.encode();
+    preimage.extend_from_slice(&(sighash_type.to_byte() as u32).to_le_bytes());
+    if sighash_type.base_type() == 0x03 {
+        preimage.extend_from_slice(&(input_index as u32).to_le_bytes());
+    }
+    Ok(hash256(&preimage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::TX_VERSION;
+    use crate::core::transaction::{TransactionInput, TransactionOutput};
+    use crate::utils::wallets::generate_keypair;
+
+    const ALL_TYPES: [SigHashType; 6] = [
+        SigHashType::All,
+        SigHashType::None,
+        SigHashType::Single,
+        SigHashType::AllPlusAnyoneCanPay,
+        SigHashType::NonePlusAnyoneCanPay,
+        SigHashType::SinglePlusAnyoneCanPay,
+    ];
+
+    fn create_transaction(input_count: u32, output_count: u32) -> Transaction {
+        let (_, pub_key) = generate_keypair();
+        Transaction {
+            transaction_version: TX_VERSION,
+            input_count,
+            inputs: (0..input_count).map(|i| TransactionInput {
+                previous_transaction_hash: hash256(&i.to_le_bytes()),
+                previous_transaction_index: i,
+                script_length: 0,
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+            }).collect(),
+            output_count,
+            outputs: (0..output_count).map(|i| TransactionOutput {
+                value: 1_000 + i as u128,
+                script_length: 0,
+                script_pub_key: Script::new(),
+                recipient_pub_key: pub_key,
+            }).collect(),
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_sighash_type_bytes() {
+        for sighash_type in ALL_TYPES {
+            assert_eq!(SigHashType::from_byte(sighash_type.to_byte()), Some(sighash_type));
+        }
+        assert_eq!(SigHashType::from_byte(0x00), None);
+        assert_eq!(SigHashType::from_byte(0x04), None);
+        assert_eq!(SigHashType::from_byte(0x80), None);
+        assert!(SigHashType::SinglePlusAnyoneCanPay.anyone_can_pay());
+        assert!(!SigHashType::All.anyone_can_pay());
+    }
+
+    #[test]
+    fn test_sighash_types_differ() {
+        let transaction = create_transaction(2, 2);
+        let hashes: Vec<sha256::Hash> = ALL_TYPES.iter()
+            .map(|sighash_type| signature_hash(&transaction, 0, &Script::new(), *sighash_type).unwrap())
+            .collect();
+        for i in 0..hashes.len() {
+            for j in i + 1..hashes.len() {
+                assert_ne!(hashes[i], hashes[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sighash_ignores_script_sigs() {
+        let transaction = create_transaction(2, 2);
+        let mut signed_transaction = transaction.clone();
+        signed_transaction.inputs[1].script_sig = Script::from(b"signature".to_vec());
+        for sighash_type in ALL_TYPES {
+            assert_eq!(signature_hash(&transaction, 0, &Script::new(), sighash_type), signature_hash(&signed_transaction, 0, &Script::new(), sighash_type));
+        }
+    }
+
+    #[test]
+    fn test_sighash_all_commits_to_everything() {
+        let transaction = create_transaction(2, 2);
+        let hash = signature_hash(&transaction, 0, &Script::new(), SigHashType::All).unwrap();
+
+        let mut modified = transaction.clone();
+        modified.outputs[1].value += 1;
+        assert_ne!(signature_hash(&modified, 0, &Script::new(), SigHashType::All).unwrap(), hash);
+        let mut modified = transaction.clone();
+        modified.inputs[1].sequence = 0;
+        assert_ne!(signature_hash(&modified, 0, &Script::new(), SigHashType::All).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_sighash_none_allows_changing_outputs() {
+        let transaction = create_transaction(2, 2);
+        let hash = signature_hash(&transaction, 0, &Script::new(), SigHashType::None).unwrap();
+
+        let mut modified = transaction.clone();
+        modified.outputs[0].value += 1;
+        modified.outputs.pop();
+        modified.inputs[1].sequence = 5;
+        assert_eq!(signature_hash(&modified, 0, &Script::new(), SigHashType::None).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_sighash_single_commits_to_matching_output() {
+        let transaction = create_transaction(2, 2);
+        let hash = signature_hash(&transaction, 1, &Script::new(), SigHashType::Single).unwrap();
+
+        let mut modified = transaction.clone();
+        modified.outputs[0].value += 1;
+        assert_eq!(signature_hash(&modified, 1, &Script::new(), SigHashType::Single).unwrap(), hash);
+        modified.outputs[1].value += 1;
+        assert_ne!(signature_hash(&modified, 1, &Script::new(), SigHashType::Single).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_sighash_single_without_output() {
+        let transaction = create_transaction(2, 1);
+        assert_eq!(signature_hash(&transaction, 1, &Script::new(), SigHashType::Single), Err(SigHashError::MissingSingleOutput(1)));
+    }
+
+    #[test]
+    fn test_sighash_anyone_can_pay_allows_adding_inputs() {
+        let transaction = create_transaction(1, 1);
+        let hash = signature_hash(&transaction, 0, &Script::new(), SigHashType::AllPlusAnyoneCanPay).unwrap();
+
+        let mut modified = transaction.clone();
+        modified.inputs.push(create_transaction(2, 0).inputs[1].clone());
+        modified.input_count = 2;
+        assert_eq!(signature_hash(&modified, 0, &Script::new(), SigHashType::AllPlusAnyoneCanPay).unwrap(), hash);
+        assert_ne!(signature_hash(&modified, 0, &Script::new(), SigHashType::All).unwrap(), signature_hash(&transaction, 0, &Script::new(), SigHashType::All).unwrap());
+    }
+
+    #[test]
+    fn test_sighash_commits_to_script_code() {
+        let transaction = create_transaction(1, 1);
+        let script_code = Script::from(vec![0x51]);
+        assert_ne!(signature_hash(&transaction, 0, &script_code, SigHashType::All), signature_hash(&transaction, 0, &Script::new(), SigHashType::All));
+    }
+
+    #[test]
+    fn test_sighash_input_out_of_range() {
+        let transaction = create_transaction(1, 1);
+        assert_eq!(signature_hash(&transaction, 1, &Script::new(), SigHashType::All), Err(SigHashError::InputIndexOutOfRange(1)));
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet};
+
+use secp256k1::hashes::sha256;
+
+use crate::constants::{BLOCK_DOWNLOAD_TIMEOUT_MS, BLOCK_DOWNLOAD_WINDOW, MAX_BLOCKS_IN_FLIGHT_PER_PEER};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::chain::ChainError;
+use crate::core::params::ChainParams;
+use crate::core::pow::{block_work, check_header_proof_of_work};
+use crate::core::transaction::calculate_merkle_root;
+use crate::core::validation::{check_block_timestamp, ValidationError};
+use crate::network::transport::PeerId;
+use crate::utils::u256::U256;
+
+/// Header stored in the header tree
+#[derive(Debug, Clone)]
+struct HeaderEntry {
+    header: BlockHeader,
+    height: u64,
+    /// Total work of the header chain ending with this header
+    chain_work: U256,
+    /// Set when the block of this header (or of one of its ancestors) failed validation
+    invalid: bool,
+}
+
+/// Block requested from a peer
+#[derive(Debug, Clone, Copy)]
+struct BlockRequest {
+    peer: PeerId,
+    /// Time (ms) when the block was requested
+    requested_at: u128,
+}
+
+/// State of the headers-first block download.
+/// Headers received from peers are validated (chain link, proof of work and timestamp) before their blocks
+/// are downloaded, the header chain with the most work decides which blocks the node asks for.
+/// Blocks are requested from every peer that has them, at most `MAX_BLOCKS_IN_FLIGHT_PER_PEER` per peer
+/// and at most `BLOCK_DOWNLOAD_WINDOW` blocks after the first missing one, and handed out in the order of the chain
+#[derive(Debug)]
+pub struct BlockSync {
+    params: ChainParams,
+    headers: HashMap<sha256::Hash, HeaderEntry>,
+    /// Hashes of the header chain with the most work, indexed by height
+    best_chain: Vec<sha256::Hash>,
+    /// Height of the first block of the best chain that may not be stored yet
+    next_height: usize,
+    /// Height of the best block of each peer, as far as the node knows
+    peers: HashMap<PeerId, u64>,
+    in_flight: HashMap<sha256::Hash, BlockRequest>,
+    /// Blocks received ahead of their parents, waiting to be connected
+    downloaded: HashMap<sha256::Hash, Block>,
+}
+
+impl BlockSync {
+    /// Creates the download state of a node whose active chain has `active_headers`
+    pub fn new(params: ChainParams, active_headers: &[BlockHeader]) -> BlockSync {
+        let mut sync = BlockSync {
+            params,
+            headers: HashMap::new(),
+            best_chain: vec![],
+            next_height: 0,
+            peers: HashMap::new(),
+            in_flight: HashMap::new(),
+            downloaded: HashMap::new(),
+        };
+        for header in active_headers {
+            sync.insert(header.clone());
+        }
+        sync
+    }
+
+    /// Height of the best header chain
+    pub fn best_height(&self) -> u64 {
+        self.best_chain.len().saturating_sub(1) as u64
+    }
+
+    /// Hashes describing the best header chain for a `getheaders` request: the last 10 blocks,
+    /// then exponentially fewer going back, always ending with the genesis block
+    pub fn locator(&self) -> Vec<sha256::Hash> {
+        let mut locator = vec![];
+        let mut height = self.best_chain.len().checked_sub(1);
+        let mut step = 1;
+        while let Some(current) = height {
+            locator.push(self.best_chain[current]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = match current.checked_sub(step) {
+                Some(next) => Some(next),
+                None if current > 0 => Some(0),
+                None => None,
+            };
+        }
+        locator
+    }
+
+    /// Registers a peer whose best block is at `height`
+    pub fn add_peer(&mut self, peer: PeerId, height: u64) {
+        let known_height = self.peers.entry(peer).or_default();
+        *known_height = (*known_height).max(height);
+    }
+
+    /// Forgets a peer, its pending requests go to other peers
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+        self.in_flight.retain(|_, request| request.peer != peer);
+    }
+
+    /// Adds the headers sent by `peer` at `now` (ms), they have to form a chain starting at a known header.
+    /// Returns the number of headers that weren't known before. Headers before an invalid one are kept
+    pub fn add_headers(&mut self, peer: PeerId, headers: &[BlockHeader], now: u128) -> Result<usize, ChainError> {
+        let Some(first) = headers.first() else {
+            return Ok(0);
+        };
+        let mut previous_headers = match first.previous_block_hash {
+            Some(previous) if !self.headers.contains_key(&previous) => return Err(ChainError::UnknownParent(previous)),
+            Some(previous) => self.branch_headers(&previous),
+            None => vec![],
+        };
+        let mut added = 0;
+        for header in headers {
+            let hash = header.hash();
+            let expected = previous_headers.last().map(BlockHeader::hash);
+            if header.previous_block_hash != expected {
+                let error = ValidationError::BadPreviousBlock { expected, found: header.previous_block_hash };
+                return Err(ChainError::InvalidBlock { hash, error });
+            }
+            if !self.headers.contains_key(&hash) {
+                match header.previous_block_hash {
+                    Some(previous) if self.headers[&previous].invalid => return Err(ChainError::InvalidParent(previous)),
+                    Some(_) => {}
+                    // the genesis block is always known, this header starts another chain
+                    None => return Err(ChainError::UnexpectedGenesis),
+                }
+                check_header_proof_of_work(header, &previous_headers, &self.params)
+                    .and_then(|_| check_block_timestamp(header, &previous_headers, now))
+                    .map_err(|error| ChainError::InvalidBlock { hash, error })?;
+                self.insert(header.clone());
+                added += 1;
+            }
+            previous_headers.push(header.clone());
+        }
+        self.add_peer(peer, previous_headers.len() as u64 - 1);
+        Ok(added)
+    }
+
+    /// Adds the header of a block connected to the active chain, the block tree already validated it
+    pub fn add_connected_header(&mut self, header: &BlockHeader) {
+        let hash = header.hash();
+        let parent_known = header.previous_block_hash.is_none_or(|previous| self.headers.contains_key(&previous));
+        if parent_known && !self.headers.contains_key(&hash) {
+            self.insert(header.clone());
+        }
+    }
+
+    /// Marks the block `hash` and all its descendants invalid. If the block was in the best header chain,
+    /// the valid header with the most work becomes the tip of the best chain
+    pub fn invalidate(&mut self, hash: &sha256::Hash) {
+        let Some(entry) = self.headers.get(hash) else { return };
+        let height = entry.height;
+        // going up by height, every parent is visited before its children
+        let mut later_headers: Vec<(u64, sha256::Hash)> = self.headers.iter()
+            .filter(|(_, entry)| entry.height > height)
+            .map(|(hash, entry)| (entry.height, *hash))
+            .collect();
+        later_headers.sort_by_key(|(height, _)| *height);
+        let mut invalid = HashSet::from([*hash]);
+        for (_, later) in later_headers {
+            if self.headers[&later].header.previous_block_hash.is_some_and(|previous| invalid.contains(&previous)) {
+                invalid.insert(later);
+            }
+        }
+        for hash in &invalid {
+            if let Some(entry) = self.headers.get_mut(hash) {
+                entry.invalid = true;
+            }
+            self.in_flight.remove(hash);
+            self.downloaded.remove(hash);
+        }
+
+        if !self.is_best(hash, height) {
+            return;
+        }
+        self.best_chain.truncate(height as usize);
+        self.next_height = self.next_height.min(height as usize);
+        let mut best = self.best_chain.last().copied();
+        for (hash, entry) in &self.headers {
+            if !entry.invalid && best.is_none_or(|best| entry.chain_work > self.headers[&best].chain_work) {
+                best = Some(*hash);
+            }
+        }
+        if let Some(best) = best {
+            self.set_best(best);
+        }
+    }
+
+    /// Blocks of the best header chain to request at `now` (ms), grouped by the peer to request them from.
+    /// `is_stored` tells which blocks the node already has. Requests older than `BLOCK_DOWNLOAD_TIMEOUT_MS`
+    /// are given up, the least busy peer that has a block is asked for it
+    pub fn request_blocks<F: Fn(&sha256::Hash) -> bool>(&mut self, is_stored: F, now: u128) -> Vec<(PeerId, Vec<sha256::Hash>)> {
+        self.in_flight.retain(|_, request| now.saturating_sub(request.requested_at) < BLOCK_DOWNLOAD_TIMEOUT_MS);
+        self.advance(&is_stored);
+        let mut load: HashMap<PeerId, usize> = self.peers.keys().map(|peer| (*peer, 0)).collect();
+        for request in self.in_flight.values() {
+            *load.entry(request.peer).or_default() += 1;
+        }
+
+        let mut requests: HashMap<PeerId, Vec<sha256::Hash>> = HashMap::new();
+        let end = self.best_chain.len().min(self.next_height + BLOCK_DOWNLOAD_WINDOW);
+        for height in self.next_height..end {
+            let hash = self.best_chain[height];
+            if is_stored(&hash) || self.downloaded.contains_key(&hash) || self.in_flight.contains_key(&hash) {
+                continue;
+            }
+            let peer = self.peers.iter()
+                .filter(|(peer, peer_height)| **peer_height >= height as u64 && load[peer] < MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+                .min_by_key(|(peer, _)| load[peer])
+                .map(|(peer, _)| *peer);
+            // peers that have later blocks are busy as well
+            let Some(peer) = peer else { break };
+            *load.get_mut(&peer).unwrap() += 1;
+            self.in_flight.insert(hash, BlockRequest { peer, requested_at: now });
+            requests.entry(peer).or_default().push(hash);
+        }
+        requests.into_iter().collect()
+    }
+
+    /// Takes a received block if it belongs to the download window of the best header chain.
+    /// Returns false for other blocks, which the node handles like any new block
+    pub fn block_received(&mut self, block: &Block) -> Result<bool, ValidationError> {
+        let hash = block.hash_block();
+        self.in_flight.remove(&hash);
+        let Some(entry) = self.headers.get(&hash) else {
+            return Ok(false);
+        };
+        if !self.is_best(&hash, entry.height) || entry.height as usize >= self.next_height + BLOCK_DOWNLOAD_WINDOW {
+            return Ok(false);
+        }
+        // transactions that don't match the header would get a valid block hash marked invalid
+        if block.transactions.is_empty() {
+            return Err(ValidationError::MissingCoinbase);
+        }
+        if calculate_merkle_root(&block.transactions) != block.header.merkle_root {
+            return Err(ValidationError::BadMerkleRoot);
+        }
+        self.downloaded.insert(hash, block.clone());
+        Ok(true)
+    }
+
+    /// Next downloaded block to connect, the first block of the best header chain that isn't stored yet
+    pub fn next_block<F: Fn(&sha256::Hash) -> bool>(&mut self, is_stored: F) -> Option<Block> {
+        self.advance(&is_stored);
+        let hash = self.best_chain.get(self.next_height)?;
+        self.downloaded.remove(hash)
+    }
+
+    /// Skips the blocks of the best header chain the node already has
+    fn advance<F: Fn(&sha256::Hash) -> bool>(&mut self, is_stored: &F) {
+        while let Some(hash) = self.best_chain.get(self.next_height) {
+            if !is_stored(hash) {
+                break;
+            }
+            self.downloaded.remove(hash);
+            self.next_height += 1;
+        }
+    }
+
+    fn is_best(&self, hash: &sha256::Hash, height: u64) -> bool {
+        self.best_chain.get(height as usize) == Some(hash)
+    }
+
+    /// Headers of the branch ending with `hash`, from the genesis block to that header
+    fn branch_headers(&self, hash: &sha256::Hash) -> Vec<BlockHeader> {
+        let mut headers = vec![];
+        let mut current = self.headers.get(hash);
+        while let Some(entry) = current {
+            headers.push(entry.header.clone());
+            current = entry.header.previous_block_hash.and_then(|previous| self.headers.get(&previous));
+        }
+        headers.reverse();
+        headers
+    }
+
+    /// Adds a header whose parent is known (or the genesis header),
+    /// its branch becomes the best header chain if it has more work
+    fn insert(&mut self, header: BlockHeader) {
+        let hash = header.hash();
+        let (height, parent_work) = match header.previous_block_hash.and_then(|previous| self.headers.get(&previous)) {
+            Some(parent) => (parent.height + 1, parent.chain_work),
+            None => (0, U256::ZERO),
+        };
+        let chain_work = parent_work + block_work(header.difficulty_target);
+        self.headers.insert(hash, HeaderEntry { header, height, chain_work, invalid: false });
+        let best_work = self.best_chain.last().map_or(U256::ZERO, |best| self.headers[best].chain_work);
+        if chain_work > best_work {
+            self.set_best(hash);
+        }
+    }
+
+    /// Makes the branch ending with `hash` the best header chain
+    fn set_best(&mut self, hash: sha256::Hash) {
+        // headers of the new branch that are not in the best chain, from the tip back to the fork point
+        let mut branch = vec![];
+        let mut current = Some(hash);
+        while let Some(hash) = current {
+            let entry = &self.headers[&hash];
+            if self.is_best(&hash, entry.height) {
+                break;
+            }
+            branch.push(hash);
+            current = entry.header.previous_block_hash;
+        }
+        let fork_height = branch.last().map_or(self.best_chain.len(), |first| self.headers[first].height as usize);
+        self.best_chain.truncate(fork_height);
+        self.best_chain.extend(branch.into_iter().rev());
+        self.next_height = self.next_height.min(fork_height);
+        // blocks downloaded for the replaced branch won't be connected
+        let (best_chain, headers) = (&self.best_chain, &self.headers);
+        self.downloaded.retain(|hash, _| best_chain.get(headers[hash].height as usize) == Some(hash));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::core::test_utils::mine_blocks;
+    use crate::utils::time::get_current_timestamp_ms;
+
+    /// Mines `count` blocks on top of `previous_headers`
+    fn headers_of(blocks: &[Block]) -> Vec<BlockHeader> {
+        blocks.iter().map(|block| block.header.clone()).collect()
+    }
+
+    /// Sync state of a node that only has the genesis block
+    fn new_sync(params: &ChainParams) -> BlockSync {
+        BlockSync::new(params.clone(), &[params.genesis_block().header])
+    }
+
+    #[test]
+    fn test_headers_extend_best_chain() {
+        let params = ChainParams::regtest();
+        let mut sync = new_sync(&params);
+        let blocks = mine_blocks(&params, &[params.genesis_block().header], 12);
+        let now = get_current_timestamp_ms();
+
+        assert_eq!(sync.add_headers(1, &headers_of(&blocks[..5]), now), Ok(5));
+        assert_eq!(sync.add_headers(1, &headers_of(&blocks), now), Ok(7));
+        assert_eq!(sync.add_headers(1, &headers_of(&blocks[3..]), now), Ok(0));
+        assert_eq!(sync.best_height(), 12);
+
+        // the last 10 blocks, then every other block going back, ending with the genesis block
+        let hashes: Vec<sha256::Hash> = blocks.iter().map(Block::hash_block).collect();
+        let mut locator: Vec<sha256::Hash> = hashes[2..].iter().rev().copied().collect();
+        locator.push(hashes[0]);
+        locator.push(params.genesis_hash());
+        assert_eq!(sync.locator(), locator);
+    }
+
+    #[test]
+    fn test_invalid_headers_are_rejected() {
+        let params = ChainParams::regtest();
+        let mut sync = new_sync(&params);
+        let blocks = mine_blocks(&params, &[params.genesis_block().header], 3);
+        let headers = headers_of(&blocks);
+        let now = get_current_timestamp_ms();
+
+        assert_eq!(sync.add_headers(1, &headers[1..], now), Err(ChainError::UnknownParent(blocks[0].hash_block())));
+        let error = ValidationError::BadPreviousBlock { expected: Some(blocks[0].hash_block()), found: Some(blocks[1].hash_block()) };
+        assert_eq!(sync.add_headers(1, &[headers[0].clone(), headers[2].clone()], now), Err(ChainError::InvalidBlock { hash: blocks[2].hash_block(), error }));
+        // the valid header before the unconnected one is kept
+        assert_eq!(sync.best_height(), 1);
+
+        let mut lowballed = headers[1].clone();
+        lowballed.difficulty_target = params.pow_limit_bits + 1;
+        let error = ValidationError::BadDifficultyTarget { expected: headers[1].difficulty_target, found: lowballed.difficulty_target };
+        assert_eq!(sync.add_headers(1, std::slice::from_ref(&lowballed), now), Err(ChainError::InvalidBlock { hash: lowballed.hash(), error }));
+
+        let other_genesis = mine_blocks(&params, &[], 1);
+        assert_eq!(sync.add_headers(1, &headers_of(&other_genesis), now), Err(ChainError::UnexpectedGenesis));
+        assert_eq!(sync.best_height(), 1);
+    }
+
+    #[test]
+    fn test_heavier_fork_replaces_best_chain() {
+        let params = ChainParams::regtest();
+        let genesis_header = params.genesis_block().header;
+        let mut sync = new_sync(&params);
+        let branch = mine_blocks(&params, std::slice::from_ref(&genesis_header), 2);
+        let fork = mine_blocks(&params, std::slice::from_ref(&genesis_header), 3);
+        let now = get_current_timestamp_ms();
+
+        sync.add_headers(1, &headers_of(&branch), now).unwrap();
+        assert_eq!(sync.block_received(&branch[1]), Ok(true));
+
+        sync.add_headers(2, &headers_of(&fork), now).unwrap();
+        assert_eq!(sync.best_height(), 3);
+        assert_eq!(sync.locator()[0], fork[2].hash_block());
+        // blocks of the replaced branch aren't connected
+        assert_eq!(sync.block_received(&branch[0]), Ok(false));
+        assert!(sync.downloaded.is_empty());
+    }
+
+    #[test]
+    fn test_blocks_are_downloaded_from_several_peers_and_connected_in_order() {
+        let params = ChainParams::regtest();
+        let mut sync = new_sync(&params);
+        let blocks = mine_blocks(&params, &[params.genesis_block().header], 20);
+        let now = get_current_timestamp_ms();
+        sync.add_headers(1, &headers_of(&blocks), now).unwrap();
+        sync.add_peer(2, 20);
+        // this peer doesn't have any of the blocks
+        sync.add_peer(3, 0);
+
+        let stored = RefCell::new(HashSet::from([params.genesis_hash()]));
+        let is_stored = |hash: &sha256::Hash| stored.borrow().contains(hash);
+        let requests = sync.request_blocks(is_stored, now);
+        let mut requested: Vec<sha256::Hash> = vec![];
+        for (peer, hashes) in &requests {
+            assert!(*peer == 1 || *peer == 2);
+            assert!(hashes.len() <= MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+            requested.extend(hashes);
+        }
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requested.len(), 20);
+        assert!(sync.request_blocks(is_stored, now).is_empty());
+
+        // blocks arrive in any order and are handed out in the order of the chain
+        for block in blocks.iter().rev() {
+            assert_eq!(sync.block_received(block), Ok(true));
+        }
+        for block in &blocks {
+            assert_eq!(sync.next_block(is_stored).as_ref(), Some(block));
+            stored.borrow_mut().insert(block.hash_block());
+        }
+        assert_eq!(sync.next_block(is_stored), None);
+    }
+
+    #[test]
+    fn test_lost_requests_are_reassigned() {
+        let params = ChainParams::regtest();
+        let mut sync = new_sync(&params);
+        let blocks = mine_blocks(&params, &[params.genesis_block().header], 3);
+        let hashes: Vec<sha256::Hash> = blocks.iter().map(Block::hash_block).collect();
+        let now = get_current_timestamp_ms();
+        let is_stored = |hash: &sha256::Hash| *hash == params.genesis_hash();
+        sync.add_headers(1, &headers_of(&blocks), now).unwrap();
+        assert_eq!(sync.request_blocks(is_stored, now), vec![(1, hashes.clone())]);
+
+        // the peer disconnects
+        sync.remove_peer(1);
+        sync.add_peer(2, 3);
+        assert_eq!(sync.request_blocks(is_stored, now), vec![(2, hashes.clone())]);
+        assert!(sync.request_blocks(is_stored, now + BLOCK_DOWNLOAD_TIMEOUT_MS - 1).is_empty());
+        // the peer doesn't answer in time
+        assert_eq!(sync.request_blocks(is_stored, now + BLOCK_DOWNLOAD_TIMEOUT_MS), vec![(2, hashes)]);
+    }
+
+    #[test]
+    fn test_invalid_block_leaves_best_chain() {
+        let params = ChainParams::regtest();
+        let mut sync = new_sync(&params);
+        let blocks = mine_blocks(&params, &[params.genesis_block().header], 3);
+        let headers = headers_of(&blocks);
+        let now = get_current_timestamp_ms();
+        sync.add_headers(1, &headers[..2], now).unwrap();
+
+        sync.invalidate(&blocks[1].hash_block());
+        assert_eq!(sync.best_height(), 1);
+        assert_eq!(sync.add_headers(1, &headers[2..], now), Err(ChainError::InvalidParent(blocks[1].hash_block())));
+        assert_eq!(sync.add_headers(1, &headers, now), Err(ChainError::InvalidParent(blocks[1].hash_block())));
+    }
+
+    #[test]
+    fn test_invalid_block_switches_to_best_valid_branch() {
+        let params = ChainParams::regtest();
+        let genesis_header = params.genesis_block().header;
+        let mut sync = new_sync(&params);
+        let blocks = mine_blocks(&params, std::slice::from_ref(&genesis_header), 3);
+        let fork = mine_blocks(&params, &[genesis_header, blocks[0].header.clone()], 2);
+        let now = get_current_timestamp_ms();
+        sync.add_headers(1, &headers_of(&blocks), now).unwrap();
+        sync.add_headers(2, &headers_of(&fork[..1]), now).unwrap();
+        assert_eq!(sync.best_height(), 3);
+
+        // the fork is the best valid branch left
+        sync.invalidate(&blocks[1].hash_block());
+        assert_eq!(sync.best_height(), 2);
+        assert_eq!(sync.locator()[0], fork[0].hash_block());
+        assert!(sync.headers[&blocks[2].hash_block()].invalid);
+
+        // descendants on side branches are invalid as well
+        sync.invalidate(&blocks[0].hash_block());
+        assert_eq!(sync.best_height(), 0);
+        assert!(sync.headers[&fork[0].hash_block()].invalid);
+        assert_eq!(sync.add_headers(2, &headers_of(&fork[1..]), now), Err(ChainError::InvalidParent(fork[0].hash_block())));
+    }
+
+    #[test]
+    fn test_block_with_mutated_transactions() {
+        let params = ChainParams::regtest();
+        let mut sync = new_sync(&params);
+        let blocks = mine_blocks(&params, &[params.genesis_block().header], 1);
+        sync.add_headers(1, &headers_of(&blocks), get_current_timestamp_ms()).unwrap();
+
+        let mut block = blocks[0].clone();
+        block.transactions.push(block.coinbase_transaction.clone());
+        assert_eq!(sync.block_received(&block), Err(ValidationError::BadMerkleRoot));
+        block.transactions.clear();
+        assert_eq!(sync.block_received(&block), Err(ValidationError::MissingCoinbase));
+        assert_eq!(sync.block_received(&blocks[0]), Ok(true));
+        // blocks without a known header are left to the node
+        let unknown_block = mine_blocks(&params, &headers_of(&blocks), 1).remove(0);
+        assert_eq!(sync.block_received(&unknown_block), Ok(false));
+    }
+}
@@ -0,0 +1,85 @@
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+use crate::constants::{COIN, TX_VERSION};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::block_template::BlockTemplate;
+use crate::core::chain::BlockTree;
+use crate::core::consensus::Node;
+use crate::core::mining::mine_block;
+use crate::core::params::ChainParams;
+use crate::core::script::Script;
+use crate::core::sighash::SigHashType;
+use crate::core::transaction::{p2pkh_script, Transaction, TransactionInput, TransactionOutput};
+use crate::core::utxo::{BlockUndo, OutPoint, UtxoSet};
+use crate::utils::wallets::generate_keypair;
+
+// value of the coinbase outputs created by `create_funded_utxo_set`
+pub const COINBASE_VALUE: u128 = 50 * COIN;
+
+/// UTXO set with `count` coinbase outputs owned by the returned key
+pub fn create_funded_utxo_set(count: u64) -> (UtxoSet, Vec<OutPoint>, SecretKey) {
+    let (secret_key, public_key) = generate_keypair();
+    let mut utxo_set = UtxoSet::new();
+    let mut outpoints = vec![];
+    for height in 0..count {
+        let coinbase = Transaction::new_coinbase_transaction(p2pkh_script(&public_key), public_key, height, COINBASE_VALUE);
+        utxo_set.apply_transaction(&coinbase, height, &mut BlockUndo::default()).unwrap();
+        outpoints.push(OutPoint { txid: coinbase.hash(), index: 0 });
+    }
+    (utxo_set, outpoints, secret_key)
+}
+
+/// Transaction spending `spent` (worth `input_value` each) into `output_count` outputs owned by `secret_key`
+pub fn create_spend(spent: &[OutPoint], input_value: u128, fee: u128, output_count: u32, secret_key: &SecretKey) -> Transaction {
+    let public_key = PublicKey::from_secret_key(&Secp256k1::new(), secret_key);
+    let script_pub_key = p2pkh_script(&public_key);
+    let output_value = (input_value * spent.len() as u128 - fee) / output_count as u128;
+    let mut transaction = Transaction {
+        transaction_version: TX_VERSION,
+        input_count: spent.len() as u32,
+        inputs: spent.iter().map(|outpoint| TransactionInput {
+            previous_transaction_hash: outpoint.txid,
+            previous_transaction_index: outpoint.index,
+            script_length: 0,
+            script_sig: Script::new(),
+            sequence: u32::MAX,
+        }).collect(),
+        output_count,
+        outputs: (0..output_count).map(|_| TransactionOutput {
+            value: output_value,
+            script_length: script_pub_key.len() as u32,
+            script_pub_key: script_pub_key.clone(),
+            recipient_pub_key: public_key,
+        }).collect(),
+        lock_time: 0,
+    };
+    for index in 0..spent.len() {
+        transaction.sign_input(index, secret_key, &script_pub_key, SigHashType::All).unwrap();
+    }
+    transaction
+}
+
+/// Node keeping its chain in memory, starting with the genesis block of `params`
+pub fn create_node(id: u32, params: ChainParams) -> Node {
+    let mut chain = BlockTree::new(params.clone());
+    chain.accept_block(params.genesis_block()).expect("genesis block is valid");
+    Node::new(id, params, chain)
+}
+
+/// Mines a block without a parent paying `miner_pub_key`, for chains that don't start with a network genesis block
+pub fn mine_genesis_block(miner_pub_key: PublicKey, params: &ChainParams) -> Block {
+    mine_block(BlockTemplate::new(miner_pub_key, &[], vec![], 0, params).block, || false).expect("mining is never cancelled")
+}
+
+/// Mines `count` blocks without transactions, each on top of the previous one starting from `previous_headers`
+pub fn mine_blocks(params: &ChainParams, previous_headers: &[BlockHeader], count: usize) -> Vec<Block> {
+    let (_, pub_key) = generate_keypair();
+    let mut headers = previous_headers.to_vec();
+    let mut blocks = vec![];
+    for _ in 0..count {
+        let block = mine_block(BlockTemplate::new(pub_key, &headers, vec![], 0, params).block, || false).unwrap();
+        headers.push(block.header.clone());
+        blocks.push(block);
+    }
+    blocks
+}
@@ -1,12 +1,22 @@
 use std::fmt;
 
-use secp256k1::hashes::sha256;
+use secp256k1::hashes::{sha256, Hash};
 use secp256k1::PublicKey;
+#[cfg(test)]
+use secp256k1::{Secp256k1, SecretKey};
 
-use crate::constants::{COINBASE_VALUE, TX_VERSION};
-use crate::utils::hash::sha256_hash;
+use crate::constants::{MAX_BIP125_RBF_SEQUENCE, TX_VERSION};
+use crate::core::script::{verify_script, Script, TransactionSignatureChecker};
+#[cfg(test)]
+use crate::core::sighash::{signature_hash, SigHashError, SigHashType};
+use crate::core::serialize::{write_vec, DecodeError, Decodable, Encodable, Reader};
+use crate::core::utxo::OutPoint;
+use crate::utils::hash::{hash256, Hasher};
+use crate::utils::hash::hash160;
+#[cfg(test)]
+use crate::utils::wallets::sign_hash;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Transaction {
     /// The version of the transaction
     pub transaction_version: u32,
@@ -23,16 +33,28 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    pub fn new_coinbase_transaction(script_pub_key: String, recipient_pub_key: PublicKey) -> Transaction {
+    /// Creates the coinbase transaction of the block at `height` paying `value` to the miner.
+    /// It has a single input spending the null outpoint, with the height in its script_sig
+    /// so that coinbase transactions of different blocks never have the same id (as in BIP34)
+    pub fn new_coinbase_transaction(script_pub_key: Script, recipient_pub_key: PublicKey, height: u64, value: u128) -> Transaction {
+        let script_sig = coinbase_script_sig(height);
         Transaction {
             transaction_version: TX_VERSION,
-            input_count: 0,
-            inputs: vec![],
+            input_count: 1,
+            inputs: vec![
+                TransactionInput {
+                    previous_transaction_hash: OutPoint::null().txid,
+                    previous_transaction_index: OutPoint::null().index,
+                    script_length: script_sig.len() as u32,
+                    script_sig,
+                    sequence: u32::MAX,
+                }
+            ],
             output_count: 1,
             outputs: vec![
                 TransactionOutput {
-                    value: COINBASE_VALUE,
-                    script_length: 0,
+                    value,
+                    script_length: script_pub_key.len() as u32,
                     script_pub_key,
                     recipient_pub_key,
                 }
@@ -41,8 +63,85 @@ impl Transaction {
         }
     }
 
+    /// Coinbase transactions create new coins, their only input spends the null outpoint
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].outpoint().is_null()
+    }
+
+    /// True if any input opts in to replace-by-fee (BIP125)
+    pub fn signals_replacement(&self) -> bool {
+        self.inputs.iter().any(|input| input.sequence <= MAX_BIP125_RBF_SEQUENCE)
+    }
+
+    /// Double SHA-256 of the canonical transaction encoding (transaction id)
     pub fn hash(&self) -> sha256::Hash {
-        sha256_hash(self.to_string().as_str())
+        hash256(&self.encode())
+    }
+
+    /// Signature of input `index` for `script_code` (the script that will check it):
+    /// the DER signature followed by the sighash type byte, as pushed by script_sigs
+    #[cfg(test)]
+    pub fn signature_for_input(&self, index: usize, secret_key: &SecretKey, script_code: &Script, sighash_type: SigHashType) -> Result<Vec<u8>, SigHashError> {
+        let digest = signature_hash(self, index, script_code, sighash_type)?;
+        let mut signature = sign_hash(&digest, secret_key).serialize_der().to_vec();
+        signature.push(sighash_type.to_byte());
+        Ok(signature)
+    }
+
+    /// Signs input `index` which spends an output locked by `script_pub_key`
+    /// and sets its script_sig. Only P2PK and P2PKH outputs can be signed this way,
+    /// script_sigs for other templates are built from `signature_for_input`
+    #[cfg(test)]
+    pub fn sign_input(&mut self, index: usize, secret_key: &SecretKey, script_pub_key: &Script, sighash_type: SigHashType) -> Result<(), SigHashError> {
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), secret_key);
+        let mut script_sig = Script::new();
+        if script_pub_key.is_p2pk() {
+            script_sig.push_data(&self.signature_for_input(index, secret_key, script_pub_key, sighash_type)?);
+        } else if script_pub_key.is_p2pkh() {
+            script_sig.push_data(&self.signature_for_input(index, secret_key, script_pub_key, sighash_type)?)
+                .push_data(&public_key.serialize());
+        } else {
+            return Err(SigHashError::UnsupportedScript);
+        }
+        let input = &mut self.inputs[index];
+        input.script_length = script_sig.len() as u32;
+        input.script_sig = script_sig;
+        Ok(())
+    }
+
+    /// Checks that the script_sig of input `index` unlocks the spent output's `script_pub_key`
+    pub fn verify_input(&self, index: usize, script_pub_key: &Script) -> bool {
+        let Some(input) = self.inputs.get(index) else {
+            return false;
+        };
+        verify_script(&input.script_sig, script_pub_key, &TransactionSignatureChecker::new(self, index)).is_ok()
+    }
+}
+
+/// Transaction encoding: version (u32), CompactSize prefixed inputs and outputs, lock time (u32).
+/// The input and output counts are taken from the vectors, so they always match the encoded lists
+impl Encodable for Transaction {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(&self.transaction_version.to_le_bytes());
+        write_vec(writer, &self.inputs);
+        write_vec(writer, &self.outputs);
+        writer.extend_from_slice(&self.lock_time.to_le_bytes());
+    }
+}
+
+impl Decodable for Transaction {
+    fn decode_from(reader: &mut Reader) -> Result<Transaction, DecodeError> {
+        let transaction_version = reader.read_u32()?;
+        let inputs: Vec<TransactionInput> = reader.read_vec()?;
+        let outputs: Vec<TransactionOutput> = reader.read_vec()?;
+        Ok(Transaction {
+            transaction_version,
+            input_count: inputs.len() as u32,
+            inputs,
+            output_count: outputs.len() as u32,
+            outputs,
+            lock_time: reader.read_u32()?,
+        })
     }
 }
 
@@ -52,38 +151,109 @@ impl fmt::Display for Transaction {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TransactionInput {
     /// The hash of the previous transaction
-    pub previous_transaction_hash: String,
+    pub previous_transaction_hash: sha256::Hash,
     /// The index of the previous transaction
     pub previous_transaction_index: u32,
     /// The length of the scriptSig field
     pub script_length: u32,
-    /// The signature script
-    pub script_sig: String,
+    /// The script unlocking the spent output
+    pub script_sig: Script,
     /// Number that miners use for transaction blocking
-    /// (to prevent the same transaction from being included in the block multiple times)
+    /// (to prevent the same transaction from being included in the block multiple times),
+    /// values up to `MAX_BIP125_RBF_SEQUENCE` signal that the transaction can be replaced (BIP125)
     pub sequence: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TransactionOutput {
     /// The number of satoshis to be transfered (1 BTC = 10^9 satoshis)
     pub value: u128,
     /// The length of the scriptPubKey field
     pub script_length: u32,
-    /// The public key script
-    pub script_pub_key: String,
+    /// The script locking the output
+    pub script_pub_key: Script,
     /// The address of the recipient (public key hash)
     /// used to make the transaction more human-readable
     pub recipient_pub_key: PublicKey,
 }
 
+/// Input encoding: previous transaction hash (32 bytes), previous output index (u32),
+/// script_sig (var_bytes) and sequence (u32)
+impl Encodable for TransactionInput {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(self.previous_transaction_hash.as_byte_array());
+        writer.extend_from_slice(&self.previous_transaction_index.to_le_bytes());
+        self.script_sig.encode_to(writer);
+        writer.extend_from_slice(&self.sequence.to_le_bytes());
+    }
+}
+
+/// Script_sig of the coinbase input of the block at `height`: a push of the height
+pub fn coinbase_script_sig(height: u64) -> Script {
+    let mut script = Script::new();
+    script.push_int(height as i64);
+    script
+}
+
+/// Standard pay to public key hash script paying to `public_key`
+pub fn p2pkh_script(public_key: &PublicKey) -> Script {
+    Script::new_p2pkh(&hash160(&public_key.serialize()))
+}
+
+impl TransactionInput {
+    /// The output spent by this input
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint {
+            txid: self.previous_transaction_hash,
+            index: self.previous_transaction_index,
+        }
+    }
+}
+
+impl Decodable for TransactionInput {
+    fn decode_from(reader: &mut Reader) -> Result<TransactionInput, DecodeError> {
+        let previous_transaction_hash = reader.read_hash()?;
+        let previous_transaction_index = reader.read_u32()?;
+        let script_sig = Script::decode_from(reader)?;
+        Ok(TransactionInput {
+            previous_transaction_hash,
+            previous_transaction_index,
+            script_length: script_sig.len() as u32,
+            script_sig,
+            sequence: reader.read_u32()?,
+        })
+    }
+}
+
+/// Output encoding: value (u128), script_pub_key (var_bytes) and the compressed recipient public key (33 bytes)
+impl Encodable for TransactionOutput {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(&self.value.to_le_bytes());
+        self.script_pub_key.encode_to(writer);
+        writer.extend_from_slice(&self.recipient_pub_key.serialize());
+    }
+}
+
+impl Decodable for TransactionOutput {
+    fn decode_from(reader: &mut Reader) -> Result<TransactionOutput, DecodeError> {
+        let value = reader.read_u128()?;
+        let script_pub_key = Script::decode_from(reader)?;
+        Ok(TransactionOutput {
+            value,
+            script_length: script_pub_key.len() as u32,
+            script_pub_key,
+            recipient_pub_key: reader.read_public_key()?,
+        })
+    }
+}
 
 /// Calculates the merkle root of a list of transactions
 /// by hashing pairs of transaction hashes until only one hash remains
-pub fn calculate_merkle_root(transactions: &Vec<Transaction>) -> sha256::Hash {
+/// (each node is the double SHA-256 of the concatenated bytes of its children)
+pub fn calculate_merkle_root(transactions: &[Transaction]) -> sha256::Hash {
     let mut hashes: Vec<sha256::Hash> = transactions.iter().map(|transaction| transaction.hash()).collect();
     while hashes.len() > 1 {
         let mut new_hashes: Vec<sha256::Hash> = vec![];
@@ -94,8 +264,9 @@ pub fn calculate_merkle_root(transactions: &Vec<Transaction>) -> sha256::Hash {
             } else {
                 &hashes[i]
             };
-            let new_hash = sha256_hash(format!("{}{}", left, right).as_str());
-            new_hashes.push(new_hash);
+            let mut hasher = Hasher::new();
+            hasher.update(left.as_byte_array()).update(right.as_byte_array());
+            new_hashes.push(hasher.finalize_double());
         }
         hashes.clear();
         hashes.extend(new_hashes);
@@ -106,10 +277,13 @@ pub fn calculate_merkle_root(transactions: &Vec<Transaction>) -> sha256::Hash {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use secp256k1::hashes::Hash;
+    use crate::constants::COIN;
+    use crate::utils::wallets::generate_keypair;
     use secp256k1::Secp256k1;
     use secp256k1::rand::rngs::OsRng;
 
+    const COINBASE_VALUE: u128 = 50 * COIN;
+
     fn generate_public_key() -> PublicKey {
         let secp = Secp256k1::new();
         let (_, public_key) = secp.generate_keypair(&mut OsRng);
@@ -119,13 +293,14 @@ mod tests {
     #[test]
     fn test_new_coinbase_transaction() {
         let pub_key = generate_public_key();
-        let script_pub_key = "76a914...88ac".to_string(); // Pseudo scriptPubKey
+        let script_pub_key = p2pkh_script(&pub_key);
 
-        let tx = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key.clone());
+        let tx = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key, 0, COINBASE_VALUE);
 
         assert_eq!(tx.transaction_version, TX_VERSION);
-        assert_eq!(tx.input_count, 0);
-        assert_eq!(tx.inputs.len(), 0);
+        assert!(tx.is_coinbase());
+        assert_eq!(tx.input_count, 1);
+        assert_eq!(tx.inputs.len(), 1);
         assert_eq!(tx.output_count, 1);
         assert_eq!(tx.outputs.len(), 1);
         assert_eq!(tx.outputs[0].value, COINBASE_VALUE);
@@ -136,9 +311,9 @@ mod tests {
     #[test]
     fn test_transaction_hash() {
         let pub_key = generate_public_key();
-        let script_pub_key = "76a914...88ac".to_string();
+        let script_pub_key = p2pkh_script(&pub_key);
 
-        let tx = Transaction::new_coinbase_transaction(script_pub_key, pub_key);
+        let tx = Transaction::new_coinbase_transaction(script_pub_key, pub_key, 0, COINBASE_VALUE);
 
         // check if the hash is 32 bytes long
         let hash = tx.hash();
@@ -148,12 +323,12 @@ mod tests {
     #[test]
     fn test_calculate_merkle_root() {
         let pub_key = generate_public_key();
-        let script_pub_key = "76a914...88ac".to_string();
+        let script_pub_key = p2pkh_script(&pub_key);
 
         // create 3 coinbase transactions
-        let tx1 = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key.clone());
-        let tx2 = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key.clone());
-        let tx3 = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key.clone());
+        let tx1 = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key, 1, COINBASE_VALUE);
+        let tx2 = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key, 2, COINBASE_VALUE);
+        let tx3 = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key, 3, COINBASE_VALUE);
 
         let transactions = vec![tx1, tx2, tx3];
         let merkle_root = calculate_merkle_root(&transactions);
@@ -161,4 +336,159 @@ mod tests {
         // check if the merkle root is 32 bytes long
         assert_eq!(merkle_root.as_byte_array().len(), 32);  // 64 hex characters = 32 bytes
     }
+
+    #[test]
+    fn test_merkle_root_hashes_raw_bytes() {
+        let tx1 = create_dummy_transaction();
+        let tx2 = create_dummy_transaction();
+        let mut concatenated = tx1.hash().to_byte_array().to_vec();
+        concatenated.extend_from_slice(tx2.hash().as_byte_array());
+
+        assert_eq!(calculate_merkle_root(std::slice::from_ref(&tx1)), tx1.hash());
+        assert_eq!(calculate_merkle_root(&[tx1, tx2]), hash256(&concatenated));
+    }
+
+    #[test]
+    fn test_signals_replacement() {
+        let mut tx = create_dummy_transaction();
+        assert!(!tx.signals_replacement());
+        tx.inputs[0].sequence = MAX_BIP125_RBF_SEQUENCE + 1;
+        assert!(!tx.signals_replacement());
+        tx.inputs[0].sequence = MAX_BIP125_RBF_SEQUENCE;
+        assert!(tx.signals_replacement());
+    }
+
+    #[test]
+    fn test_coinbase_transactions_are_unique_per_height() {
+        let pub_key = generate_public_key();
+        let script_pub_key = p2pkh_script(&pub_key);
+
+        let tx1 = Transaction::new_coinbase_transaction(script_pub_key.clone(), pub_key, 1, COINBASE_VALUE);
+        let tx2 = Transaction::new_coinbase_transaction(script_pub_key, pub_key, 2, COINBASE_VALUE);
+
+        assert_ne!(tx1.hash(), tx2.hash());
+        assert!(!create_dummy_transaction().is_coinbase());
+    }
+
+    fn create_dummy_transaction() -> Transaction {
+        let pub_key = generate_public_key();
+        let input = TransactionInput {
+            previous_transaction_hash: hash256(b"previous_transaction"),
+            previous_transaction_index: 1,
+            script_length: 12,
+            script_sig: Script::from(b"dummy_script".to_vec()),
+            sequence: u32::MAX,
+        };
+        let output = TransactionOutput {
+            value: 1_000,
+            script_length: 25,
+            script_pub_key: p2pkh_script(&pub_key),
+            recipient_pub_key: pub_key,
+        };
+        Transaction {
+            transaction_version: TX_VERSION,
+            input_count: 1,
+            inputs: vec![input],
+            output_count: 2,
+            outputs: vec![output.clone(), output],
+            lock_time: 7,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify_p2pkh_input() {
+        let (secret_key, public_key) = generate_keypair();
+        let spent_script_pub_key = p2pkh_script(&public_key);
+        let mut tx = create_dummy_transaction();
+        tx.sign_input(0, &secret_key, &spent_script_pub_key, SigHashType::All).unwrap();
+
+        assert!(tx.inputs[0].script_sig.is_push_only());
+        assert_eq!(tx.inputs[0].script_length as usize, tx.inputs[0].script_sig.len());
+        assert!(tx.verify_input(0, &spent_script_pub_key));
+        assert!(!tx.verify_input(0, &p2pkh_script(&generate_public_key())));
+        assert!(!tx.verify_input(1, &spent_script_pub_key));
+
+        // changing a signed output invalidates the signature
+        tx.outputs[1].value += 1;
+        assert!(!tx.verify_input(0, &spent_script_pub_key));
+    }
+
+    #[test]
+    fn test_sign_p2pk_input_with_sighash_none() {
+        let (secret_key, public_key) = generate_keypair();
+        let spent_script_pub_key = Script::new_p2pk(&public_key);
+        let mut tx = create_dummy_transaction();
+        tx.sign_input(0, &secret_key, &spent_script_pub_key, SigHashType::NonePlusAnyoneCanPay).unwrap();
+
+        tx.outputs[1].value += 1;
+        assert!(tx.verify_input(0, &spent_script_pub_key));
+        // the sighash type itself is signed
+        let mut signature = tx.signature_for_input(0, &secret_key, &spent_script_pub_key, SigHashType::NonePlusAnyoneCanPay).unwrap();
+        *signature.last_mut().unwrap() = SigHashType::None.to_byte();
+        let mut script_sig = Script::new();
+        script_sig.push_data(&signature);
+        tx.inputs[0].script_sig = script_sig;
+        assert!(!tx.verify_input(0, &spent_script_pub_key));
+    }
+
+    #[test]
+    fn test_sign_input_errors() {
+        let (secret_key, public_key) = generate_keypair();
+        let spent_script_pub_key = p2pkh_script(&public_key);
+        let mut tx = create_dummy_transaction();
+        assert_eq!(tx.sign_input(1, &secret_key, &spent_script_pub_key, SigHashType::All), Err(SigHashError::InputIndexOutOfRange(1)));
+        assert_eq!(tx.sign_input(0, &secret_key, &Script::new_multisig(1, &[public_key]), SigHashType::All), Err(SigHashError::UnsupportedScript));
+        tx.outputs.clear();
+        assert_eq!(tx.sign_input(0, &secret_key, &spent_script_pub_key, SigHashType::Single), Err(SigHashError::MissingSingleOutput(0)));
+    }
+
+    #[test]
+    fn test_transaction_encoding_roundtrip() {
+        let tx = create_dummy_transaction();
+        let bytes = tx.encode();
+        assert_eq!(&bytes[..4], &TX_VERSION.to_le_bytes());
+        assert_eq!(&bytes[bytes.len() - 4..], &7u32.to_le_bytes());
+        assert_eq!(Transaction::decode(&bytes).unwrap(), tx);
+    }
+
+    #[test]
+    fn test_transaction_decoding_fills_counts() {
+        let mut tx = create_dummy_transaction();
+        tx.input_count = 0;
+        tx.outputs[0].script_length = 0;
+        let decoded_tx = Transaction::decode(&tx.encode()).unwrap();
+        assert_eq!(decoded_tx.input_count, 1);
+        assert_eq!(decoded_tx.output_count, 2);
+        assert_eq!(decoded_tx.outputs[0].script_length, 25);
+    }
+
+    #[test]
+    fn test_transaction_input_encoding() {
+        let input = create_dummy_transaction().inputs[0].clone();
+        let bytes = input.encode();
+        assert_eq!(bytes.len(), 32 + 4 + 1 + 12 + 4);
+        assert_eq!(TransactionInput::decode(&bytes).unwrap(), input);
+    }
+
+    #[test]
+    fn test_transaction_output_encoding() {
+        let output = create_dummy_transaction().outputs[0].clone();
+        let bytes = output.encode();
+        assert_eq!(&bytes[..16], &1_000u128.to_le_bytes());
+        assert_eq!(TransactionOutput::decode(&bytes).unwrap(), output);
+        // an invalid public key can't be decoded
+        let mut bytes = bytes;
+        let length = bytes.len();
+        bytes[length - 33] = 0x05;
+        assert_eq!(TransactionOutput::decode(&bytes), Err(DecodeError::InvalidPublicKey));
+    }
+
+    #[test]
+    fn test_transaction_hash_depends_on_encoding() {
+        let mut tx = create_dummy_transaction();
+        let hash = tx.hash();
+        assert_eq!(hash, hash256(&tx.encode()));
+        tx.lock_time += 1;
+        assert_ne!(tx.hash(), hash);
+    }
 }
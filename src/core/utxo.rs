@@ -0,0 +1,493 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use secp256k1::hashes::{sha256, Hash};
+
+use crate::core::block::Block;
+use crate::core::serialize::{write_vec, DecodeError, Decodable, Encodable, Reader};
+use crate::core::transaction::{Transaction, TransactionOutput};
+
+/// Reference to an output of a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    /// The hash of the transaction that created the output
+    pub txid: sha256::Hash,
+    /// The index of the output in the transaction
+    pub index: u32,
+}
+
+impl OutPoint {
+    /// Outpoint spent by the input of a coinbase transaction, it doesn't reference any real output
+    pub fn null() -> OutPoint {
+        OutPoint {
+            txid: sha256::Hash::all_zeros(),
+            index: u32::MAX,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::null()
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.index)
+    }
+}
+
+/// Unspent output together with the information needed to validate its spending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoEntry {
+    pub output: TransactionOutput,
+    /// Height of the block that created the output
+    pub height: u64,
+    /// Whether the output was created by a coinbase transaction
+    pub is_coinbase: bool,
+}
+
+/// Outputs spent by a block, in the order they were spent,
+/// needed to restore the UTXO set when the block is disconnected
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    pub spent_outputs: Vec<(OutPoint, UtxoEntry)>,
+}
+
+/// Outpoint encoding: txid followed by the output index (u32)
+impl Encodable for OutPoint {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(self.txid.as_byte_array());
+        writer.extend_from_slice(&self.index.to_le_bytes());
+    }
+}
+
+impl Decodable for OutPoint {
+    fn decode_from(reader: &mut Reader) -> Result<OutPoint, DecodeError> {
+        Ok(OutPoint { txid: reader.read_hash()?, index: reader.read_u32()? })
+    }
+}
+
+/// UTXO entry encoding: output, height (u64) and coinbase flag (u8)
+impl Encodable for UtxoEntry {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        self.output.encode_to(writer);
+        writer.extend_from_slice(&self.height.to_le_bytes());
+        writer.push(self.is_coinbase as u8);
+    }
+}
+
+impl Decodable for UtxoEntry {
+    fn decode_from(reader: &mut Reader) -> Result<UtxoEntry, DecodeError> {
+        Ok(UtxoEntry {
+            output: TransactionOutput::decode_from(reader)?,
+            height: reader.read_u64()?,
+            is_coinbase: reader.read_bool()?,
+        })
+    }
+}
+
+/// Block undo encoding: CompactSize prefixed list of spent outpoints with their entries
+impl Encodable for BlockUndo {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        write_vec(writer, &self.spent_outputs);
+    }
+}
+
+impl Decodable for BlockUndo {
+    fn decode_from(reader: &mut Reader) -> Result<BlockUndo, DecodeError> {
+        Ok(BlockUndo { spent_outputs: reader.read_vec()? })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtxoError {
+    /// The spent output doesn't exist or was already spent
+    MissingOutput(OutPoint),
+    /// The output already exists in the set (e.g. two identical transactions)
+    DuplicateOutput(OutPoint),
+    /// The same output is spent twice by one transaction
+    DuplicateInput(OutPoint),
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UtxoError::MissingOutput(outpoint) => write!(f, "output {} is missing or already spent", outpoint),
+            UtxoError::DuplicateOutput(outpoint) => write!(f, "output {} already exists", outpoint),
+            UtxoError::DuplicateInput(outpoint) => write!(f, "output {} is spent twice", outpoint),
+        }
+    }
+}
+
+/// Set of all unspent transaction outputs, keyed by outpoint
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, UtxoEntry>,
+}
+
+impl UtxoSet {
+    pub fn new() -> UtxoSet {
+        UtxoSet::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoEntry> {
+        self.outputs.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.outputs.contains_key(outpoint)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &UtxoEntry)> {
+        self.outputs.iter()
+    }
+
+    /// Adds an entry without any checks, used to load a stored set
+    pub fn insert(&mut self, outpoint: OutPoint, entry: UtxoEntry) {
+        self.outputs.insert(outpoint, entry);
+    }
+
+    /// Removes an entry without any checks, used to load a stored set
+    pub fn remove(&mut self, outpoint: &OutPoint) -> Option<UtxoEntry> {
+        self.outputs.remove(outpoint)
+    }
+
+    /// Applies a transaction: its inputs are removed from the set (and recorded in `undo`)
+    /// and its outputs are added. The set is left untouched if the transaction can't be applied
+    pub fn apply_transaction(&mut self, transaction: &Transaction, height: u64, undo: &mut BlockUndo) -> Result<(), UtxoError> {
+        check_applies(transaction, |outpoint| self.contains(outpoint))?;
+        let txid = transaction.hash();
+        let is_coinbase = transaction.is_coinbase();
+        let spent_inputs = if is_coinbase { &[] } else { transaction.inputs.as_slice() };
+        for input in spent_inputs {
+            let outpoint = input.outpoint();
+            let entry = self.outputs.remove(&outpoint).unwrap();
+            undo.spent_outputs.push((outpoint, entry));
+        }
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            let outpoint = OutPoint { txid, index: index as u32 };
+            self.outputs.insert(outpoint, UtxoEntry { output: output.clone(), height, is_coinbase });
+        }
+        Ok(())
+    }
+
+    /// Applies all transactions of a block at the given height and returns the data needed to undo it.
+    /// If any transaction can't be applied, the set is restored to its state before the block
+    pub fn apply_block(&mut self, block: &Block, height: u64) -> Result<BlockUndo, UtxoError> {
+        let mut undo = BlockUndo::default();
+        for (i, transaction) in block.transactions.iter().enumerate() {
+            if let Err(error) = self.apply_transaction(transaction, height, &mut undo) {
+                self.undo_transactions(&block.transactions[..i], undo);
+                return Err(error);
+            }
+        }
+        Ok(undo)
+    }
+
+    /// Rolls back a block applied with `apply_block`: removes the outputs it created
+    /// and restores the outputs it spent
+    pub fn undo_block(&mut self, block: &Block, undo: BlockUndo) -> Result<(), UtxoError> {
+        // outputs created by the block are either still unspent or were spent later in the same block
+        let spent_in_block: HashSet<OutPoint> = undo.spent_outputs.iter().map(|(outpoint, _)| *outpoint).collect();
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            for index in 0..transaction.outputs.len() as u32 {
+                let outpoint = OutPoint { txid, index };
+                if !self.contains(&outpoint) && !spent_in_block.contains(&outpoint) {
+                    return Err(UtxoError::MissingOutput(outpoint));
+                }
+            }
+        }
+        self.undo_transactions(&block.transactions, undo);
+        Ok(())
+    }
+
+    /// Undoes transactions in reverse order, so outputs created and spent within the same block
+    /// are removed again after being restored
+    fn undo_transactions(&mut self, transactions: &[Transaction], mut undo: BlockUndo) {
+        for transaction in transactions.iter().rev() {
+            let txid = transaction.hash();
+            for index in 0..transaction.outputs.len() as u32 {
+                self.outputs.remove(&OutPoint { txid, index });
+            }
+            if transaction.is_coinbase() {
+                continue;
+            }
+            for _ in &transaction.inputs {
+                if let Some((outpoint, entry)) = undo.spent_outputs.pop() {
+                    self.outputs.insert(outpoint, entry);
+                }
+            }
+        }
+    }
+}
+
+/// Checks that a transaction can be applied to a set containing the outpoints for which `contains` is true:
+/// its inputs exist and are spent once, its outputs don't exist yet
+fn check_applies<F: Fn(&OutPoint) -> bool>(transaction: &Transaction, contains: F) -> Result<(), UtxoError> {
+    let mut spent_outpoints = HashSet::new();
+    if !transaction.is_coinbase() {
+        for input in &transaction.inputs {
+            let outpoint = input.outpoint();
+            if !spent_outpoints.insert(outpoint) {
+                return Err(UtxoError::DuplicateInput(outpoint));
+            }
+            if !contains(&outpoint) {
+                return Err(UtxoError::MissingOutput(outpoint));
+            }
+        }
+    }
+    let txid = transaction.hash();
+    for index in 0..transaction.outputs.len() as u32 {
+        let outpoint = OutPoint { txid, index };
+        if contains(&outpoint) {
+            return Err(UtxoError::DuplicateOutput(outpoint));
+        }
+    }
+    Ok(())
+}
+
+/// Transactions applied on top of a UTXO set without changing it, used to validate a block
+/// without copying the whole set
+#[derive(Debug)]
+pub struct UtxoOverlay<'a> {
+    base: &'a UtxoSet,
+    /// Outputs created on top of the base set and still unspent
+    added: HashMap<OutPoint, UtxoEntry>,
+    /// Outputs of the base set spent on top of it
+    spent: HashSet<OutPoint>,
+}
+
+impl<'a> UtxoOverlay<'a> {
+    pub fn new(base: &'a UtxoSet) -> UtxoOverlay<'a> {
+        UtxoOverlay { base, added: HashMap::new(), spent: HashSet::new() }
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoEntry> {
+        if let Some(entry) = self.added.get(outpoint) {
+            return Some(entry);
+        }
+        if self.spent.contains(outpoint) {
+            return None;
+        }
+        self.base.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.get(outpoint).is_some()
+    }
+
+    /// Same as `UtxoSet::apply_transaction`, the overlay is left untouched if the transaction can't be applied
+    pub fn apply_transaction(&mut self, transaction: &Transaction, height: u64) -> Result<(), UtxoError> {
+        check_applies(transaction, |outpoint| self.contains(outpoint))?;
+        let txid = transaction.hash();
+        let is_coinbase = transaction.is_coinbase();
+        if !is_coinbase {
+            for input in &transaction.inputs {
+                let outpoint = input.outpoint();
+                if self.added.remove(&outpoint).is_none() {
+                    self.spent.insert(outpoint);
+                }
+            }
+        }
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            let outpoint = OutPoint { txid, index: index as u32 };
+            self.added.insert(outpoint, UtxoEntry { output: output.clone(), height, is_coinbase });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::PublicKey;
+    use crate::constants::{COIN, TX_VERSION};
+    use crate::core::test_utils::mine_genesis_block;
+    use crate::core::params::ChainParams;
+    use crate::core::script::Script;
+    use crate::core::transaction::TransactionInput;
+    use crate::utils::wallets::generate_keypair;
+
+    const COINBASE_VALUE: u128 = 50 * COIN;
+
+    fn create_spending_transaction(spent: &[OutPoint], value: u128, recipient_pub_key: PublicKey) -> Transaction {
+        let inputs: Vec<TransactionInput> = spent.iter().map(|outpoint| TransactionInput {
+            previous_transaction_hash: outpoint.txid,
+            previous_transaction_index: outpoint.index,
+            script_length: 0,
+            script_sig: Script::new(),
+            sequence: u32::MAX,
+        }).collect();
+        Transaction {
+            transaction_version: TX_VERSION,
+            input_count: inputs.len() as u32,
+            inputs,
+            output_count: 2,
+            outputs: vec![
+                TransactionOutput { value: value / 2, script_length: 0, script_pub_key: Script::new(), recipient_pub_key },
+                TransactionOutput { value: value / 2, script_length: 0, script_pub_key: Script::new(), recipient_pub_key },
+            ],
+            lock_time: 0,
+        }
+    }
+
+    fn create_block(transactions: Vec<Transaction>) -> Block {
+        let (_, pub_key) = generate_keypair();
+        let mut block = mine_genesis_block(pub_key, &ChainParams::regtest());
+        block.transactions.extend(transactions);
+        block
+    }
+
+    fn coinbase_outpoint(block: &Block) -> OutPoint {
+        OutPoint { txid: block.transactions[0].hash(), index: 0 }
+    }
+
+    #[test]
+    fn test_null_outpoint() {
+        assert!(OutPoint::null().is_null());
+        assert!(!OutPoint { txid: sha256::Hash::all_zeros(), index: 0 }.is_null());
+    }
+
+    #[test]
+    fn test_apply_coinbase_block() {
+        let mut utxo_set = UtxoSet::new();
+        let block = create_block(vec![]);
+        let undo = utxo_set.apply_block(&block, 0).unwrap();
+
+        assert!(undo.spent_outputs.is_empty());
+        assert_eq!(utxo_set.len(), 1);
+        let entry = utxo_set.get(&coinbase_outpoint(&block)).unwrap();
+        assert_eq!(entry.output.value, COINBASE_VALUE);
+        assert_eq!(entry.height, 0);
+        assert!(entry.is_coinbase);
+    }
+
+    #[test]
+    fn test_apply_and_undo_spending_block() {
+        let mut utxo_set = UtxoSet::new();
+        let first_block = create_block(vec![]);
+        utxo_set.apply_block(&first_block, 0).unwrap();
+
+        let (_, recipient) = generate_keypair();
+        let spend = create_spending_transaction(&[coinbase_outpoint(&first_block)], COINBASE_VALUE, recipient);
+        let second_block = create_block(vec![spend.clone()]);
+        let undo = utxo_set.apply_block(&second_block, 1).unwrap();
+
+        assert!(!utxo_set.contains(&coinbase_outpoint(&first_block)));
+        assert!(utxo_set.contains(&OutPoint { txid: spend.hash(), index: 1 }));
+        assert!(!utxo_set.get(&OutPoint { txid: spend.hash(), index: 0 }).unwrap().is_coinbase);
+        assert_eq!(utxo_set.len(), 3);
+        assert_eq!(undo.spent_outputs.len(), 1);
+
+        utxo_set.undo_block(&second_block, undo).unwrap();
+        assert_eq!(utxo_set.len(), 1);
+        assert!(utxo_set.contains(&coinbase_outpoint(&first_block)));
+    }
+
+    #[test]
+    fn test_spending_missing_output() {
+        let mut utxo_set = UtxoSet::new();
+        let (_, recipient) = generate_keypair();
+        let missing = OutPoint { txid: crate::utils::hash::hash256(b"missing"), index: 0 };
+        let block = create_block(vec![create_spending_transaction(&[missing], 10, recipient)]);
+
+        assert_eq!(utxo_set.apply_block(&block, 0), Err(UtxoError::MissingOutput(missing)));
+        // the coinbase applied before the failing transaction is rolled back
+        assert!(utxo_set.is_empty());
+    }
+
+    #[test]
+    fn test_double_spend_in_block() {
+        let mut utxo_set = UtxoSet::new();
+        let first_block = create_block(vec![]);
+        utxo_set.apply_block(&first_block, 0).unwrap();
+
+        let (_, recipient) = generate_keypair();
+        let (_, other_recipient) = generate_keypair();
+        let spent = coinbase_outpoint(&first_block);
+        let second_block = create_block(vec![
+            create_spending_transaction(&[spent], COINBASE_VALUE, recipient),
+            create_spending_transaction(&[spent], COINBASE_VALUE, other_recipient),
+        ]);
+
+        assert_eq!(utxo_set.apply_block(&second_block, 1), Err(UtxoError::MissingOutput(spent)));
+        assert_eq!(utxo_set.len(), 1);
+        assert!(utxo_set.contains(&spent));
+    }
+
+    #[test]
+    fn test_duplicate_input() {
+        let mut utxo_set = UtxoSet::new();
+        let first_block = create_block(vec![]);
+        utxo_set.apply_block(&first_block, 0).unwrap();
+
+        let (_, recipient) = generate_keypair();
+        let spent = coinbase_outpoint(&first_block);
+        let block = create_block(vec![create_spending_transaction(&[spent, spent], COINBASE_VALUE, recipient)]);
+
+        assert_eq!(utxo_set.apply_block(&block, 1), Err(UtxoError::DuplicateInput(spent)));
+        assert_eq!(utxo_set.len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_output() {
+        let mut utxo_set = UtxoSet::new();
+        let block = create_block(vec![]);
+        utxo_set.apply_block(&block, 0).unwrap();
+
+        assert_eq!(utxo_set.apply_block(&block, 1), Err(UtxoError::DuplicateOutput(coinbase_outpoint(&block))));
+        assert_eq!(utxo_set.len(), 1);
+    }
+
+    #[test]
+    fn test_spend_output_created_in_same_block() {
+        let mut utxo_set = UtxoSet::new();
+        let first_block = create_block(vec![]);
+        utxo_set.apply_block(&first_block, 0).unwrap();
+
+        let (_, recipient) = generate_keypair();
+        let parent = create_spending_transaction(&[coinbase_outpoint(&first_block)], COINBASE_VALUE, recipient);
+        let child = create_spending_transaction(&[OutPoint { txid: parent.hash(), index: 0 }], COINBASE_VALUE / 2, recipient);
+        let second_block = create_block(vec![parent, child]);
+
+        let undo = utxo_set.apply_block(&second_block, 1).unwrap();
+        assert_eq!(undo.spent_outputs.len(), 2);
+        utxo_set.undo_block(&second_block, undo).unwrap();
+        assert_eq!(utxo_set.len(), 1);
+        assert!(utxo_set.contains(&coinbase_outpoint(&first_block)));
+    }
+
+    #[test]
+    fn test_overlay_leaves_base_untouched() {
+        let mut utxo_set = UtxoSet::new();
+        let first_block = create_block(vec![]);
+        utxo_set.apply_block(&first_block, 0).unwrap();
+
+        let (_, recipient) = generate_keypair();
+        let spent = coinbase_outpoint(&first_block);
+        let parent = create_spending_transaction(&[spent], COINBASE_VALUE, recipient);
+        let created = OutPoint { txid: parent.hash(), index: 0 };
+        let child = create_spending_transaction(&[created], COINBASE_VALUE / 2, recipient);
+
+        let mut overlay = UtxoOverlay::new(&utxo_set);
+        overlay.apply_transaction(&parent, 1).unwrap();
+        assert!(!overlay.contains(&spent) && overlay.contains(&created));
+        assert_eq!(overlay.apply_transaction(&parent, 1), Err(UtxoError::MissingOutput(spent)));
+        overlay.apply_transaction(&child, 1).unwrap();
+        assert!(!overlay.contains(&created));
+        assert_eq!(overlay.get(&OutPoint { txid: child.hash(), index: 1 }).unwrap().height, 1);
+
+        assert_eq!(utxo_set.len(), 1);
+        assert!(utxo_set.contains(&spent));
+    }
+}
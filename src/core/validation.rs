@@ -0,0 +1,484 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use secp256k1::hashes::sha256;
+
+use crate::constants::{MAX_BLOCK_SIZE, MAX_FUTURE_BLOCK_TIME_MS, MAX_MONEY, MEDIAN_TIME_SPAN};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::params::ChainParams;
+use crate::core::transaction::{coinbase_script_sig, Transaction, TransactionOutput};
+use crate::core::utxo::{OutPoint, UtxoError};
+#[cfg(test)]
+use crate::core::utxo::UtxoSet;
+
+/// Reason why a block or a transaction is invalid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The encoded block is larger than `MAX_BLOCK_SIZE`
+    BlockTooLarge(usize),
+    /// The first block of the chain isn't the genesis block of the network
+    BadGenesisBlock(sha256::Hash),
+    /// The block doesn't point to the last block of the chain
+    BadPreviousBlock { expected: Option<sha256::Hash>, found: Option<sha256::Hash> },
+    BadMerkleRoot,
+    /// The timestamp isn't later than the median time past
+    TimestampTooOld { timestamp: u128, median_time_past: u128 },
+    /// The timestamp is more than `MAX_FUTURE_BLOCK_TIME_MS` ahead of the local clock
+    TimestampTooNew { timestamp: u128, max_timestamp: u128 },
+    /// The declared difficulty target isn't the one expected by the chain
+    BadDifficultyTarget { expected: u32, found: u32 },
+    /// The block hash doesn't satisfy the difficulty target
+    BadProofOfWork,
+    /// The declared input or output count doesn't match the list
+    BadTransactionCounts(sha256::Hash),
+    NoInputs(sha256::Hash),
+    NoOutputs(sha256::Hash),
+    /// A value (or a sum of values) is out of the allowed money range
+    ValueOutOfRange(sha256::Hash),
+    /// The output is spent twice, by one transaction or by two transactions of the block
+    DoubleSpend(OutPoint),
+    /// The spent output doesn't exist or was spent by an earlier block
+    MissingInput(OutPoint),
+    /// The input script doesn't unlock the spent output
+    BadSignature { txid: sha256::Hash, input_index: usize },
+    /// The outputs are worth more than the inputs
+    InsufficientInputValue(sha256::Hash),
+    /// A coinbase transaction where a regular transaction is expected
+    UnexpectedCoinbase(sha256::Hash),
+    /// The first transaction of the block isn't a coinbase
+    MissingCoinbase,
+    MultipleCoinbases,
+    /// The first transaction differs from `Block::coinbase_transaction`
+    CoinbaseMismatch,
+    /// The coinbase script_sig doesn't hold the block height
+    BadCoinbaseHeight(u64),
+    /// The coinbase claims more than the block subsidy plus the fees
+    CoinbaseValueTooHigh { claimed: u128, allowed: u128 },
+    /// A transaction creates an output that already exists
+    DuplicateOutput(OutPoint),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::BlockTooLarge(size) => write!(f, "block of {} bytes is larger than {} bytes", size, MAX_BLOCK_SIZE),
+            ValidationError::BadGenesisBlock(hash) => write!(f, "unexpected genesis block {}", hash),
+            ValidationError::BadPreviousBlock { expected, found } => write!(f, "previous block is {:?}, expected {:?}", found, expected),
+            ValidationError::BadMerkleRoot => write!(f, "merkle root doesn't match the transactions"),
+            ValidationError::TimestampTooOld { timestamp, median_time_past } => {
+                write!(f, "timestamp {} isn't later than the median time past {}", timestamp, median_time_past)
+            }
+            ValidationError::TimestampTooNew { timestamp, max_timestamp } => write!(f, "timestamp {} is later than {}", timestamp, max_timestamp),
+            ValidationError::BadDifficultyTarget { expected, found } => write!(f, "difficulty target {:#010x}, expected {:#010x}", found, expected),
+            ValidationError::BadProofOfWork => write!(f, "block hash doesn't satisfy the difficulty target"),
+            ValidationError::BadTransactionCounts(txid) => write!(f, "transaction {} has wrong input or output counts", txid),
+            ValidationError::NoInputs(txid) => write!(f, "transaction {} has no inputs", txid),
+            ValidationError::NoOutputs(txid) => write!(f, "transaction {} has no outputs", txid),
+            ValidationError::ValueOutOfRange(txid) => write!(f, "transaction {} has a value out of range", txid),
+            ValidationError::DoubleSpend(outpoint) => write!(f, "output {} is spent twice", outpoint),
+            ValidationError::MissingInput(outpoint) => write!(f, "output {} is missing or already spent", outpoint),
+            ValidationError::BadSignature { txid, input_index } => write!(f, "input {} of transaction {} has an invalid signature", input_index, txid),
+            ValidationError::InsufficientInputValue(txid) => write!(f, "outputs of transaction {} are worth more than its inputs", txid),
+            ValidationError::UnexpectedCoinbase(txid) => write!(f, "unexpected coinbase transaction {}", txid),
+            ValidationError::MissingCoinbase => write!(f, "first transaction isn't a coinbase"),
+            ValidationError::MultipleCoinbases => write!(f, "more than one coinbase transaction"),
+            ValidationError::CoinbaseMismatch => write!(f, "coinbase transaction doesn't match the first transaction"),
+            ValidationError::BadCoinbaseHeight(height) => write!(f, "coinbase doesn't hold the block height {}", height),
+            ValidationError::CoinbaseValueTooHigh { claimed, allowed } => write!(f, "coinbase claims {}, only {} allowed", claimed, allowed),
+            ValidationError::DuplicateOutput(outpoint) => write!(f, "output {} already exists", outpoint),
+        }
+    }
+}
+
+impl From<UtxoError> for ValidationError {
+    fn from(error: UtxoError) -> ValidationError {
+        match error {
+            UtxoError::MissingOutput(outpoint) => ValidationError::MissingInput(outpoint),
+            UtxoError::DuplicateOutput(outpoint) => ValidationError::DuplicateOutput(outpoint),
+            UtxoError::DuplicateInput(outpoint) => ValidationError::DoubleSpend(outpoint),
+        }
+    }
+}
+
+/// Context-free transaction checks: the declared counts match the lists,
+/// there is at least one input and one output, no output is spent twice
+/// and no value (or sum of values) exceeds `MAX_MONEY`
+pub fn check_transaction(transaction: &Transaction) -> Result<(), ValidationError> {
+    let txid = transaction.hash();
+    if transaction.input_count as usize != transaction.inputs.len()
+        || transaction.output_count as usize != transaction.outputs.len()
+    {
+        return Err(ValidationError::BadTransactionCounts(txid));
+    }
+    if transaction.inputs.is_empty() {
+        return Err(ValidationError::NoInputs(txid));
+    }
+    if transaction.outputs.is_empty() {
+        return Err(ValidationError::NoOutputs(txid));
+    }
+    if total_output_value(transaction).is_none() {
+        return Err(ValidationError::ValueOutOfRange(txid));
+    }
+    let mut spent_outpoints = HashSet::new();
+    for input in &transaction.inputs {
+        if !spent_outpoints.insert(input.outpoint()) {
+            return Err(ValidationError::DoubleSpend(input.outpoint()));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the inputs of a (non coinbase) transaction against the UTXO set:
+/// every input spends an existing unspent output, is signed by the owner of that output
+/// and the inputs are worth at least as much as the outputs.
+/// Returns the transaction fee (inputs - outputs) if the transaction is valid
+#[cfg(test)]
+pub fn check_transaction_inputs(transaction: &Transaction, utxo_set: &UtxoSet) -> Result<u128, ValidationError> {
+    check_transaction_inputs_with(transaction, |outpoint| utxo_set.get(outpoint).map(|entry| entry.output.clone()))
+}
+
+/// Same as `check_transaction_inputs`, with the spent outputs looked up by `get_output`
+/// (e.g. the UTXO set extended with the outputs of unconfirmed transactions)
+pub fn check_transaction_inputs_with<F: Fn(&OutPoint) -> Option<TransactionOutput>>(transaction: &Transaction, get_output: F) -> Result<u128, ValidationError> {
+    let txid = transaction.hash();
+    if transaction.is_coinbase() {
+        return Err(ValidationError::UnexpectedCoinbase(txid));
+    }
+    let mut input_value: u128 = 0;
+    for (index, input) in transaction.inputs.iter().enumerate() {
+        let outpoint = input.outpoint();
+        let output = get_output(&outpoint).ok_or(ValidationError::MissingInput(outpoint))?;
+        if !transaction.verify_input(index, &output.script_pub_key) {
+            return Err(ValidationError::BadSignature { txid, input_index: index });
+        }
+        input_value = checked_add_money(input_value, output.value).ok_or(ValidationError::ValueOutOfRange(txid))?;
+    }
+    let output_value = total_output_value(transaction).ok_or(ValidationError::ValueOutOfRange(txid))?;
+    input_value.checked_sub(output_value).ok_or(ValidationError::InsufficientInputValue(txid))
+}
+
+/// New coins created by the block at `height`: the reward starts at `initial_subsidy`
+/// and is halved every `halving_interval` blocks until it reaches zero
+pub fn block_subsidy(height: u64, params: &ChainParams) -> u128 {
+    let halvings = height / params.halving_interval;
+    if halvings >= 128 {
+        return 0;
+    }
+    params.initial_subsidy >> halvings
+}
+
+/// Coinbase rules of a block at `height` whose other transactions pay `fees` in total:
+/// the first transaction (and only the first one) is a coinbase, it's the same as `Block::coinbase_transaction`,
+/// its script_sig holds the block height and it claims at most the block subsidy plus the fees
+pub fn check_coinbase(block: &Block, height: u64, fees: u128, params: &ChainParams) -> Result<(), ValidationError> {
+    let coinbase = match block.transactions.first() {
+        Some(coinbase) if coinbase.is_coinbase() => coinbase,
+        _ => return Err(ValidationError::MissingCoinbase),
+    };
+    if *coinbase != block.coinbase_transaction {
+        return Err(ValidationError::CoinbaseMismatch);
+    }
+    if block.transactions.iter().skip(1).any(|transaction| transaction.is_coinbase()) {
+        return Err(ValidationError::MultipleCoinbases);
+    }
+    if coinbase.inputs[0].script_sig != coinbase_script_sig(height) {
+        return Err(ValidationError::BadCoinbaseHeight(height));
+    }
+    let claimed = total_output_value(coinbase).ok_or(ValidationError::ValueOutOfRange(coinbase.hash()))?;
+    let allowed = fees.checked_add(block_subsidy(height, params)).ok_or(ValidationError::ValueOutOfRange(coinbase.hash()))?;
+    if claimed > allowed {
+        return Err(ValidationError::CoinbaseValueTooHigh { claimed, allowed });
+    }
+    Ok(())
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` headers (0 for an empty chain)
+pub fn median_time_past(previous_headers: &[BlockHeader]) -> u128 {
+    let start = previous_headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<u128> = previous_headers[start..].iter().map(|header| header.timestamp).collect();
+    if timestamps.is_empty() {
+        return 0;
+    }
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+/// Timestamp rules of a block on top of `previous_headers`: it's later than the median time past
+/// and at most `MAX_FUTURE_BLOCK_TIME_MS` ahead of `now`
+pub fn check_block_timestamp(header: &BlockHeader, previous_headers: &[BlockHeader], now: u128) -> Result<(), ValidationError> {
+    let median_time_past = median_time_past(previous_headers);
+    if header.timestamp <= median_time_past {
+        return Err(ValidationError::TimestampTooOld { timestamp: header.timestamp, median_time_past });
+    }
+    let max_timestamp = now + MAX_FUTURE_BLOCK_TIME_MS;
+    if header.timestamp > max_timestamp {
+        return Err(ValidationError::TimestampTooNew { timestamp: header.timestamp, max_timestamp });
+    }
+    Ok(())
+}
+
+/// Sum of the transaction outputs, None if any value is out of the allowed money range
+pub fn total_output_value(transaction: &Transaction) -> Option<u128> {
+    transaction.outputs.iter().try_fold(0, |total, output| checked_add_money(total, output.value))
+}
+
+fn checked_add_money(total: u128, value: u128) -> Option<u128> {
+    if value > MAX_MONEY {
+        return None;
+    }
+    total.checked_add(value).filter(|sum| *sum <= MAX_MONEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use crate::constants::{SOFTWARE_VERSION, TX_VERSION};
+    use crate::core::test_utils::{create_funded_utxo_set, mine_genesis_block, COINBASE_VALUE};
+    use crate::core::script::Script;
+    use crate::core::transaction::{p2pkh_script, TransactionInput};
+    use crate::core::sighash::SigHashType;
+    use crate::utils::hash::sha256_hash;
+    use crate::utils::wallets::generate_keypair;
+
+    fn create_signed_transaction(spent: OutPoint, value: u128, secret_key: &SecretKey, recipient_pub_key: PublicKey) -> Transaction {
+        let mut transaction = Transaction {
+            transaction_version: TX_VERSION,
+            input_count: 1,
+            inputs: vec![TransactionInput {
+                previous_transaction_hash: spent.txid,
+                previous_transaction_index: spent.index,
+                script_length: 0,
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+            }],
+            output_count: 1,
+            outputs: vec![TransactionOutput {
+                value,
+                script_length: 0,
+                script_pub_key: p2pkh_script(&recipient_pub_key),
+                recipient_pub_key,
+            }],
+            lock_time: 0,
+        };
+        let spent_script_pub_key = p2pkh_script(&PublicKey::from_secret_key(&Secp256k1::new(), secret_key));
+        transaction.sign_input(0, secret_key, &spent_script_pub_key, SigHashType::All).unwrap();
+        transaction
+    }
+
+    fn create_block_with_coinbase(height: u64, value: u128) -> Block {
+        let (_, public_key) = generate_keypair();
+        let coinbase = Transaction::new_coinbase_transaction(p2pkh_script(&public_key), public_key, height, value);
+        let mut block = mine_genesis_block(public_key, &ChainParams::regtest());
+        block.transactions = vec![coinbase.clone()];
+        block.coinbase_transaction = coinbase;
+        block
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let params = ChainParams::main();
+        let (subsidy, interval) = (params.initial_subsidy, params.halving_interval);
+        assert_eq!(block_subsidy(0, &params), subsidy);
+        assert_eq!(block_subsidy(interval - 1, &params), subsidy);
+        assert_eq!(block_subsidy(interval, &params), subsidy / 2);
+        assert_eq!(block_subsidy(2 * interval, &params), subsidy / 4);
+        assert_eq!(block_subsidy(64 * interval, &params), 0);
+        assert_eq!(block_subsidy(u64::MAX, &params), 0);
+        // regtest halves much sooner
+        assert_eq!(block_subsidy(150, &ChainParams::regtest()), subsidy / 2);
+    }
+
+    #[test]
+    fn test_total_subsidy_is_below_max_money() {
+        let params = ChainParams::main();
+        let interval = params.halving_interval;
+        let total: u128 = (0..64).map(|halving| block_subsidy(halving * interval, &params) * interval as u128).sum();
+        assert!(total <= MAX_MONEY);
+    }
+
+    #[test]
+    fn test_valid_coinbase() {
+        let block = create_block_with_coinbase(5, COINBASE_VALUE + 100);
+        assert_eq!(check_coinbase(&block, 5, 100, &ChainParams::main()), Ok(()));
+        // claiming less than allowed is fine
+        assert_eq!(check_coinbase(&block, 5, 200, &ChainParams::main()), Ok(()));
+    }
+
+    #[test]
+    fn test_coinbase_claims_too_much() {
+        let block = create_block_with_coinbase(5, COINBASE_VALUE + 100);
+        assert_eq!(check_coinbase(&block, 5, 99, &ChainParams::main()), Err(ValidationError::CoinbaseValueTooHigh { claimed: COINBASE_VALUE + 100, allowed: COINBASE_VALUE + 99 }));
+        let interval = ChainParams::main().halving_interval;
+        let block = create_block_with_coinbase(interval, COINBASE_VALUE);
+        assert_eq!(check_coinbase(&block, interval, 0, &ChainParams::main()), Err(ValidationError::CoinbaseValueTooHigh { claimed: COINBASE_VALUE, allowed: COINBASE_VALUE / 2 }));
+    }
+
+    #[test]
+    fn test_coinbase_wrong_height() {
+        let block = create_block_with_coinbase(5, COINBASE_VALUE);
+        assert_eq!(check_coinbase(&block, 6, 0, &ChainParams::main()), Err(ValidationError::BadCoinbaseHeight(6)));
+    }
+
+    #[test]
+    fn test_coinbase_mismatch() {
+        let mut block = create_block_with_coinbase(5, COINBASE_VALUE);
+        block.coinbase_transaction.outputs[0].value -= 1;
+        assert_eq!(check_coinbase(&block, 5, 0, &ChainParams::main()), Err(ValidationError::CoinbaseMismatch));
+    }
+
+    #[test]
+    fn test_coinbase_position() {
+        let (_, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let transaction = create_signed_transaction(spent, 1_000, &secret_key, recipient);
+
+        // the coinbase isn't the first transaction
+        let mut block = create_block_with_coinbase(5, COINBASE_VALUE);
+        block.transactions.insert(0, transaction);
+        assert_eq!(check_coinbase(&block, 5, 0, &ChainParams::main()), Err(ValidationError::MissingCoinbase));
+
+        // two coinbase transactions
+        let mut block = create_block_with_coinbase(5, COINBASE_VALUE);
+        block.transactions.push(block.coinbase_transaction.clone());
+        assert_eq!(check_coinbase(&block, 5, 0, &ChainParams::main()), Err(ValidationError::MultipleCoinbases));
+    }
+
+    #[test]
+    fn test_valid_transaction() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let transaction = create_signed_transaction(spent, COINBASE_VALUE - 1_000, &secret_key, recipient);
+
+        assert_eq!(check_transaction(&transaction), Ok(()));
+        assert_eq!(check_transaction_inputs(&transaction, &utxo_set), Ok(1_000));
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let (_, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let transaction = create_signed_transaction(spent, 1_000, &secret_key, recipient);
+
+        assert_eq!(check_transaction_inputs(&transaction, &UtxoSet::new()), Err(ValidationError::MissingInput(spent)));
+    }
+
+    #[test]
+    fn test_outputs_exceed_inputs() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let transaction = create_signed_transaction(spent, COINBASE_VALUE + 1, &secret_key, recipient);
+
+        assert_eq!(check_transaction_inputs(&transaction, &utxo_set), Err(ValidationError::InsufficientInputValue(transaction.hash())));
+    }
+
+    #[test]
+    fn test_wrong_signer() {
+        let (utxo_set, outpoints, _) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (other_secret_key, recipient) = generate_keypair();
+        let transaction = create_signed_transaction(spent, 1_000, &other_secret_key, recipient);
+
+        assert_eq!(check_transaction_inputs(&transaction, &utxo_set), Err(ValidationError::BadSignature { txid: transaction.hash(), input_index: 0 }));
+    }
+
+    #[test]
+    fn test_modified_after_signing() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let mut transaction = create_signed_transaction(spent, 1_000, &secret_key, recipient);
+        transaction.outputs[0].value = 2_000;
+
+        assert_eq!(check_transaction_inputs(&transaction, &utxo_set), Err(ValidationError::BadSignature { txid: transaction.hash(), input_index: 0 }));
+    }
+
+    #[test]
+    fn test_missing_signature() {
+        let (utxo_set, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let mut transaction = create_signed_transaction(spent, 1_000, &secret_key, recipient);
+        transaction.inputs[0].script_sig = Script::new();
+
+        assert_eq!(check_transaction_inputs(&transaction, &utxo_set), Err(ValidationError::BadSignature { txid: transaction.hash(), input_index: 0 }));
+    }
+
+    #[test]
+    fn test_count_mismatch() {
+        let (_, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let mut transaction = create_signed_transaction(spent, 1_000, &secret_key, recipient);
+        transaction.output_count = 2;
+
+        assert_eq!(check_transaction(&transaction), Err(ValidationError::BadTransactionCounts(transaction.hash())));
+    }
+
+    #[test]
+    fn test_duplicate_inputs() {
+        let (_, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let mut transaction = create_signed_transaction(spent, 1_000, &secret_key, recipient);
+        transaction.inputs.push(transaction.inputs[0].clone());
+        transaction.input_count = 2;
+
+        assert_eq!(check_transaction(&transaction), Err(ValidationError::DoubleSpend(spent)));
+    }
+
+    #[test]
+    fn test_value_out_of_range() {
+        let (_, outpoints, secret_key) = create_funded_utxo_set(1);
+        let spent = outpoints[0];
+        let (_, recipient) = generate_keypair();
+        let mut transaction = create_signed_transaction(spent, MAX_MONEY + 1, &secret_key, recipient);
+        assert_eq!(check_transaction(&transaction), Err(ValidationError::ValueOutOfRange(transaction.hash())));
+
+        // each value is in range, but the sum isn't
+        transaction.outputs[0].value = MAX_MONEY;
+        transaction.outputs.push(transaction.outputs[0].clone());
+        transaction.output_count = 2;
+        assert_eq!(check_transaction(&transaction), Err(ValidationError::ValueOutOfRange(transaction.hash())));
+
+        transaction.outputs[0].value = u128::MAX;
+        assert_eq!(check_transaction(&transaction), Err(ValidationError::ValueOutOfRange(transaction.hash())));
+    }
+
+    fn create_header(timestamp: u128) -> BlockHeader {
+        BlockHeader {
+            software_version: SOFTWARE_VERSION.to_string(),
+            previous_block_hash: None,
+            merkle_root: sha256_hash("dummy_merkle_root"),
+            timestamp,
+            difficulty_target: 0x1f00ffff,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(median_time_past(&[]), 0);
+        let headers: Vec<BlockHeader> = [5, 1, 4].into_iter().map(create_header).collect();
+        assert_eq!(median_time_past(&headers), 4);
+
+        // only the last MEDIAN_TIME_SPAN headers count
+        let headers: Vec<BlockHeader> = (0..20).map(|i| create_header(i * 10)).collect();
+        assert_eq!(median_time_past(&headers), 140);
+    }
+
+    #[test]
+    fn test_block_timestamp() {
+        let headers: Vec<BlockHeader> = (1..=11).map(|i| create_header(i * 1_000)).collect();
+        let now = 20_000;
+        let max_timestamp = now + MAX_FUTURE_BLOCK_TIME_MS;
+        assert_eq!(check_block_timestamp(&create_header(6_000), &headers, now), Err(ValidationError::TimestampTooOld { timestamp: 6_000, median_time_past: 6_000 }));
+        assert_eq!(check_block_timestamp(&create_header(6_001), &headers, now), Ok(()));
+        assert_eq!(check_block_timestamp(&create_header(max_timestamp), &headers, now), Ok(()));
+        assert_eq!(check_block_timestamp(&create_header(max_timestamp + 1), &headers, now), Err(ValidationError::TimestampTooNew { timestamp: max_timestamp + 1, max_timestamp }));
+    }
+}
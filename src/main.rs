@@ -1,39 +1,118 @@
 mod core;
 mod constants;
+mod network;
 mod utils;
 
 use rand::Rng;
 
-use core::{block::Block, consensus::Node};
-use std::{sync::{mpsc, Arc, Mutex}, time::Duration};
-use constants::{AVERAGE_BLOCK_TIME_MS, NUMBER_OF_NODES};
+use core::block_store::BlockStore;
+use core::chainstate::Chainstate;
+use core::chain::ChainError;
+use core::consensus::{Mining, Node};
+use core::params::{ChainParams, Network};
+use network::channel::ChannelTransport;
+use network::tcp::TcpTransport;
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::{mpsc, Arc}, time::Duration};
 
+/// Number of simulated nodes
+const NUMBER_OF_NODES: u32 = 5;
+
+/// Directory holding the data of every node, one subdirectory per network and node
+const DATA_DIR: &str = "data";
+
+const USAGE: &str = "usage: bitcoin-rust [main|testnet|regtest|<params file>] [--listen <addr>] [--connect <addr>]... [--mine]";
+
+/// Command line options
+struct Options {
+    params: ChainParams,
+    /// Address the node accepts peers on, the default port of the network on localhost if only `connect` is given
+    listen: Option<SocketAddr>,
+    /// Nodes to connect to on start
+    connect: Vec<SocketAddr>,
+    /// Whether the TCP node mines blocks
+    mine: bool,
+}
 
 fn main() {
-    multithreaded_blockchain();
-    
+    let options = parse_args();
+    // without any TCP option the nodes are simulated as threads of this process
+    if options.listen.is_none() && options.connect.is_empty() {
+        multithreaded_blockchain(options.params);
+    } else {
+        p2p_node(options);
+    }
+}
+
+fn parse_args() -> Options {
+    let exit_with_usage = |error: String| -> ! {
+        eprintln!("{}\n{}", error, USAGE);
+        std::process::exit(1);
+    };
+    let parse_addr = |value: Option<String>| -> SocketAddr {
+        let value = value.unwrap_or_else(|| exit_with_usage("missing address".to_string()));
+        value.parse().unwrap_or_else(|_| exit_with_usage(format!("invalid address {}", value)))
+    };
+
+    let mut options = Options { params: ChainParams::main(), listen: None, connect: vec![], mine: false };
+    let mut args = std::env::args().skip(1).peekable();
+    // the first argument is a network name (main, testnet, regtest) or the path of a custom params file
+    if let Some(arg) = args.next_if(|arg| !arg.starts_with("--")) {
+        options.params = match arg.parse::<Network>() {
+            Ok(network) => ChainParams::for_network(network),
+            Err(_) => ChainParams::from_file(Path::new(&arg)).unwrap_or_else(|error| {
+                eprintln!("Invalid chain params {}: {}", arg, error);
+                std::process::exit(1);
+            }),
+        };
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => options.listen = Some(parse_addr(args.next())),
+            "--connect" => options.connect.push(parse_addr(args.next())),
+            "--mine" => options.mine = true,
+            _ => exit_with_usage(format!("unknown option {}", arg)),
+        }
+    }
+    options
 }
 
-fn multithreaded_blockchain() {
+/// Runs a single node that talks to other `bitcoin-rust` processes over TCP
+fn p2p_node(options: Options) {
+    let params = options.params;
+    let listen = options.listen.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], params.default_port)));
+    // the port keeps the data of nodes running on the same machine apart
+    let node = Arc::new(open_node(listen.port() as u32, &params));
+    let transport = TcpTransport::new(params.magic);
+    match transport.listen(listen) {
+        Ok(addr) => println!("#{} node listening on {}", node.id, addr),
+        Err(error) => {
+            eprintln!("Can't listen on {}: {}", listen, error);
+            std::process::exit(1);
+        }
+    }
+    let mining = if options.mine { Mining::Continuous } else { Mining::Disabled };
+    let node_thread = Arc::clone(&node).start_node(Arc::clone(&transport) as _, mining);
+    for addr in options.connect {
+        if let Err(error) = transport.connect(addr) {
+            println!("Can't connect to {}: {}", addr, error);
+        }
+    }
+    node_thread.join().unwrap();
+}
+
+fn multithreaded_blockchain(params: ChainParams) {
     let mut tx_channels = vec![];
     let mut node_threads = vec![];
-    let tx_rx_channels = Arc::new(Mutex::new(vec![]));
 
-    // Creates channels for syncing blocks between nodes
-    for _  in 0..NUMBER_OF_NODES {
-        let (tx_block, rx_block) = mpsc::channel::<Block>();
-        tx_rx_channels.lock().unwrap().push((tx_block, rx_block));
-        
-    }
+    // Creates the in-process transports for syncing blocks between nodes
+    let transports = ChannelTransport::connect_all(NUMBER_OF_NODES as usize);
 
     // Creating NUMBER_OF_NODES threads to simulate nodes
-    for id in 0..NUMBER_OF_NODES {
+    for (id, transport) in (0..NUMBER_OF_NODES).zip(transports) {
         // channel for picking random node to mine a block
-        let (tx, rx) = mpsc::channel::<u32>();
-        // clone tx_rx_channels to be used in the thread
-        let tx_rx_channels_clone = Arc::clone(&tx_rx_channels);
-        let node = Arc::new(Node::new(id));
-        let thread = node.start_node(rx, tx_rx_channels_clone);
+        let (tx, rx) = mpsc::channel::<()>();
+        let node = Arc::new(open_node(id, &params));
+        let thread = node.start_node(Arc::new(transport), Mining::OnRequest(rx));
         node_threads.push(thread);
         tx_channels.push(tx);
     }
@@ -43,8 +122,28 @@ fn multithreaded_blockchain() {
         let random_node_id: u32 = rand::thread_rng().gen_range(0..NUMBER_OF_NODES);
         println!("MAIN THREAD picked a random node id: {}", random_node_id);
         let choosen_tx = &tx_channels[random_node_id as usize];
-        choosen_tx.send(random_node_id).unwrap();
-        std::thread::sleep(Duration::from_millis(AVERAGE_BLOCK_TIME_MS));
+        choosen_tx.send(()).unwrap();
+        std::thread::sleep(Duration::from_millis(params.target_spacing_ms));
         println!("------------------------------------");
     }
 }
+
+/// Creates a node that reloads the blocks and the UTXO set it stored before the last shutdown
+fn open_node(id: u32, params: &ChainParams) -> Node {
+    let data_dir = node_data_dir(id, params);
+    let node = BlockStore::open(&data_dir.join("blocks"), params.magic)
+        .and_then(|store| Ok((store, Chainstate::open(&data_dir.join("chainstate"))?)))
+        .map_err(ChainError::Store)
+        .and_then(|(store, chainstate)| Node::with_storage(id, params.clone(), store, chainstate));
+    match node {
+        Ok(node) => node,
+        Err(error) => {
+            eprintln!("Can't load the chain of #{} node from {}: {}", id, data_dir.display(), error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn node_data_dir(id: u32, params: &ChainParams) -> PathBuf {
+    Path::new(DATA_DIR).join(params.network.to_string()).join(format!("node{}", id))
+}
@@ -0,0 +1,64 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::network::message::{NetworkError, NetworkMessage};
+use crate::network::transport::{PeerEvent, PeerId, Transport};
+
+/// In-process transport of a simulated node: every node has an mpsc mailbox
+/// and all nodes created together are peers of each other, the peer id is the node's index
+#[derive(Debug)]
+pub struct ChannelTransport {
+    id: PeerId,
+    mailboxes: Vec<Sender<PeerEvent>>,
+    events: Mutex<Receiver<PeerEvent>>,
+}
+
+impl ChannelTransport {
+    /// Creates the transports of `count` nodes that are all connected to each other
+    pub fn connect_all(count: usize) -> Vec<ChannelTransport> {
+        let (mailboxes, receivers): (Vec<Sender<PeerEvent>>, Vec<Receiver<PeerEvent>>) = (0..count).map(|_| mpsc::channel()).unzip();
+        receivers.into_iter()
+            .enumerate()
+            .map(|(id, events)| ChannelTransport { id: id as PeerId, mailboxes: mailboxes.clone(), events: Mutex::new(events) })
+            .collect()
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, peer: PeerId, message: &NetworkMessage) -> Result<(), NetworkError> {
+        let mailbox = self.mailboxes.get(peer as usize).filter(|_| peer != self.id).ok_or(NetworkError::UnknownPeer(peer))?;
+        mailbox.send(PeerEvent::Message(self.id, message.clone())).map_err(|_| NetworkError::Disconnected)
+    }
+
+    fn receive_timeout(&self, timeout: Duration) -> Option<PeerEvent> {
+        self.events.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    fn peers(&self) -> Vec<PeerId> {
+        (0..self.mailboxes.len() as PeerId).filter(|peer| *peer != self.id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn test_send_and_broadcast() {
+        let transports = ChannelTransport::connect_all(3);
+        assert_eq!(transports[1].peers(), vec![0, 2]);
+
+        transports[0].send(2, &NetworkMessage::Ping(1)).unwrap();
+        assert_eq!(transports[2].receive_timeout(TIMEOUT), Some(PeerEvent::Message(0, NetworkMessage::Ping(1))));
+        assert_eq!(transports[0].send(0, &NetworkMessage::Ping(1)), Err(NetworkError::UnknownPeer(0)));
+        assert_eq!(transports[0].send(3, &NetworkMessage::Ping(1)), Err(NetworkError::UnknownPeer(3)));
+
+        transports[1].broadcast(&NetworkMessage::Verack, Some(2));
+        assert_eq!(transports[0].receive_timeout(TIMEOUT), Some(PeerEvent::Message(1, NetworkMessage::Verack)));
+        assert_eq!(transports[2].receive_timeout(TIMEOUT), None);
+        assert_eq!(transports[1].receive_timeout(TIMEOUT), None);
+    }
+}
@@ -0,0 +1,413 @@
+use std::fmt;
+use std::io::{Read, Write};
+
+use secp256k1::hashes::{sha256, Hash};
+
+use crate::constants::{MAX_HEADERS_RESULTS, MAX_INV_ITEMS, MAX_MESSAGE_PAYLOAD_SIZE};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::serialize::{write_var_bytes, write_vec, DecodeError, Decodable, Encodable, Reader};
+use crate::core::transaction::Transaction;
+use crate::utils::hash::hash256;
+
+/// Size of a message header: magic bytes, command, payload size (u32) and checksum
+pub const MESSAGE_HEADER_SIZE: usize = 4 + COMMAND_SIZE + 4 + 4;
+
+/// Size of the command field, the command name is padded with zero bytes
+const COMMAND_SIZE: usize = 12;
+
+/// Kind of object announced in an inv message or requested with getdata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InventoryType {
+    Transaction,
+    Block,
+}
+
+/// Object announced in an inv message or requested with getdata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: InventoryType,
+    pub hash: sha256::Hash,
+}
+
+impl Inventory {
+    pub fn block(hash: sha256::Hash) -> Inventory {
+        Inventory { kind: InventoryType::Block, hash }
+    }
+
+    pub fn transaction(txid: sha256::Hash) -> Inventory {
+        Inventory { kind: InventoryType::Transaction, hash: txid }
+    }
+}
+
+/// Inventory encoding: type (u32, 1 for transactions and 2 for blocks) followed by the hash
+impl Encodable for Inventory {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        let kind: u32 = match self.kind {
+            InventoryType::Transaction => 1,
+            InventoryType::Block => 2,
+        };
+        writer.extend_from_slice(&kind.to_le_bytes());
+        writer.extend_from_slice(self.hash.as_byte_array());
+    }
+}
+
+impl Decodable for Inventory {
+    fn decode_from(reader: &mut Reader) -> Result<Inventory, DecodeError> {
+        let kind = match reader.read_u32()? {
+            1 => InventoryType::Transaction,
+            2 => InventoryType::Block,
+            _ => return Err(DecodeError::InvalidInventoryType),
+        };
+        Ok(Inventory { kind, hash: reader.read_hash()? })
+    }
+}
+
+/// First message sent on a new connection, describes the sending node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub protocol_version: u32,
+    /// Time (ms) of the sending node
+    pub timestamp: u128,
+    /// Random value of the sending node, used to detect connections to itself
+    pub nonce: u64,
+    /// Software name and version of the sending node
+    pub user_agent: String,
+    /// Height of the sending node's active chain
+    pub start_height: u64,
+}
+
+/// Version encoding: protocol version (u32), timestamp (u128), nonce (u64), var_str user agent and start height (u64)
+impl Encodable for VersionMessage {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(&self.protocol_version.to_le_bytes());
+        writer.extend_from_slice(&self.timestamp.to_le_bytes());
+        writer.extend_from_slice(&self.nonce.to_le_bytes());
+        write_var_bytes(writer, self.user_agent.as_bytes());
+        writer.extend_from_slice(&self.start_height.to_le_bytes());
+    }
+}
+
+impl Decodable for VersionMessage {
+    fn decode_from(reader: &mut Reader) -> Result<VersionMessage, DecodeError> {
+        Ok(VersionMessage {
+            protocol_version: reader.read_u32()?,
+            timestamp: reader.read_u128()?,
+            nonce: reader.read_u64()?,
+            user_agent: reader.read_var_string()?,
+            start_height: reader.read_u64()?,
+        })
+    }
+}
+
+/// Request for the headers of the active chain after the first known block of `locator`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetHeadersMessage {
+    /// Hashes of blocks of the requester's active chain, from its tip back to the genesis block
+    pub locator: Vec<sha256::Hash>,
+    /// Last header to send, None to send as many as possible
+    pub stop_hash: Option<sha256::Hash>,
+}
+
+/// Getheaders encoding: CompactSize prefixed locator hashes and the stop hash (32 zero bytes for None)
+impl Encodable for GetHeadersMessage {
+    fn encode_to(&self, writer: &mut Vec<u8>) {
+        write_vec(writer, &self.locator);
+        let stop_hash = self.stop_hash.unwrap_or(sha256::Hash::all_zeros());
+        writer.extend_from_slice(stop_hash.as_byte_array());
+    }
+}
+
+impl Decodable for GetHeadersMessage {
+    fn decode_from(reader: &mut Reader) -> Result<GetHeadersMessage, DecodeError> {
+        let locator = reader.read_vec()?;
+        let stop_hash = reader.read_hash()?;
+        Ok(GetHeadersMessage {
+            locator,
+            stop_hash: if stop_hash == sha256::Hash::all_zeros() { None } else { Some(stop_hash) },
+        })
+    }
+}
+
+/// Message exchanged between nodes over TCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkMessage {
+    Version(VersionMessage),
+    /// Accepts the version of the peer, the handshake is done once both sides sent it
+    Verack,
+    Ping(u64),
+    /// Answer to a ping with the same nonce
+    Pong(u64),
+    /// Announces transactions or blocks
+    Inv(Vec<Inventory>),
+    /// Requests the announced transactions or blocks
+    GetData(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    GetHeaders(GetHeadersMessage),
+    /// Answer to getheaders, ordered from the oldest header
+    Headers(Vec<BlockHeader>),
+}
+
+impl NetworkMessage {
+    /// Name of the message in the message header
+    pub fn command(&self) -> &'static str {
+        match self {
+            NetworkMessage::Version(_) => "version",
+            NetworkMessage::Verack => "verack",
+            NetworkMessage::Ping(_) => "ping",
+            NetworkMessage::Pong(_) => "pong",
+            NetworkMessage::Inv(_) => "inv",
+            NetworkMessage::GetData(_) => "getdata",
+            NetworkMessage::Block(_) => "block",
+            NetworkMessage::Tx(_) => "tx",
+            NetworkMessage::GetHeaders(_) => "getheaders",
+            NetworkMessage::Headers(_) => "headers",
+        }
+    }
+
+    fn encode_payload(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        match self {
+            NetworkMessage::Version(version) => version.encode_to(&mut payload),
+            NetworkMessage::Verack => {}
+            NetworkMessage::Ping(nonce) | NetworkMessage::Pong(nonce) => payload.extend_from_slice(&nonce.to_le_bytes()),
+            NetworkMessage::Inv(items) | NetworkMessage::GetData(items) => write_vec(&mut payload, items),
+            NetworkMessage::Block(block) => block.encode_to(&mut payload),
+            NetworkMessage::Tx(transaction) => transaction.encode_to(&mut payload),
+            NetworkMessage::GetHeaders(get_headers) => get_headers.encode_to(&mut payload),
+            NetworkMessage::Headers(headers) => write_vec(&mut payload, headers),
+        }
+        payload
+    }
+
+    fn decode_payload(command: &str, payload: &[u8]) -> Result<NetworkMessage, NetworkError> {
+        let decode_error = |error| NetworkError::Decode { command: command.to_string(), error };
+        let message = match command {
+            "version" => NetworkMessage::Version(VersionMessage::decode(payload).map_err(decode_error)?),
+            "verack" if payload.is_empty() => NetworkMessage::Verack,
+            "verack" => return Err(decode_error(DecodeError::TrailingBytes)),
+            "ping" => NetworkMessage::Ping(decode_nonce(payload).map_err(decode_error)?),
+            "pong" => NetworkMessage::Pong(decode_nonce(payload).map_err(decode_error)?),
+            "inv" => NetworkMessage::Inv(decode_limited_vec(payload, MAX_INV_ITEMS).map_err(decode_error)?),
+            "getdata" => NetworkMessage::GetData(decode_limited_vec(payload, MAX_INV_ITEMS).map_err(decode_error)?),
+            "block" => NetworkMessage::Block(Block::decode(payload).map_err(decode_error)?),
+            "tx" => NetworkMessage::Tx(Transaction::decode(payload).map_err(decode_error)?),
+            "getheaders" => NetworkMessage::GetHeaders(GetHeadersMessage::decode(payload).map_err(decode_error)?),
+            "headers" => NetworkMessage::Headers(decode_limited_vec(payload, MAX_HEADERS_RESULTS).map_err(decode_error)?),
+            _ => return Err(NetworkError::UnknownCommand(command.to_string())),
+        };
+        Ok(message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkError {
+    /// Reading from or writing to the connection failed
+    Io(String),
+    /// The peer closed the connection
+    Disconnected,
+    /// A message doesn't start with the magic bytes of the network
+    BadMagic([u8; 4]),
+    /// The command field isn't a zero padded ASCII name
+    InvalidCommand,
+    UnknownCommand(String),
+    /// The payload is larger than `MAX_MESSAGE_PAYLOAD_SIZE`
+    PayloadTooLarge(usize),
+    /// The payload doesn't match the checksum of the message header
+    BadChecksum { command: String },
+    /// The payload can't be decoded as the message of its command
+    Decode { command: String, error: DecodeError },
+    /// The peer didn't start with version and verack
+    UnexpectedMessage(&'static str),
+    /// The peer uses an older protocol version
+    UnsupportedVersion(u32),
+    /// The connection leads back to this node
+    SelfConnection,
+    UnknownPeer(u64),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Io(error) => write!(f, "network error: {}", error),
+            NetworkError::Disconnected => write!(f, "peer disconnected"),
+            NetworkError::BadMagic(magic) => write!(f, "bad magic bytes {:02x?}", magic),
+            NetworkError::InvalidCommand => write!(f, "invalid command"),
+            NetworkError::UnknownCommand(command) => write!(f, "unknown command {}", command),
+            NetworkError::PayloadTooLarge(size) => write!(f, "payload of {} bytes is too large", size),
+            NetworkError::BadChecksum { command } => write!(f, "bad checksum of {} message", command),
+            NetworkError::Decode { command, error } => write!(f, "invalid {} message: {}", command, error),
+            NetworkError::UnexpectedMessage(command) => write!(f, "unexpected {} message during the handshake", command),
+            NetworkError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            NetworkError::SelfConnection => write!(f, "connected to self"),
+            NetworkError::UnknownPeer(id) => write!(f, "unknown peer {}", id),
+        }
+    }
+}
+
+impl From<std::io::Error> for NetworkError {
+    fn from(error: std::io::Error) -> NetworkError {
+        match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => NetworkError::Disconnected,
+            _ => NetworkError::Io(error.to_string()),
+        }
+    }
+}
+
+/// Frames a message: magic bytes, zero padded command, payload size (u32),
+/// the first 4 bytes of the payload's hash256 as a checksum and the payload
+pub fn encode_message(magic: [u8; 4], message: &NetworkMessage) -> Vec<u8> {
+    let payload = message.encode_payload();
+    let mut command = [0; COMMAND_SIZE];
+    command[..message.command().len()].copy_from_slice(message.command().as_bytes());
+
+    let mut bytes = Vec::with_capacity(MESSAGE_HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&magic);
+    bytes.extend_from_slice(&command);
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&hash256(&payload)[..4]);
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+pub fn write_message<W: Write>(writer: &mut W, magic: [u8; 4], message: &NetworkMessage) -> Result<(), NetworkError> {
+    writer.write_all(&encode_message(magic, message))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next framed message, blocking until it's complete
+pub fn read_message<R: Read>(reader: &mut R, magic: [u8; 4]) -> Result<NetworkMessage, NetworkError> {
+    let mut header = [0; MESSAGE_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let message_magic: [u8; 4] = header[..4].try_into().unwrap();
+    if message_magic != magic {
+        return Err(NetworkError::BadMagic(message_magic));
+    }
+    let command = decode_command(&header[4..4 + COMMAND_SIZE]).ok_or(NetworkError::InvalidCommand)?;
+    let size = u32::from_le_bytes(header[4 + COMMAND_SIZE..8 + COMMAND_SIZE].try_into().unwrap()) as usize;
+    if size > MAX_MESSAGE_PAYLOAD_SIZE {
+        return Err(NetworkError::PayloadTooLarge(size));
+    }
+    let checksum = &header[8 + COMMAND_SIZE..];
+
+    let mut payload = vec![0; size];
+    reader.read_exact(&mut payload)?;
+    if hash256(&payload)[..4] != *checksum {
+        return Err(NetworkError::BadChecksum { command });
+    }
+    NetworkMessage::decode_payload(&command, &payload)
+}
+
+/// Command name without its zero padding, None if it isn't ASCII or there are bytes after the padding
+fn decode_command(bytes: &[u8]) -> Option<String> {
+    let length = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    if !bytes[..length].is_ascii() || bytes[length..].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes[..length]).into_owned())
+}
+
+fn decode_nonce(payload: &[u8]) -> Result<u64, DecodeError> {
+    let mut reader = Reader::new(payload);
+    let nonce = reader.read_u64()?;
+    if !reader.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(nonce)
+}
+
+/// Decodes a CompactSize prefixed list that may have at most `max_items` items
+fn decode_limited_vec<T: Decodable>(payload: &[u8], max_items: usize) -> Result<Vec<T>, DecodeError> {
+    let mut reader = Reader::new(payload);
+    let count = reader.read_length()?;
+    if count > max_items {
+        return Err(DecodeError::LengthTooLarge);
+    }
+    let items = (0..count).map(|_| T::decode_from(&mut reader)).collect::<Result<Vec<T>, DecodeError>>()?;
+    if !reader.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{PROTOCOL_VERSION, SOFTWARE_VERSION};
+    use crate::core::params::ChainParams;
+    use crate::utils::hash::sha256_hash;
+
+    const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    fn roundtrip(message: NetworkMessage) {
+        let bytes = encode_message(MAGIC, &message);
+        assert_eq!(read_message(&mut bytes.as_slice(), MAGIC), Ok(message));
+    }
+
+    #[test]
+    fn test_message_roundtrips() {
+        let block = ChainParams::regtest().genesis_block();
+        roundtrip(NetworkMessage::Version(VersionMessage {
+            protocol_version: PROTOCOL_VERSION,
+            timestamp: 1_704_067_200_000,
+            nonce: 42,
+            user_agent: SOFTWARE_VERSION.to_string(),
+            start_height: 7,
+        }));
+        roundtrip(NetworkMessage::Verack);
+        roundtrip(NetworkMessage::Ping(1));
+        roundtrip(NetworkMessage::Pong(u64::MAX));
+        roundtrip(NetworkMessage::Inv(vec![Inventory::block(block.hash_block()), Inventory::transaction(sha256_hash("tx"))]));
+        roundtrip(NetworkMessage::GetData(vec![]));
+        roundtrip(NetworkMessage::Tx(block.coinbase_transaction.clone()));
+        roundtrip(NetworkMessage::GetHeaders(GetHeadersMessage { locator: vec![block.hash_block()], stop_hash: None }));
+        roundtrip(NetworkMessage::GetHeaders(GetHeadersMessage { locator: vec![], stop_hash: Some(block.hash_block()) }));
+        roundtrip(NetworkMessage::Headers(vec![block.header.clone()]));
+        roundtrip(NetworkMessage::Block(block));
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let bytes = encode_message(MAGIC, &NetworkMessage::Ping(5));
+        assert_eq!(bytes.len(), MESSAGE_HEADER_SIZE + 8);
+        assert_eq!(&bytes[..4], &MAGIC);
+        assert_eq!(&bytes[4..16], b"ping\0\0\0\0\0\0\0\0");
+        assert_eq!(&bytes[16..20], &8u32.to_le_bytes());
+        assert_eq!(&bytes[20..24], &hash256(&5u64.to_le_bytes())[..4]);
+
+        // several messages are read one after another from the same stream
+        let mut stream = bytes.clone();
+        stream.extend(encode_message(MAGIC, &NetworkMessage::Verack));
+        let mut reader = stream.as_slice();
+        assert_eq!(read_message(&mut reader, MAGIC), Ok(NetworkMessage::Ping(5)));
+        assert_eq!(read_message(&mut reader, MAGIC), Ok(NetworkMessage::Verack));
+        assert_eq!(read_message(&mut reader, MAGIC), Err(NetworkError::Disconnected));
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let bytes = encode_message(MAGIC, &NetworkMessage::Ping(5));
+        let other_magic = [0xf9, 0xbe, 0xb4, 0xd9];
+        assert_eq!(read_message(&mut bytes.as_slice(), other_magic), Err(NetworkError::BadMagic(MAGIC)));
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(read_message(&mut corrupted.as_slice(), MAGIC), Err(NetworkError::BadChecksum { command: "ping".to_string() }));
+
+        let mut unknown = bytes.clone();
+        unknown[4..8].copy_from_slice(b"pang");
+        assert_eq!(read_message(&mut unknown.as_slice(), MAGIC), Err(NetworkError::UnknownCommand("pang".to_string())));
+
+        let mut padded = bytes.clone();
+        padded[15] = b'x';
+        assert_eq!(read_message(&mut padded.as_slice(), MAGIC), Err(NetworkError::InvalidCommand));
+
+        let mut too_large = bytes.clone();
+        too_large[16..20].copy_from_slice(&(MAX_MESSAGE_PAYLOAD_SIZE as u32 + 1).to_le_bytes());
+        assert_eq!(read_message(&mut too_large.as_slice(), MAGIC), Err(NetworkError::PayloadTooLarge(MAX_MESSAGE_PAYLOAD_SIZE + 1)));
+
+        // a cut off message
+        assert_eq!(read_message(&mut &bytes[..bytes.len() - 1], MAGIC), Err(NetworkError::Disconnected));
+    }
+}
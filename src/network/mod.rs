@@ -0,0 +1,5 @@
+pub mod message;
+pub mod peer;
+pub mod transport;
+pub mod channel;
+pub mod tcp;
@@ -0,0 +1,165 @@
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::constants::{HANDSHAKE_TIMEOUT_MS, PROTOCOL_VERSION, SOFTWARE_VERSION};
+use crate::network::message::{read_message, write_message, NetworkError, NetworkMessage, VersionMessage};
+use crate::utils::time::get_current_timestamp_ms;
+
+/// TCP connection to another node that completed the version handshake
+#[derive(Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Version message the peer sent during the handshake
+    pub version: VersionMessage,
+    magic: [u8; 4],
+    stream: TcpStream,
+    /// Held while a message is written, so messages sent from different threads don't interleave
+    write_lock: Mutex<()>,
+}
+
+impl Peer {
+    /// Connects to a node and performs the handshake
+    pub fn connect(addr: SocketAddr, magic: [u8; 4], local_version: &VersionMessage) -> Result<Peer, NetworkError> {
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_millis(HANDSHAKE_TIMEOUT_MS))?;
+        Peer::handshake(stream, magic, local_version)
+    }
+
+    /// Performs the handshake on a connection accepted from another node
+    pub fn accept(stream: TcpStream, magic: [u8; 4], local_version: &VersionMessage) -> Result<Peer, NetworkError> {
+        Peer::handshake(stream, magic, local_version)
+    }
+
+    /// Both sides send their version, then accept the version of the other side with verack.
+    /// The connection is dropped if the peer sends anything else first, or if the peer turns out to be this node
+    fn handshake(stream: TcpStream, magic: [u8; 4], local_version: &VersionMessage) -> Result<Peer, NetworkError> {
+        let addr = stream.peer_addr()?;
+        stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS)))?;
+        // a peer that stops reading can't block the node forever
+        stream.set_write_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS)))?;
+        stream.set_nodelay(true)?;
+        write_message(&mut &stream, magic, &NetworkMessage::Version(local_version.clone()))?;
+
+        let version = match read_message(&mut &stream, magic)? {
+            NetworkMessage::Version(version) => version,
+            message => return Err(NetworkError::UnexpectedMessage(message.command())),
+        };
+        if version.nonce == local_version.nonce {
+            return Err(NetworkError::SelfConnection);
+        }
+        if version.protocol_version < PROTOCOL_VERSION {
+            return Err(NetworkError::UnsupportedVersion(version.protocol_version));
+        }
+        write_message(&mut &stream, magic, &NetworkMessage::Verack)?;
+        match read_message(&mut &stream, magic)? {
+            NetworkMessage::Verack => {}
+            message => return Err(NetworkError::UnexpectedMessage(message.command())),
+        }
+
+        stream.set_read_timeout(None)?;
+        Ok(Peer { addr, version, magic, stream, write_lock: Mutex::new(()) })
+    }
+
+    pub fn send(&self, message: &NetworkMessage) -> Result<(), NetworkError> {
+        let _write_lock = self.write_lock.lock().unwrap();
+        write_message(&mut &self.stream, self.magic, message)
+    }
+
+    /// Reader of the messages sent by the peer, meant to be used from its own thread
+    pub fn reader(&self) -> Result<PeerReader, NetworkError> {
+        Ok(PeerReader { magic: self.magic, stream: BufReader::new(self.stream.try_clone()?) })
+    }
+
+    /// Closes the connection, a blocked `PeerReader` returns an error
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Receiving side of a peer connection
+#[derive(Debug)]
+pub struct PeerReader {
+    magic: [u8; 4],
+    stream: BufReader<TcpStream>,
+}
+
+impl PeerReader {
+    /// Blocks until the next message arrives
+    pub fn receive(&mut self) -> Result<NetworkMessage, NetworkError> {
+        read_message(&mut self.stream, self.magic)
+    }
+}
+
+/// Version message describing this node
+pub fn local_version(nonce: u64, start_height: u64) -> VersionMessage {
+    VersionMessage {
+        protocol_version: PROTOCOL_VERSION,
+        timestamp: get_current_timestamp_ms(),
+        nonce,
+        user_agent: format!("/bitcoin-rust:{}/", SOFTWARE_VERSION),
+        start_height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[test]
+    fn test_handshake_and_messages() {
+        let (listener, addr) = listen();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let peer = Peer::accept(stream, MAGIC, &local_version(2, 10)).unwrap();
+            let mut reader = peer.reader().unwrap();
+            // answer a ping
+            if let NetworkMessage::Ping(nonce) = reader.receive().unwrap() {
+                peer.send(&NetworkMessage::Pong(nonce)).unwrap();
+            }
+            peer.version.start_height
+        });
+
+        let peer = Peer::connect(addr, MAGIC, &local_version(1, 5)).unwrap();
+        assert_eq!(peer.version.nonce, 2);
+        assert_eq!(peer.version.start_height, 10);
+        let mut reader = peer.reader().unwrap();
+        peer.send(&NetworkMessage::Ping(7)).unwrap();
+        assert_eq!(reader.receive(), Ok(NetworkMessage::Pong(7)));
+        assert_eq!(server.join().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_self_connection() {
+        let (listener, addr) = listen();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Peer::accept(stream, MAGIC, &local_version(1, 0)).map(|_| ())
+        });
+        assert_eq!(Peer::connect(addr, MAGIC, &local_version(1, 0)).unwrap_err(), NetworkError::SelfConnection);
+        assert_eq!(server.join().unwrap(), Err(NetworkError::SelfConnection));
+    }
+
+    #[test]
+    fn test_handshake_must_start_with_version() {
+        let (listener, addr) = listen();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            write_message(&mut &stream, MAGIC, &NetworkMessage::Ping(1)).unwrap();
+            // keep the connection open until the other side has read the message
+            let _ = read_message(&mut &stream, MAGIC);
+        });
+        assert_eq!(Peer::connect(addr, MAGIC, &local_version(1, 0)).unwrap_err(), NetworkError::UnexpectedMessage("ping"));
+        server.join().unwrap();
+    }
+}
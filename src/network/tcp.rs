@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::network::message::{NetworkError, NetworkMessage, VersionMessage};
+use crate::network::peer::{local_version, Peer};
+use crate::network::transport::{PeerEvent, PeerId, Transport};
+
+/// Transport over TCP connections: accepts peers on a listening socket, connects to other nodes
+/// and delivers everything their peers send as `PeerEvent`s. Each peer has a thread reading its messages
+#[derive(Debug)]
+pub struct TcpTransport {
+    magic: [u8; 4],
+    /// Random value of the version message, a connection receiving it back leads to this node
+    nonce: u64,
+    /// Height of the active chain announced to new peers
+    best_height: AtomicU64,
+    /// Connected peers, shared with the senders so a slow write doesn't hold up the other peers
+    peers: Mutex<HashMap<PeerId, Arc<Peer>>>,
+    next_peer_id: AtomicU64,
+    events_tx: Sender<PeerEvent>,
+    events: Mutex<Receiver<PeerEvent>>,
+}
+
+impl TcpTransport {
+    /// Creates a network without peers for the network with the given magic bytes
+    pub fn new(magic: [u8; 4]) -> Arc<TcpTransport> {
+        let (events_tx, events) = mpsc::channel();
+        Arc::new(TcpTransport {
+            magic,
+            nonce: rand::random(),
+            best_height: AtomicU64::new(0),
+            peers: Mutex::new(HashMap::new()),
+            next_peer_id: AtomicU64::new(0),
+            events_tx,
+            events: Mutex::new(events),
+        })
+    }
+
+    /// Accepts peers on `addr` in a background thread, returns the address the socket is bound to
+    pub fn listen(self: &Arc<Self>, addr: SocketAddr) -> Result<SocketAddr, NetworkError> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let network = Arc::clone(self);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                // the handshake of a slow peer doesn't hold up the others
+                let network = Arc::clone(&network);
+                thread::spawn(move || {
+                    let peer = Peer::accept(stream, network.magic, &network.local_version());
+                    if let Err(error) = peer.and_then(|peer| network.add_peer(peer)) {
+                        println!("Inbound connection failed: {}", error);
+                    }
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// Connects to the node at `addr`
+    pub fn connect(self: &Arc<Self>, addr: SocketAddr) -> Result<PeerId, NetworkError> {
+        let peer = Peer::connect(addr, self.magic, &self.local_version())?;
+        self.add_peer(peer)
+    }
+
+    fn local_version(&self) -> VersionMessage {
+        local_version(self.nonce, self.best_height.load(Ordering::Relaxed))
+    }
+
+    /// Registers a peer that completed the handshake and starts reading its messages
+    fn add_peer(self: &Arc<Self>, peer: Peer) -> Result<PeerId, NetworkError> {
+        let mut reader = peer.reader()?;
+        let id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
+        let version = peer.version.clone();
+        let addr = peer.addr;
+        self.peers.lock().unwrap().insert(id, Arc::new(peer));
+        let _ = self.events_tx.send(PeerEvent::Connected(id, version));
+
+        let network = Arc::clone(self);
+        thread::spawn(move || {
+            loop {
+                match reader.receive() {
+                    Ok(message) => {
+                        let _ = network.events_tx.send(PeerEvent::Message(id, message));
+                    }
+                    Err(error) => {
+                        if error != NetworkError::Disconnected {
+                            println!("Disconnecting peer {} ({}): {}", id, addr, error);
+                        }
+                        break;
+                    }
+                }
+            }
+            if let Some(peer) = network.peers.lock().unwrap().remove(&id) {
+                peer.shutdown();
+            }
+            let _ = network.events_tx.send(PeerEvent::Disconnected(id));
+        });
+        Ok(id)
+    }
+}
+
+impl Transport for TcpTransport {
+    /// Sends a message to a peer, the peer is disconnected if the message can't be written
+    fn send(&self, peer: PeerId, message: &NetworkMessage) -> Result<(), NetworkError> {
+        let connection = self.peers.lock().unwrap().get(&peer).cloned().ok_or(NetworkError::UnknownPeer(peer))?;
+        let result = connection.send(message);
+        if result.is_err() {
+            connection.shutdown();
+        }
+        result
+    }
+
+    fn receive_timeout(&self, timeout: Duration) -> Option<PeerEvent> {
+        self.events.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    fn peers(&self) -> Vec<PeerId> {
+        self.peers.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Closes the connection, a `Disconnected` event follows once its reader thread stops
+    fn disconnect(&self, peer: PeerId) {
+        if let Some(connection) = self.peers.lock().unwrap().get(&peer) {
+            connection.shutdown();
+        }
+    }
+
+    fn set_best_height(&self, height: u64) {
+        self.best_height.store(height, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn local_addr() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[test]
+    fn test_connect_and_exchange_messages() {
+        let server = TcpTransport::new(MAGIC);
+        server.set_best_height(3);
+        let addr = server.listen(local_addr()).unwrap();
+        let client = TcpTransport::new(MAGIC);
+        let server_id = client.connect(addr).unwrap();
+
+        let Some(PeerEvent::Connected(_, version)) = client.receive_timeout(TIMEOUT) else { panic!("no connected event") };
+        assert_eq!(version.start_height, 3);
+        let Some(PeerEvent::Connected(client_id, _)) = server.receive_timeout(TIMEOUT) else { panic!("no connected event") };
+
+        client.send(server_id, &NetworkMessage::Ping(9)).unwrap();
+        assert_eq!(server.receive_timeout(TIMEOUT), Some(PeerEvent::Message(client_id, NetworkMessage::Ping(9))));
+        server.broadcast(&NetworkMessage::Pong(9), None);
+        assert_eq!(client.receive_timeout(TIMEOUT), Some(PeerEvent::Message(server_id, NetworkMessage::Pong(9))));
+
+        client.disconnect(server_id);
+        assert_eq!(client.receive_timeout(TIMEOUT), Some(PeerEvent::Disconnected(server_id)));
+        assert_eq!(server.receive_timeout(TIMEOUT), Some(PeerEvent::Disconnected(client_id)));
+        assert!(client.peers().is_empty());
+        assert_eq!(client.send(server_id, &NetworkMessage::Ping(1)), Err(NetworkError::UnknownPeer(server_id)));
+    }
+
+    #[test]
+    fn test_other_network_is_rejected() {
+        let server = TcpTransport::new(MAGIC);
+        let addr = server.listen(local_addr()).unwrap();
+        let client = TcpTransport::new([0xf9, 0xbe, 0xb4, 0xd9]);
+        assert!(matches!(client.connect(addr), Err(NetworkError::BadMagic(MAGIC))));
+        assert!(server.receive_timeout(Duration::from_millis(200)).is_none());
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use crate::network::message::{NetworkError, NetworkMessage, VersionMessage};
+
+/// Identifier of a peer within a transport, never reused while the node runs
+pub type PeerId = u64;
+
+/// Something that happened on a peer connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer completed the handshake with this version message
+    Connected(PeerId, VersionMessage),
+    Message(PeerId, NetworkMessage),
+    Disconnected(PeerId),
+}
+
+/// Way a node exchanges messages with its peers,
+/// the node logic is the same whether peers are threads of this process or other processes
+pub trait Transport: Send + Sync {
+    /// Sends a message to one peer
+    fn send(&self, peer: PeerId, message: &NetworkMessage) -> Result<(), NetworkError>;
+
+    /// Sends a message to every peer except `except`
+    fn broadcast(&self, message: &NetworkMessage, except: Option<PeerId>) {
+        for peer in self.peers() {
+            if Some(peer) != except {
+                if let Err(error) = self.send(peer, message) {
+                    println!("Can't send {} to peer {}: {}", message.command(), peer, error);
+                }
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the next event of any peer
+    fn receive_timeout(&self, timeout: Duration) -> Option<PeerEvent>;
+
+    /// Currently reachable peers
+    fn peers(&self) -> Vec<PeerId>;
+
+    /// Drops the connection to a misbehaving peer, if the transport can
+    fn disconnect(&self, _peer: PeerId) {}
+
+    /// Height of the active chain announced to peers that connect later
+    fn set_best_height(&self, _height: u64) {}
+}
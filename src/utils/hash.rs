@@ -1,9 +1,71 @@
-use secp256k1::hashes::{sha256, Hash};
+use secp256k1::hashes::{hash160, sha256, Hash, HashEngine};
 
+#[cfg(test)]
 pub fn sha256_hash(data: &str) -> sha256::Hash {
     sha256::Hash::hash(data.as_bytes())
 }
 
+/// Single SHA-256 of raw bytes
+pub fn sha256_bytes(data: &[u8]) -> sha256::Hash {
+    sha256::Hash::hash(data)
+}
+
+/// Double SHA-256 (SHA-256 of the SHA-256), used for block hashes, transaction ids and merkle nodes
+pub fn hash256(data: &[u8]) -> sha256::Hash {
+    sha256::Hash::hash(sha256::Hash::hash(data).as_byte_array())
+}
+
+/// RIPEMD-160 of the SHA-256, used for public key and script hashes
+pub fn hash160(data: &[u8]) -> hash160::Hash {
+    hash160::Hash::hash(data)
+}
+
+/// BIP340 style tagged hash: SHA-256(SHA-256(tag) || SHA-256(tag) || data),
+/// so hashes computed for different purposes can never collide
+#[cfg(test)]
+pub fn tagged_hash(tag: &str, data: &[u8]) -> sha256::Hash {
+    let mut hasher = Hasher::new_tagged(tag);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Streaming SHA-256 hasher, data can be fed in several parts without concatenating it first
+#[derive(Clone, Default)]
+pub struct Hasher {
+    engine: sha256::HashEngine,
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher::default()
+    }
+
+    /// Hasher with the BIP340 tag prefix already fed in
+    #[cfg(test)]
+    pub fn new_tagged(tag: &str) -> Hasher {
+        let tag_hash = sha256::Hash::hash(tag.as_bytes());
+        let mut hasher = Hasher::new();
+        hasher.update(tag_hash.as_byte_array());
+        hasher.update(tag_hash.as_byte_array());
+        hasher
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> &mut Hasher {
+        self.engine.input(data);
+        self
+    }
+
+    /// Single SHA-256 of all the data fed so far
+    pub fn finalize(self) -> sha256::Hash {
+        sha256::Hash::from_engine(self.engine)
+    }
+
+    /// Double SHA-256 of all the data fed so far
+    pub fn finalize_double(self) -> sha256::Hash {
+        sha256::Hash::hash(self.finalize().as_byte_array())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -15,4 +77,42 @@ mod tests {
         let hash = sha256_hash(data);
         assert_eq!(hash.to_string(), expected_hash);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sha256_bytes() {
+        let expected_hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert_eq!(sha256_bytes(b"hello").to_string(), expected_hash);
+        assert_eq!(sha256_bytes(b"hello"), sha256_hash("hello"));
+    }
+
+    #[test]
+    fn test_hash256() {
+        let expected_hash = "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50";
+        assert_eq!(hash256(b"hello").to_string(), expected_hash);
+    }
+
+    #[test]
+    fn test_hash160() {
+        // compressed public key of the secp256k1 generator point (secret key 1)
+        let public_key = [
+            0x02, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b, 0x07,
+            0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17, 0x98,
+        ];
+        assert_eq!(hash160(&public_key).to_string(), "751e76e8199196d454941c45d1b3a323f1433bd6");
+    }
+
+    #[test]
+    fn test_tagged_hash() {
+        let expected_hash = "a97ff4dc59e2e158c00a7d9cf1e7d60fb090ecf5f728b6d17be7cbbb0fc572dd";
+        assert_eq!(tagged_hash("BIP0340/challenge", b"hello").to_string(), expected_hash);
+        assert_ne!(tagged_hash("other/tag", b"hello"), tagged_hash("BIP0340/challenge", b"hello"));
+    }
+
+    #[test]
+    fn test_streaming_hasher() {
+        let mut hasher = Hasher::new();
+        hasher.update(b"hel").update(b"lo");
+        assert_eq!(hasher.clone().finalize(), sha256_bytes(b"hello"));
+        assert_eq!(hasher.finalize_double(), hash256(b"hello"));
+    }
+}
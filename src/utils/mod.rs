@@ -1,3 +1,6 @@
 pub mod hash;
+#[cfg(test)]
+pub mod temp_dir;
 pub mod time;
+pub mod u256;
 pub mod wallets;
\ No newline at end of file
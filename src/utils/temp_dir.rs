@@ -0,0 +1,23 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Empty directory in the system temp dir, removed when it's dropped (used by tests that write files)
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let dir = std::env::temp_dir().join(format!("bitcoin-rust-test-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
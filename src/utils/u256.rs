@@ -0,0 +1,274 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Not, Shl, Shr, Sub};
+
+/// Unsigned 256-bit integer used for proof of work targets and chain work
+/// (stored as four 64-bit limbs, least significant limb first)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0, 0, 0, 0]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> U256 {
+        U256([value, 0, 0, 0])
+    }
+
+    /// Reads a 256-bit number from 32 big-endian bytes
+    pub fn from_be_bytes(bytes: [u8; 32]) -> U256 {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let start = 32 - (i + 1) * 8;
+            *limb = u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            let start = 32 - (i + 1) * 8;
+            bytes[start..start + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|limb| *limb == 0)
+    }
+
+    /// Number of significant bits (position of the highest set bit + 1)
+    pub fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    /// Returns the lowest 64 bits of the number
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    pub fn checked_add(&self, other: &U256) -> Option<U256> {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (sum, overflow1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, overflow2) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = overflow1 || overflow2;
+        }
+        if carry { None } else { Some(U256(result)) }
+    }
+
+    pub fn checked_sub(&self, other: &U256) -> Option<U256> {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (difference, overflow1) = self.0[i].overflowing_sub(other.0[i]);
+            let (difference, overflow2) = difference.overflowing_sub(borrow as u64);
+            *limb = difference;
+            borrow = overflow1 || overflow2;
+        }
+        if borrow { None } else { Some(U256(result)) }
+    }
+
+    pub fn checked_mul_u64(&self, other: u64) -> Option<U256> {
+        let mut result = [0u64; 4];
+        let mut carry: u128 = 0;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 { None } else { Some(U256(result)) }
+    }
+
+    /// Divides by a 64-bit number (panics on division by zero)
+    pub fn div_u64(&self, other: u64) -> U256 {
+        assert!(other != 0, "division by zero");
+        let mut result = [0u64; 4];
+        let mut remainder: u128 = 0;
+        for i in (0..4).rev() {
+            let dividend = (remainder << 64) | self.0[i] as u128;
+            result[i] = (dividend / other as u128) as u64;
+            remainder = dividend % other as u128;
+        }
+        U256(result)
+    }
+
+    /// Divides by another 256-bit number using binary long division (panics on division by zero)
+    pub fn div(&self, other: &U256) -> U256 {
+        assert!(!other.is_zero(), "division by zero");
+        if self < other {
+            return U256::ZERO;
+        }
+        let shift = self.bits() - other.bits();
+        let mut divisor = *other << shift;
+        let mut remainder = *self;
+        let mut quotient = U256::ZERO;
+        for i in (0..=shift).rev() {
+            if remainder >= divisor {
+                remainder = remainder - divisor;
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
+            }
+            divisor = divisor >> 1;
+        }
+        quotient
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> Ordering {
+        for i in (0..4).rev() {
+            match self.0[i].cmp(&other.0[i]) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: U256) -> U256 {
+        self.checked_add(&other).expect("U256 addition overflow")
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, other: U256) -> U256 {
+        self.checked_sub(&other).expect("U256 subtraction underflow")
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let limb_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+        for (i, limb) in result.iter_mut().enumerate().skip(limb_shift) {
+            *limb = self.0[i - limb_shift] << bit_shift;
+            if bit_shift > 0 && i > limb_shift {
+                *limb |= self.0[i - limb_shift - 1] >> (64 - bit_shift);
+            }
+        }
+        U256(result)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let limb_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+        for (i, limb) in result.iter_mut().take(4usize.saturating_sub(limb_shift)).enumerate() {
+            *limb = self.0[i + limb_shift] >> bit_shift;
+            if bit_shift > 0 && i + limb_shift + 1 < 4 {
+                *limb |= self.0[i + limb_shift + 1] << (64 - bit_shift);
+            }
+        }
+        U256(result)
+    }
+}
+
+impl fmt::Display for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.to_be_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_be_bytes_roundtrip() {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let number = U256::from_be_bytes(bytes);
+        assert_eq!(number.to_be_bytes(), bytes);
+        assert_eq!(number.low_u64(), 0x18191a1b1c1d1e1f);
+    }
+
+    #[test]
+    fn test_bits() {
+        assert_eq!(U256::ZERO.bits(), 0);
+        assert_eq!(U256::ONE.bits(), 1);
+        assert_eq!((U256::ONE << 200).bits(), 201);
+        assert_eq!(U256::MAX.bits(), 256);
+    }
+
+    #[test]
+    fn test_add_sub() {
+        let a = U256::from_u64(u64::MAX);
+        let b = a + U256::ONE;
+        assert_eq!(b, U256::ONE << 64);
+        assert_eq!(b - U256::ONE, a);
+        assert!(U256::MAX.checked_add(&U256::ONE).is_none());
+        assert!(U256::ZERO.checked_sub(&U256::ONE).is_none());
+    }
+
+    #[test]
+    fn test_shifts() {
+        let number = U256::from_u64(0xabcd);
+        assert_eq!((number << 100) >> 100, number);
+        assert_eq!(number >> 16, U256::ZERO);
+        assert_eq!(U256::MAX << 256, U256::ZERO);
+    }
+
+    #[test]
+    fn test_mul_div_u64() {
+        let number = U256::ONE << 130;
+        assert_eq!(number.checked_mul_u64(4).unwrap(), U256::ONE << 132);
+        assert_eq!(number.div_u64(4), U256::ONE << 128);
+        assert!(U256::MAX.checked_mul_u64(2).is_none());
+    }
+
+    #[test]
+    fn test_div() {
+        let dividend = (U256::ONE << 200) + U256::from_u64(12345);
+        let divisor = U256::ONE << 100;
+        assert_eq!(dividend.div(&divisor), U256::ONE << 100);
+        assert_eq!(U256::from_u64(100).div(&U256::from_u64(7)), U256::from_u64(14));
+        assert_eq!(U256::from_u64(5).div(&U256::from_u64(7)), U256::ZERO);
+    }
+
+    #[test]
+    fn test_ordering() {
+        assert!(U256::ONE << 64 > U256::from_u64(u64::MAX));
+        assert!(U256::ZERO < U256::ONE);
+    }
+}
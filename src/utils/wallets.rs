@@ -1,8 +1,9 @@
 use secp256k1::ecdsa::Signature;
-use secp256k1::hashes::Hash;
+use secp256k1::hashes::{sha256, Hash};
 use secp256k1::rand::rngs::OsRng;
 use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
 
+#[cfg(test)]
 use super::hash::sha256_hash;
 
 
@@ -12,6 +13,7 @@ pub fn generate_keypair() -> (SecretKey, PublicKey) {
     (secret_key, public_key)
 }
 
+#[cfg(test)]
 pub fn sign_with_key(message: &str, secret_key: &SecretKey) -> Signature {
     let secp = Secp256k1::new();
     let digest = sha256_hash(message);
@@ -19,6 +21,7 @@ pub fn sign_with_key(message: &str, secret_key: &SecretKey) -> Signature {
     secp.sign_ecdsa(&message, secret_key)
 }
 
+#[cfg(test)]
 pub fn verify_signature(message: &str, signature: &Signature, public_key: &PublicKey) -> bool {
     let secp = Secp256k1::new();
     let digest = sha256_hash(message);
@@ -26,12 +29,27 @@ pub fn verify_signature(message: &str, signature: &Signature, public_key: &Publi
     secp.verify_ecdsa(&message, signature, public_key).is_ok()
 }
 
+/// Signs an already computed 32-byte digest (e.g. a transaction signature hash)
+#[cfg(test)]
+pub fn sign_hash(digest: &sha256::Hash, secret_key: &SecretKey) -> Signature {
+    let secp = Secp256k1::new();
+    let message = Message::from_digest(digest.to_byte_array());
+    secp.sign_ecdsa(&message, secret_key)
+}
+
+pub fn verify_hash(digest: &sha256::Hash, signature: &Signature, public_key: &PublicKey) -> bool {
+    let secp = Secp256k1::new();
+    let message = Message::from_digest(digest.to_byte_array());
+    secp.verify_ecdsa(&message, signature, public_key).is_ok()
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::op_ref)]
     fn test_generate_keypair() {
         let (secret_key, public_key) = generate_keypair();
         assert!(&PublicKey::from_secret_key(&Secp256k1::new(), &secret_key) == &public_key);
@@ -53,4 +71,15 @@ mod tests {
         let message = "Hello, World";
         assert!(!verify_signature(message, &signature, &public_key));
     }
+
+    #[test]
+    fn test_verify_hash() {
+        let (secret_key, public_key) = generate_keypair();
+        let (_, other_public_key) = generate_keypair();
+        let digest = sha256_hash("Hello, World!");
+        let signature = sign_hash(&digest, &secret_key);
+        assert!(verify_hash(&digest, &signature, &public_key));
+        assert!(!verify_hash(&digest, &signature, &other_public_key));
+        assert!(!verify_hash(&sha256_hash("Hello, World"), &signature, &public_key));
+    }
 }
\ No newline at end of file